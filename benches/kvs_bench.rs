@@ -3,7 +3,6 @@ use criterion::{criterion_group, criterion_main};
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
 use tempfile::TempDir;
-use sled;
 
 use kvs::{KvStore, KvsEngine, SledEngine};
 
@@ -17,12 +16,12 @@ fn gennerate_kvpairs() -> Vec<(String, String)>  {
 
         let value_len = rng.gen_range(1, 100_001);
 
-        let key: String = (&mut rng)
+        let key: String = rng
             .sample_iter(&Alphanumeric)
             .take(key_len)
             .collect();
         
-        let value: String = (&mut rng)
+        let value: String = rng
             .sample_iter(&Alphanumeric)
             .take(value_len)
             .collect();
@@ -44,7 +43,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
             }, 
-            |(mut store, _temp_dir)| {
+            |(store, _temp_dir)| {
                 for (k, v) in key_value_pairs.iter() {
                     let res = store.set(String::from(k), String::from(v));
                     assert!(res.is_ok());
@@ -61,7 +60,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (SledEngine::new(sled::open(temp_dir.path()).unwrap()), temp_dir)
             }, 
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
                 for (k, v) in key_value_pairs.iter() {
                     let res = db.set(String::from(k), String::from(v));
                     assert!(res.is_ok());
@@ -90,13 +89,13 @@ fn get_bench(c: &mut Criterion) {
             || {
                 // 打开一个kvs引擎并设置键值对
                 let temp_dir = TempDir::new().unwrap();
-                let mut store = KvStore::open(temp_dir.path()).unwrap();
+                let store = KvStore::open(temp_dir.path()).unwrap();
                 for (k, v) in key_value_pairs.iter() {
                     let res = store.set(String::from(k), String::from(v));
                     assert!(res.is_ok());
                 }
                 (store, temp_dir)
-            }, |(mut store, _temp_dir)| {
+            }, |(store, _temp_dir)| {
                 for &key in request_keys.iter() {
                     let res = store.get(String::from(key));
                     assert!(res.is_ok_and(|x| x.is_some()));
@@ -110,14 +109,14 @@ fn get_bench(c: &mut Criterion) {
             || {
                 // 打开一个sled引擎并设置键值对
                 let temp_dir = TempDir::new().unwrap();
-                let mut db = SledEngine::new(sled::open(&temp_dir
+                let db = SledEngine::new(sled::open(&temp_dir
                 ).unwrap());
                 for (k, v) in key_value_pairs.iter() {
                     let res = db.set(String::from(k), String::from(v));
                     assert!(res.is_ok());
                 }
                 (db, temp_dir)
-            }, |(mut db, _temp_dir)| {
+            }, |(db, _temp_dir)| {
                 for &key in request_keys.iter() {
                     let res = db.get(String::from(key));
                     assert!(res.is_ok_and(|x| x.is_some()));
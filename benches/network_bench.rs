@@ -0,0 +1,68 @@
+use criterion::{criterion_group, criterion_main};
+use criterion::Criterion;
+use slog::{o, Discard, Logger};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer};
+
+const BENCH_ADDRESS: &str = "127.0.0.1:4100";
+
+/// 每轮基准测试写入的键值对数量
+const REQUEST_NUM: usize = 1000;
+
+/// 在后台线程中启动一个kvs服务器，并等待其开始监听
+fn start_server(addr: SocketAddr) -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32).unwrap();
+    let logger = Arc::new(Logger::root(Discard, o!()));
+    thread::spawn(move || KvsServer::new(engine, pool).run(addr, logger));
+
+    while KvsClient::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(50));
+    }
+    temp_dir
+}
+
+/// 比较逐个请求与流水线两种方式写入相同键值对的耗时
+fn network_set_bench(c: &mut Criterion) {
+    let addr: SocketAddr = BENCH_ADDRESS.parse().unwrap();
+    let _temp_dir = start_server(addr);
+    let key_value_pairs: Vec<(String, String)> = (0..REQUEST_NUM)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+
+    let mut group = c.benchmark_group("network_set_bench");
+
+    group.bench_function("sequential", |b| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        b.iter(|| {
+            for (k, v) in key_value_pairs.iter() {
+                client.set(k.clone(), v.clone()).unwrap();
+            }
+        });
+    });
+
+    group.bench_function("pipelined", |b| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        b.iter(|| {
+            let mut pipeline = client.pipeline();
+            for (k, v) in key_value_pairs.iter() {
+                pipeline.set(k.clone(), v.clone());
+            }
+            for resp in pipeline.execute().unwrap() {
+                assert!(resp.is_ok());
+            }
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, network_set_bench);
+criterion_main!(benches);
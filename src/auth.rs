@@ -0,0 +1,268 @@
+//! 用户认证与按键前缀的访问控制
+
+use crate::error::{KvsError, Result};
+
+use ring::digest::SHA256_OUTPUT_LEN;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::File;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::Arc;
+
+const PASSWORD_HASH_SCHEME: &str = "pbkdf2-sha256";
+const PBKDF2_ALGORITHM: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+/// `hash_password`使用的迭代次数
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+/// 服务器的用户与访问控制配置
+///
+/// 从JSON文件加载，格式如下：
+///
+/// ```json
+/// {
+///   "users": [
+///     {
+///       "name": "alice",
+///       "password_hash": "pbkdf2-sha256$<迭代次数>$<十六进制盐>$<十六进制摘要>",
+///       "admin": true,
+///       "rules": [
+///         { "prefix": "app:", "read": true, "write": true },
+///         { "prefix": "", "read": true }
+///       ]
+///     }
+///   ]
+/// }
+/// ```
+///
+/// `password_hash`由`AuthConfig::hash_password`或`kvs-server --hash-password`生成，每个用户使用不同的随机盐。
+/// 只要有一条规则的前缀匹配键并授予了相应权限，即允许访问；空前缀匹配所有键。
+/// `admin`为true的用户还可以执行压缩、同步与备份等管理请求，默认为false。
+#[derive(Debug)]
+pub struct AuthConfig {
+    users: HashMap<String, User>,
+    /// 校验不存在的用户时使用，使其耗时与校验存在的用户相同
+    dummy: PasswordHash,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthFile {
+    users: Vec<UserEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserEntry {
+    name: String,
+    password_hash: String,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct User {
+    password_hash: PasswordHash,
+    admin: bool,
+    rules: Vec<Rule>,
+}
+
+/// 对以`prefix`开头的键授予读写权限
+#[derive(Debug, Deserialize)]
+struct Rule {
+    prefix: String,
+    #[serde(default)]
+    read: bool,
+    #[serde(default)]
+    write: bool,
+}
+
+/// PBKDF2-HMAC-SHA256的参数与结果
+#[derive(Debug)]
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// 解析`pbkdf2-sha256$<迭代次数>$<十六进制盐>$<十六进制摘要>`格式的字符串
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('$');
+        if parts.next()? != PASSWORD_HASH_SCHEME {
+            return None;
+        }
+        let iterations = parts.next()?.parse().ok()?;
+        let salt = decode_hex(parts.next()?).filter(|salt| !salt.is_empty())?;
+        let hash = decode_hex(parts.next()?).filter(|hash| hash.len() == SHA256_OUTPUT_LEN)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(PasswordHash { iterations, salt, hash })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(PBKDF2_ALGORITHM, self.iterations, &self.salt, password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+/// 访问类型
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Access {
+    Read,
+    Write,
+}
+
+impl AuthConfig {
+    /// 从给定JSON文件加载配置
+    pub fn load(path: &Path) -> Result<Self> {
+        let file: AuthFile = serde_json::from_reader(File::open(path)?)?;
+        let mut users = HashMap::new();
+        for entry in file.users {
+            let password_hash = PasswordHash::parse(&entry.password_hash).ok_or_else(|| {
+                KvsError::StringError(format!("Invalid password_hash for user {}", entry.name))
+            })?;
+            let user = User {
+                password_hash,
+                admin: entry.admin,
+                rules: entry.rules,
+            };
+            if users.insert(entry.name.clone(), user).is_some() {
+                return Err(KvsError::StringError(format!("Duplicate user {}", entry.name)));
+            }
+        }
+        let dummy = PasswordHash {
+            iterations: NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            salt: vec![0; SALT_LEN],
+            hash: vec![0; SHA256_OUTPUT_LEN],
+        };
+        Ok(AuthConfig { users, dummy })
+    }
+
+    /// 以随机盐计算密码的摘要，返回可填入配置文件`password_hash`的字符串
+    pub fn hash_password(password: &str) -> Result<String> {
+        let mut salt = [0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| KvsError::StringError("Failed to generate salt".to_owned()))?;
+        let mut hash = [0; SHA256_OUTPUT_LEN];
+        let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+        pbkdf2::derive(PBKDF2_ALGORITHM, iterations, &salt, password.as_bytes(), &mut hash);
+        Ok(format!(
+            "{}${}${}${}",
+            PASSWORD_HASH_SCHEME,
+            iterations,
+            encode_hex(&salt),
+            encode_hex(&hash)
+        ))
+    }
+
+    /// 校验用户名与密码，用户不存在时同样计算一次摘要，避免通过响应时间推测用户是否存在
+    fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(entry) => entry.password_hash.verify(password),
+            None => {
+                self.dummy.verify(password);
+                false
+            }
+        }
+    }
+}
+
+/// 单个连接的认证状态
+pub(crate) struct Session {
+    auth: Option<Arc<AuthConfig>>,
+    user: Option<String>,
+}
+
+impl Session {
+    /// 若`auth`为None，则服务器未启用认证，所有请求均被允许
+    pub fn new(auth: Option<Arc<AuthConfig>>) -> Self {
+        Session { auth, user: None }
+    }
+
+    /// 以给定用户身份登录，失败时保持原有身份不变
+    pub fn login(&mut self, user: String, password: &str) -> Result<()> {
+        match &self.auth {
+            Some(auth) if auth.verify(&user, password) => {
+                self.user = Some(user);
+                Ok(())
+            }
+            Some(_) => Err(KvsError::AuthFailed),
+            // 未启用认证时任何凭据都会被接受
+            None => Ok(()),
+        }
+    }
+
+    /// 已登录的用户名
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// 启用认证时要求已经登录，用于不涉及具体键的请求
+    pub fn check_authenticated(&self) -> Result<()> {
+        match (&self.auth, &self.user) {
+            (Some(_), None) => Err(KvsError::PermissionDenied),
+            _ => Ok(()),
+        }
+    }
+
+    /// 检查当前用户能否执行管理请求，未启用认证时所有请求均被允许
+    pub fn check_admin(&self) -> Result<()> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(()),
+        };
+        match self.user.as_ref().and_then(|user| auth.users.get(user)) {
+            Some(user) if user.admin => Ok(()),
+            _ => Err(KvsError::PermissionDenied),
+        }
+    }
+
+    /// 检查当前用户能否以给定方式访问键
+    pub fn check(&self, key: &str, access: Access) -> Result<()> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(()),
+        };
+        let user = self
+            .user
+            .as_ref()
+            .and_then(|user| auth.users.get(user))
+            .ok_or(KvsError::PermissionDenied)?;
+
+        let allowed = user.rules.iter().any(|rule| {
+            key.starts_with(&rule.prefix)
+                && match access {
+                    Access::Read => rule.read,
+                    Access::Write => rule.write,
+                }
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(KvsError::PermissionDenied)
+        }
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, byte| {
+        let _ = write!(s, "{:02x}", byte);
+        s
+    })
+}
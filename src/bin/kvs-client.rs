@@ -1,8 +1,11 @@
 use clap::{Parser, Subcommand};
-use kvs::{KvsClient, Result};
+use kvs::{ClientTls, Codec, Event, KvsClientBuilder, KvsError, Result, ServerStats, SlowRequest};
+use serde_json::json;
 use std::process::exit;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 const DEFAULT_CONNECT_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
 
@@ -17,6 +20,51 @@ struct Cli {
 
     #[arg(short, long, global = true, default_value_t = DEFAULT_CONNECT_ADDRESS, value_parser = addr_parser)]
     addr: SocketAddr,
+
+    /// 连接该路径上的Unix domain socket而不是TCP地址
+    #[cfg(unix)]
+    #[arg(long, global = true, conflicts_with_all = ["addr", "tls_ca"])]
+    socket: Option<PathBuf>,
+
+    /// 请求与响应的编码方式，json便于抓包调试
+    #[arg(long, global = true, default_value_t = Codec::Bincode, value_parser = codec_parser)]
+    codec: Codec,
+
+    /// 启用TLS，且只信任该PEM文件中的CA证书
+    #[arg(long, global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// 校验服务器证书时使用的名称，默认使用所连接的IP
+    #[arg(long, global = true, requires = "tls_ca")]
+    tls_server_name: Option<String>,
+
+    /// 双向TLS时出示的客户端证书链（PEM）
+    #[arg(long, global = true, requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// 双向TLS时使用的客户端私钥（PEM）
+    #[arg(long, global = true, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// 连接、发送请求与等待响应各自的超时时间（秒）
+    #[arg(long, global = true, value_parser = timeout_parser)]
+    timeout: Option<f64>,
+
+    /// 读请求与带幂等令牌的写请求在连接出错时的重试次数
+    #[arg(long, global = true, default_value_t = 0)]
+    retries: u32,
+
+    /// 连接后以该用户身份认证
+    #[arg(long, global = true, requires = "password")]
+    user: Option<String>,
+
+    /// 认证使用的密码
+    #[arg(long, global = true, requires = "user")]
+    password: Option<String>,
+
+    /// 请求附带的请求ID，服务器在日志中记录该ID；默认自动生成
+    #[arg(long, global = true)]
+    request_id: Option<String>,
 }
 
 fn addr_parser(s: &str) -> std::result::Result<SocketAddr, String> {
@@ -26,6 +74,17 @@ fn addr_parser(s: &str) -> std::result::Result<SocketAddr, String> {
     }
 } 
 
+fn timeout_parser(s: &str) -> std::result::Result<f64, String> {
+    match f64::from_str(s) {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(secs),
+        _ => Err(String::from("Invalid timeout")),
+    }
+}
+
+fn codec_parser(s: &str) -> std::result::Result<Codec, String> {
+    Codec::from_str(s).map_err(|e| e.to_string())
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// 设置键值
@@ -36,13 +95,71 @@ enum Commands {
 
     /// 删除键
     Rm { key: String },
+
+    /// 批量获取多个键值
+    Mget {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+
+    /// 批量设置键值，参数依次为KEY VALUE对
+    Mset {
+        #[arg(required = true, value_names = ["KEY", "VALUE"])]
+        pairs: Vec<String>,
+    },
+
+    /// 持续输出以给定前缀开头的键的变更，直到连接断开
+    Watch {
+        #[arg(default_value = "")]
+        key_or_prefix: String,
+    },
+
+    /// 从给定序号开始持续输出所有变更，每行以序号开头
+    Changes {
+        #[arg(long = "from", default_value_t = 0)]
+        from_seq: u64,
+    },
+
+    /// 输出服务器的版本、运行时间、连接数以及存储引擎的统计信息
+    Stats {
+        /// 以JSON格式输出
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// 管理请求，启用认证时须以管理员身份登录
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommands {
+    /// 立即压缩存储引擎的日志
+    Compact,
+
+    /// 把已写入的数据同步到磁盘
+    Flush,
+
+    /// 把数据的一致副本写入服务器备份目录下的给定相对路径，该目录须不存在或为空
+    Backup { dest: PathBuf },
+
+    /// 输出服务器最近的慢请求，最新的在前
+    SlowLog,
 }
 
 /// 运行kvs_client
 /// # Usages
-/// kvs-client set <KEY> <VALUE> [--addr IP-PORT]
-/// kvs-client get <KEY> [--addr IP-PORT]
-/// kvs-client rm <KEY> [--addr IP-PORT]
+/// kvs-client set <KEY> <VALUE> [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
+/// kvs-client get <KEY> [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
+/// kvs-client rm <KEY> [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
+/// kvs-client mget <KEY>... [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
+/// kvs-client mset <KEY> <VALUE> [<KEY> <VALUE>]... [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
+/// kvs-client watch [KEY-OR-PREFIX] [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
+/// kvs-client changes [--from SEQ] [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
+/// kvs-client stats [--json] [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
+/// kvs-client admin (compact | flush | backup <DEST> | slow-log) [--addr IP-PORT | --socket PATH] [--codec CODEC] [TLS-OPTIONS] [--user USER --password PASSWORD]
 #[allow(unused_variables)]
 fn main() {
     let cli = Cli::parse();
@@ -53,13 +170,23 @@ fn main() {
 }
 
 fn run(cli: Cli) -> Result<()> {
+    let builder = client_builder(&cli)?;
+    let request_id = cli.request_id.clone();
+    let connect = || {
+        let mut client = builder.clone().build()?;
+        if let Some(request_id) = &request_id {
+            client.set_request_id(request_id.clone());
+        }
+        Ok::<_, KvsError>(client)
+    };
+
     match cli.command {
         Commands::Set { key, value } => {
-            let mut client = KvsClient::connect(cli.addr)?;
+            let mut client = connect()?;
             client.set(key, value)?;
         }
         Commands::Get { key } => {
-            let mut client = KvsClient::connect(cli.addr)?;
+            let mut client = connect()?;
             if let Some(value) = client.get(key)? {
                 println!("{}", value);
             } else {
@@ -67,9 +194,181 @@ fn run(cli: Cli) -> Result<()> {
             }
         }
         Commands::Rm { key } => {
-            let mut client = KvsClient::connect(cli.addr)?;
+            let mut client = connect()?;
             client.remove(key)?;
         }
+        Commands::Mget { keys } => {
+            let mut client = connect()?;
+            for value in client.mget(keys)? {
+                match value? {
+                    Some(value) => println!("{}", value),
+                    None => println!("Key not found"),
+                }
+            }
+        }
+        Commands::Mset { pairs } => {
+            if pairs.len() % 2 != 0 {
+                return Err(KvsError::StringError("Missing value for the last key".to_string()));
+            }
+            let pairs = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+
+            let mut client = connect()?;
+            for res in client.mset(pairs)? {
+                res?;
+            }
+        }
+        Commands::Watch { key_or_prefix } => {
+            let mut client = connect()?;
+            for event in client.watch(key_or_prefix)? {
+                match event? {
+                    Event::Set { key, value } => println!("set {} {}", key, value),
+                    Event::Remove { key } => println!("rm {}", key),
+                }
+            }
+        }
+        Commands::Changes { from_seq } => {
+            let mut client = connect()?;
+            for change in client.changes(from_seq)? {
+                let change = change?;
+                match change.event {
+                    Event::Set { key, value } => println!("{} set {} {}", change.seq, key, value),
+                    Event::Remove { key } => println!("{} rm {}", change.seq, key),
+                }
+            }
+        }
+        Commands::Stats { json } => {
+            let stats = connect()?.stats()?;
+            if json {
+                println!("{:#}", stats_json(&stats));
+            } else {
+                print_stats(&stats);
+            }
+        }
+        Commands::Admin { command } => {
+            let mut client = connect()?;
+            match command {
+                AdminCommands::Compact => client.compact()?,
+                AdminCommands::Flush => client.flush()?,
+                AdminCommands::Backup { dest } => client.backup(dest)?,
+                AdminCommands::SlowLog => {
+                    for entry in client.slow_log()? {
+                        print_slow_request(&entry);
+                    }
+                }
+            }
+        }
     }
     Ok(())
 }
+
+/// 时长以秒为单位输出，便于其他工具处理
+fn stats_json(stats: &ServerStats) -> serde_json::Value {
+    let engine = stats.engine.as_ref().map(|engine| {
+        json!({
+            "type": engine.engine,
+            "data_dir": engine.data_dir,
+            "keys": engine.keys,
+            "disk_bytes": engine.disk_bytes,
+            "generations": engine.generations,
+            "live_bytes": engine.live_bytes,
+            "uncompacted_bytes": engine.uncompacted_bytes,
+            "compactions": engine.compactions,
+            "compaction_seconds": engine.compaction_time.as_secs_f64(),
+        })
+    });
+    json!({
+        "version": stats.version,
+        "uptime_seconds": stats.uptime.as_secs_f64(),
+        "engine": engine,
+        "connections": {
+            "open": stats.connections,
+            "queued": stats.queued_connections,
+            "accepted": stats.accepted_connections,
+            "rejected": stats.rejected_connections,
+        },
+    })
+}
+
+fn print_stats(stats: &ServerStats) {
+    let mut rows = vec![
+        ("version", stats.version.clone()),
+        ("uptime", format!("{}s", stats.uptime.as_secs())),
+    ];
+    if let Some(engine) = &stats.engine {
+        let data_dir = engine.data_dir.as_ref().map_or("-".to_owned(), |dir| dir.display().to_string());
+        rows.extend([
+            ("engine", engine.engine.clone()),
+            ("data dir", data_dir),
+            ("keys", engine.keys.to_string()),
+            ("disk bytes", engine.disk_bytes.to_string()),
+            ("generations", engine.generations.to_string()),
+            ("live bytes", engine.live_bytes.to_string()),
+            ("uncompacted bytes", engine.uncompacted_bytes.to_string()),
+            ("compactions", engine.compactions.to_string()),
+            ("compaction time", format!("{:.3}s", engine.compaction_time.as_secs_f64())),
+        ]);
+    }
+    rows.extend([
+        ("connections", stats.connections.to_string()),
+        ("queued connections", stats.queued_connections.to_string()),
+        ("accepted connections", stats.accepted_connections.to_string()),
+        ("rejected connections", stats.rejected_connections.to_string()),
+    ]);
+
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, value) in rows {
+        println!("{:width$}  {}", name, value, width = width);
+    }
+}
+
+fn print_slow_request(entry: &SlowRequest) {
+    let ago = SystemTime::now().duration_since(entry.at).unwrap_or_default();
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    println!(
+        "{}s ago {} {} {} total={:.3}ms queue={:.3}ms engine={:.3}ms write={:.3}ms",
+        ago.as_secs(),
+        entry.peer,
+        entry.command,
+        entry.key.as_deref().unwrap_or("-"),
+        ms(entry.total),
+        ms(entry.queue_wait),
+        ms(entry.engine),
+        ms(entry.write_back),
+    );
+}
+
+fn client_builder(cli: &Cli) -> Result<KvsClientBuilder> {
+    let mut builder = KvsClientBuilder::new(cli.addr);
+    #[cfg(unix)]
+    if let Some(socket) = &cli.socket {
+        builder = KvsClientBuilder::unix(socket);
+    }
+    builder = builder.codec(cli.codec).retries(cli.retries);
+    if let Some(tls) = client_tls(cli)? {
+        builder = builder.tls(tls);
+    }
+    if let Some(secs) = cli.timeout {
+        let timeout = Duration::from_secs_f64(secs);
+        builder = builder.connect_timeout(timeout).read_timeout(timeout).write_timeout(timeout);
+    }
+    if let (Some(user), Some(password)) = (&cli.user, &cli.password) {
+        builder = builder.auth(user.clone(), password.clone());
+    }
+    Ok(builder)
+}
+
+fn client_tls(cli: &Cli) -> Result<Option<ClientTls>> {
+    let ca = match &cli.tls_ca {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let identity = cli.tls_cert.as_deref().zip(cli.tls_key.as_deref());
+    let mut tls = ClientTls::new(ca, identity)?;
+    if let Some(name) = &cli.tls_server_name {
+        tls = tls.with_server_name(name)?;
+    }
+    Ok(Some(tls))
+}
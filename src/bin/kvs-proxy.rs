@@ -0,0 +1,107 @@
+use clap::Parser;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[macro_use]
+extern crate slog;
+extern crate slog_async;
+extern crate slog_term;
+
+use slog::{Drain, Logger};
+
+use kvs::{HashRing, KvsClientBuilder, KvsServer, PrefixTable, Protocol, ProxyEngine, Result, Routing};
+
+const DEFAULT_LISTENING_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
+const DEFAULT_VIRTUAL_NODES: usize = 160;
+const DEFAULT_BACKEND_CONNECTIONS: usize = 16;
+
+#[derive(Debug, Parser)]
+#[command(name = "kvs-proxy",
+        version = env!("CARGO_PKG_VERSION"),
+        author = env!("CARGO_PKG_AUTHORS"),
+        about = "Routes kvs requests to backend kvs-servers")]
+struct Cli {
+    /// 监听的TCP地址
+    #[arg(short, long, default_value_t = DEFAULT_LISTENING_ADDRESS, value_parser = addr_parser)]
+    addr: SocketAddr,
+
+    /// 对客户端提供的协议
+    #[arg(long, default_value_t = Protocol::Kvs, value_parser = protocol_parser)]
+    protocol: Protocol,
+
+    /// 按一致性哈希分布键的后端地址，可多次指定
+    #[arg(long = "backend", value_parser = addr_parser, required_unless_present = "routes", conflicts_with = "routes")]
+    backends: Vec<SocketAddr>,
+
+    /// 按键前缀路由，格式为PREFIX=ADDR，可多次指定；空前缀（=ADDR）作为默认路由
+    #[arg(long = "route", value_parser = route_parser)]
+    routes: Vec<(String, SocketAddr)>,
+
+    /// 哈希环上每个后端的虚拟节点数
+    #[arg(long, default_value_t = DEFAULT_VIRTUAL_NODES)]
+    virtual_nodes: usize,
+
+    /// 到每个后端最多同时保持的连接数
+    #[arg(long, default_value_t = DEFAULT_BACKEND_CONNECTIONS)]
+    backend_connections: usize,
+}
+
+fn addr_parser(s: &str) -> std::result::Result<SocketAddr, String> {
+    SocketAddr::from_str(s).map_err(|_| String::from("Invalid addr"))
+}
+
+fn route_parser(s: &str) -> std::result::Result<(String, SocketAddr), String> {
+    let (prefix, addr) = s
+        .rsplit_once('=')
+        .ok_or_else(|| String::from("Invalid route, expected PREFIX=ADDR"))?;
+    Ok((prefix.to_owned(), addr_parser(addr)?))
+}
+
+fn protocol_parser(s: &str) -> std::result::Result<Protocol, String> {
+    Protocol::from_str(s).map_err(|e| e.to_string())
+}
+
+/// 运行kvs-proxy
+/// # Usages
+/// kvs-proxy [--addr IP-PORT] [--protocol PROTOCOL] (--backend IP-PORT... [--virtual-nodes N] | --route PREFIX=IP-PORT...) [--backend-connections N]
+fn main() {
+    let decorator = slog_term::PlainDecorator::new(std::io::stderr());
+    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let logger = Arc::new(Logger::root(drain, o!("kvs-proxy version" => env!("CARGO_PKG_VERSION"))));
+
+    let cli = Cli::parse();
+    info!(logger, "Listening on {}", cli.addr; "protocol" => cli.protocol.to_string());
+    if let Err(e) = run(&cli, logger.clone()) {
+        error!(logger, "{}", e);
+        drop(logger);
+        exit(1);
+    }
+}
+
+fn run(cli: &Cli, logger: Arc<Logger>) -> Result<()> {
+    let routing = if cli.routes.is_empty() {
+        let mut ring = HashRing::new(cli.virtual_nodes);
+        for backend in &cli.backends {
+            info!(logger, "Backend {}", backend);
+            ring.add(*backend);
+        }
+        Routing::HashRing(ring)
+    } else {
+        let mut table = PrefixTable::new();
+        for (prefix, backend) in &cli.routes {
+            info!(logger, "Routing prefix {:?} to {}", prefix, backend);
+            table = table.route(prefix.clone(), *backend);
+        }
+        Routing::Prefix(table)
+    };
+
+    // 后端地址由路由决定，此处的地址不会被使用
+    let options = KvsClientBuilder::new(cli.addr);
+    let engine = ProxyEngine::new(routing, options, cli.backend_connections)?;
+    let pool = NaiveThreadPool::new(num_cpus::get() as u32)?;
+    KvsServer::new(engine, pool).protocol(cli.protocol).run(cli.addr, logger)
+}
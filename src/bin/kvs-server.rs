@@ -1,15 +1,16 @@
 use clap::{Parser, ValueEnum};
-use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
 use serde::{Deserialize, Serialize};
-use num_cpus;
 use std::fmt::Display;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
 use std::env;
 use std::env::current_dir;
 use std::process::exit;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[macro_use]
 extern crate slog;
@@ -18,11 +19,15 @@ extern crate slog_term;
 
 use slog::{Drain, Logger};
 
-use kvs::{KvStore, KvsEngine, SledEngine, KvsServer, Result};
+use kvs::{AuthConfig, ClientTls, ClusterConfig, KvStore, KvsClientBuilder, KvsEngine, KvsError, SledEngine, KvsServer, Protocol, RateLimit, Result, ServerTls};
 
 const DEFAULT_LISTENING_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
 const DEFAULT_STORAGE_ENGINE: Engine = Engine::Kvs;
+const DEFAULT_SLOW_LOG_SIZE: usize = 128;
+const DEFAULT_THREADS: u32 = 64;
 const ENGINE_FILE_SUFFIX: &str = ".engine";
+const RAFT_DIR: &str = "raft";
+const REPLICA_STATE_FILE: &str = "replica.seq";
 
 #[derive(Debug, Parser)]
 #[command(name = env!("CARGO_PKG_NAME"), 
@@ -30,11 +35,117 @@ const ENGINE_FILE_SUFFIX: &str = ".engine";
         author = env!("CARGO_PKG_AUTHORS"), 
         about = env!("CARGO_PKG_DESCRIPTION"))]
 struct Cli {
-    #[arg(short, long, default_value_t = DEFAULT_LISTENING_ADDRESS, value_parser = addr_parser)]
-    addr: SocketAddr,
+    /// 监听的TCP地址，未指定且未指定--socket时使用127.0.0.1:4000
+    #[arg(short, long, value_parser = addr_parser)]
+    addr: Option<SocketAddr>,
+
+    /// 监听的Unix domain socket路径，可与--addr同时使用
+    #[cfg(unix)]
+    #[arg(long)]
+    socket: Option<PathBuf>,
 
     #[arg(short, long, value_enum)]
     engine: Option<Engine>,
+
+    /// 对外提供的协议，resp可供redis-cli等Redis客户端使用
+    #[arg(long, default_value_t = Protocol::Kvs, value_parser = protocol_parser)]
+    protocol: Protocol,
+
+    /// 额外提供HTTP/JSON网关的地址
+    #[arg(long, value_parser = addr_parser)]
+    http_addr: Option<SocketAddr>,
+
+    /// 以Prometheus文本格式提供/metrics监控指标的地址
+    #[arg(long, value_parser = addr_parser)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// 启用TLS时使用的服务器证书链（PEM）
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// 启用TLS时使用的服务器私钥（PEM）
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// 要求客户端出示由该PEM文件中的CA签发的证书（双向TLS）
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// 用户与访问控制配置文件（JSON），指定后客户端须先认证
+    #[arg(long)]
+    auth_config: Option<PathBuf>,
+
+    /// 从标准输入读取一行密码，输出可填入认证配置文件的password_hash后退出
+    #[arg(long)]
+    hash_password: bool,
+
+    /// 处理连接的工作线程数；每个连接在关闭前占用一个线程，其余连接排队等待
+    #[arg(long, default_value_t = DEFAULT_THREADS)]
+    threads: u32,
+
+    /// 同时保持的最大连接数，超出时新连接收到"server busy"
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// 最多排队等待工作线程的连接数，超出时新连接收到"server busy"
+    #[arg(long)]
+    queue_size: Option<usize>,
+
+    /// 连接在两个请求之间最长的空闲时间（秒）
+    #[arg(long, value_parser = seconds_parser)]
+    idle_timeout: Option<Duration>,
+
+    /// 读完一个请求的最长时间（秒）
+    #[arg(long, value_parser = seconds_parser)]
+    read_timeout: Option<Duration>,
+
+    /// 每个用户或客户端IP每秒最多处理的请求数
+    #[arg(long, value_parser = rate_parser)]
+    rate_limit_ops: Option<f64>,
+
+    /// 每个用户或客户端IP每秒最多收发的字节数
+    #[arg(long, value_parser = rate_parser)]
+    rate_limit_bytes: Option<f64>,
+
+    /// 记录耗时超过该值的请求（秒）
+    #[arg(long, value_parser = seconds_parser)]
+    slow_log_threshold: Option<Duration>,
+
+    /// 允许管理员通过Backup请求在该目录下写入备份，未指定时拒绝所有备份请求
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// 最多保留的慢请求记录数
+    #[arg(long, default_value_t = DEFAULT_SLOW_LOG_SIZE, requires = "slow_log_threshold")]
+    slow_log_size: usize,
+
+    /// 作为该地址上主节点的只读从节点运行
+    #[arg(long, value_parser = addr_parser)]
+    replica_of: Option<SocketAddr>,
+
+    /// 从节点通过TLS连接主节点，用该CA证书（PEM）校验主节点的证书
+    #[arg(long, requires = "replica_of")]
+    replica_tls_ca: Option<PathBuf>,
+
+    /// 从节点连接主节点后以该用户身份认证，该用户需要读取全部键
+    #[arg(long, requires_all = ["replica_of", "replica_password"])]
+    replica_user: Option<String>,
+
+    /// 从节点认证使用的密码
+    #[arg(long, requires = "replica_user")]
+    replica_password: Option<String>,
+
+    /// 作为Raft集群中该编号的节点运行，须同时以--peer列出包括自身在内的所有节点
+    #[arg(long, requires_all = ["peers", "cluster_secret_file"], conflicts_with = "replica_of")]
+    node_id: Option<u64>,
+
+    /// 集群节点，格式为ID=客户端地址/节点间通信地址，如1=127.0.0.1:4000/127.0.0.1:5000
+    #[arg(long = "peer", value_parser = peer_parser, requires = "node_id")]
+    peers: Vec<(u64, SocketAddr, SocketAddr)>,
+
+    /// 保存集群共享密钥的文件，所有节点须使用相同的内容；节点间通信的地址仍只应在私有网络中可达
+    #[arg(long, requires = "node_id")]
+    cluster_secret_file: Option<PathBuf>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug, Serialize, Deserialize)]
@@ -47,9 +158,9 @@ enum Engine {
 
 impl Display for Engine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            &Engine::Kvs => write!(f, "kvs"),
-            &Engine::Sled => write!(f, "sled"),
+        match *self {
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
         }
     }
 }
@@ -61,9 +172,39 @@ fn addr_parser(s: &str) -> std::result::Result<SocketAddr, String> {
     }
 } 
 
+fn seconds_parser(s: &str) -> std::result::Result<Duration, String> {
+    match f64::from_str(s) {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(String::from("Invalid number of seconds")),
+    }
+}
+
+fn rate_parser(s: &str) -> std::result::Result<f64, String> {
+    match f64::from_str(s) {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(String::from("Invalid rate")),
+    }
+}
+
+fn peer_parser(s: &str) -> std::result::Result<(u64, SocketAddr, SocketAddr), String> {
+    let invalid = || String::from("Invalid peer, expected ID=ADDR/RAFT-ADDR");
+    let (id, addrs) = s.split_once('=').ok_or_else(invalid)?;
+    let (addr, raft_addr) = addrs.split_once('/').ok_or_else(invalid)?;
+    Ok((
+        id.parse().map_err(|_| invalid())?,
+        addr_parser(addr)?,
+        addr_parser(raft_addr)?,
+    ))
+}
+
+fn protocol_parser(s: &str) -> std::result::Result<Protocol, String> {
+    Protocol::from_str(s).map_err(|e| e.to_string())
+}
+
 /// 运行kvs_server
 /// # Usages
-/// kvs-server [--addr IP-PORT] [--engine ENGINE-NAME]
+/// kvs-server --hash-password
+/// kvs-server [--addr IP-PORT] [--socket PATH] [--engine ENGINE-NAME] [--protocol PROTOCOL] [--http-addr IP-PORT] [--metrics-addr IP-PORT] [--tls-cert PEM --tls-key PEM [--tls-client-ca PEM]] [--auth-config PATH] [--threads N] [--max-connections N] [--queue-size N] [--idle-timeout SECS] [--read-timeout SECS] [--rate-limit-ops N] [--rate-limit-bytes N] [--slow-log-threshold SECS [--slow-log-size N]] [--backup-dir PATH] [--replica-of IP-PORT [--replica-tls-ca PEM] [--replica-user USER --replica-password PASSWORD]] [--node-id ID --peer ID=IP-PORT/IP-PORT... --cluster-secret-file PATH]
 fn main() {
     let decorator = slog_term::PlainDecorator::new(std::io::stderr());
     let drain = slog_term::CompactFormat::new(decorator).build().fuse();
@@ -73,7 +214,21 @@ fn main() {
     let server_logger = Arc::new(server_logger); 
 
     let cli = Cli::parse();
-    info!(server_logger, "Listening on {}", cli.addr; "IP address" => cli.addr.ip().to_string(), "port" => cli.addr.port().to_string());
+    if cli.hash_password {
+        if let Err(e) = print_password_hash() {
+            error!(server_logger, "{}", e);
+            drop(server_logger);
+            exit(1);
+        }
+        return;
+    }
+    if let Some(addr) = tcp_addr(&cli) {
+        info!(server_logger, "Listening on {}", addr; "IP address" => addr.ip().to_string(), "port" => addr.port().to_string());
+    }
+    #[cfg(unix)]
+    if let Some(socket) = &cli.socket {
+        info!(server_logger, "Listening on unix socket {}", socket.display());
+    }
 
     let cur_engine = match current_engine() {
         Ok(eng) => eng,
@@ -92,7 +247,27 @@ fn main() {
     let engine = cli.engine.unwrap_or(DEFAULT_STORAGE_ENGINE);
     info!(server_logger, "Storage Engine: {}", engine; "storage engine" => format!("{}", engine));
 
-    let res = run(engine, cli.addr, server_logger.clone());
+    info!(server_logger, "Protocol: {}", cli.protocol);
+    if let Some(http_addr) = cli.http_addr {
+        info!(server_logger, "HTTP gateway listening on {}", http_addr);
+    }
+    if let Some(metrics_addr) = cli.metrics_addr {
+        info!(server_logger, "Metrics endpoint listening on {}", metrics_addr);
+    }
+    if cli.tls_cert.is_some() {
+        info!(server_logger, "TLS enabled"; "client auth" => cli.tls_client_ca.is_some());
+    }
+    if let Some(auth_config) = &cli.auth_config {
+        info!(server_logger, "Authentication enabled"; "config" => auth_config.display().to_string());
+    }
+    if let Some(primary) = cli.replica_of {
+        info!(server_logger, "Replicating from primary {}", primary);
+    }
+    if let Some(id) = cli.node_id {
+        info!(server_logger, "Running as cluster node {}", id; "nodes" => cli.peers.len());
+    }
+
+    let res = run(engine, &cli, server_logger.clone());
     if let Err(e) = res {
         error!(server_logger, "{}", e);
         drop(server_logger);
@@ -100,26 +275,113 @@ fn main() {
     }
 }
 
-fn run(engine: Engine, addr: SocketAddr, logger: Arc<Logger>) -> Result<()> {
+fn run(engine: Engine, cli: &Cli, logger: Arc<Logger>) -> Result<()> {
     let engine_file = OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(current_dir()?.join(ENGINE_FILE_SUFFIX))?;
 
     serde_json::to_writer(engine_file, &engine)?;
 
-    let pool = NaiveThreadPool::new(num_cpus::get() as u32)?;
+    let pool = match cli.queue_size {
+        Some(size) => SharedQueueThreadPool::with_queue_size(cli.threads, size)?,
+        None => SharedQueueThreadPool::new(cli.threads)?,
+    };
 
     match engine {
-        Engine::Kvs => run_with_engine(KvStore::open(current_dir()?)?, pool, addr, logger),
-        Engine::Sled => run_with_engine(SledEngine::new(sled::open(current_dir()?)?), pool, addr, logger),
+        Engine::Kvs => run_with_engine(KvStore::open(current_dir()?)?, pool, cli, logger),
+        Engine::Sled => run_with_engine(SledEngine::new(sled::open(current_dir()?)?), pool, cli, logger),
     }
 }
 
-fn run_with_engine<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, addr: SocketAddr, logger: Arc<Logger>) -> Result<()> {
+fn run_with_engine<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, cli: &Cli, logger: Arc<Logger>) -> Result<()> {
+
+    let mut server = KvsServer::new(engine, pool).protocol(cli.protocol);
+    if let Some(http_addr) = cli.http_addr {
+        server = server.http_addr(http_addr);
+    }
+    if let Some(metrics_addr) = cli.metrics_addr {
+        server = server.metrics_addr(metrics_addr);
+    }
+    if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+        server = server.tls(ServerTls::new(cert, key, cli.tls_client_ca.as_deref())?);
+    }
+    if let Some(auth_config) = &cli.auth_config {
+        server = server.auth(AuthConfig::load(auth_config)?);
+    }
+    if let Some(max) = cli.max_connections {
+        server = server.max_connections(max);
+    }
+    if let Some(size) = cli.queue_size {
+        server = server.queue_size(size);
+    }
+    if let Some(timeout) = cli.idle_timeout {
+        server = server.idle_timeout(timeout);
+    }
+    if let Some(timeout) = cli.read_timeout {
+        server = server.read_timeout(timeout);
+    }
+    if cli.rate_limit_ops.is_some() || cli.rate_limit_bytes.is_some() {
+        let mut limit = RateLimit::new();
+        if let Some(rate) = cli.rate_limit_ops {
+            limit = limit.ops_per_sec(rate);
+        }
+        if let Some(rate) = cli.rate_limit_bytes {
+            limit = limit.bytes_per_sec(rate);
+        }
+        server = server.rate_limit(limit);
+    }
+    if let Some(threshold) = cli.slow_log_threshold {
+        server = server.slow_log(threshold, cli.slow_log_size);
+    }
+    if let Some(dir) = &cli.backup_dir {
+        server = server.backup_dir(dir);
+    }
+    if let Some(primary) = cli.replica_of {
+        let mut primary = KvsClientBuilder::new(primary);
+        if let Some(ca) = &cli.replica_tls_ca {
+            primary = primary.tls(ClientTls::new(ca, None)?);
+        }
+        if let (Some(user), Some(password)) = (&cli.replica_user, &cli.replica_password) {
+            primary = primary.auth(user.clone(), password.clone());
+        }
+        server = server.replica_of(primary, current_dir()?.join(REPLICA_STATE_FILE));
+    }
+    if let Some(id) = cli.node_id {
+        let secret_file = cli.cluster_secret_file.as_ref().expect("required by --node-id");
+        let secret = fs::read(secret_file)?;
+        let secret = secret.trim_ascii_end();
+        if secret.is_empty() {
+            return Err(KvsError::StringError(format!("Cluster secret file {} is empty", secret_file.display())));
+        }
+        let mut config = ClusterConfig::new(id, current_dir()?.join(RAFT_DIR), secret);
+        for &(peer, addr, raft_addr) in &cli.peers {
+            config = config.node(peer, addr, raft_addr);
+        }
+        server = server.cluster(config);
+    }
+    #[cfg(unix)]
+    if let Some(socket) = &cli.socket {
+        server = server.unix_socket(socket);
+    }
+
+    match tcp_addr(cli) {
+        Some(addr) => server.run(addr, logger),
+        #[cfg(unix)]
+        None => server.run_unix(cli.socket.clone().unwrap(), logger),
+        #[cfg(not(unix))]
+        None => unreachable!(),
+    }
+}
 
-    let server = KvsServer::new(engine, pool);
-    server.run(addr, logger)
+/// 只指定了Unix socket时不监听TCP
+fn tcp_addr(cli: &Cli) -> Option<SocketAddr> {
+    #[cfg(unix)]
+    if cli.addr.is_none() && cli.socket.is_some() {
+        return None;
+    }
+    Some(cli.addr.unwrap_or(DEFAULT_LISTENING_ADDRESS))
 }
 
 fn current_engine() -> Result<Option<Engine>>{
@@ -131,7 +393,15 @@ fn current_engine() -> Result<Option<Engine>>{
     let engine_file = File::open(engine_path)?;
 
     match serde_json::from_reader(engine_file)? {
-        Engine::Kvs => return Ok(Some(Engine::Kvs)),
-        Engine::Sled => return Ok(Some(Engine::Sled))
+        Engine::Kvs => Ok(Some(Engine::Kvs)),
+        Engine::Sled => Ok(Some(Engine::Sled))
     }
-}
\ No newline at end of file
+}
+
+fn print_password_hash() -> Result<()> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    println!("{}", AuthConfig::hash_password(password)?);
+    Ok(())
+}
@@ -1,63 +1,851 @@
 use std::net::{TcpStream, SocketAddr};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use crate::error::Result;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::error::{KvsError, Result};
+use crate::codec::{read_frame, write_frame, Codec};
 use crate::common::*;
-use crate::error::KvsError;
+use crate::engines::{Change, Event};
+use crate::stream::{BufStream, ReadWrite};
+use crate::tls::ClientTls;
+
+/// 流水线中已发送但尚未读取响应的请求数量上限
+///
+/// 若一次性发送全部请求而不读取响应，服务器的响应会填满双方的socket缓冲区，
+/// 导致服务器阻塞在写入上而不再读取请求，进而使客户端的写入也被阻塞。
+const PIPELINE_WINDOW: usize = 64;
+
+/// 默认的重试间隔，第n次重试前等待n倍的间隔
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// 单个请求因集群节点不是领导者而重定向或等待选举的最大次数
+const MAX_REDIRECTS: u32 = 10;
+
+/// 客户端连接的目标
+#[derive(Clone, Debug)]
+enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// `KvsClient`的构建器，用于配置超时、重连与重试策略
+///
+/// ```no_run
+/// # use kvs::{KvsClientBuilder, Result};
+/// # use std::time::Duration;
+/// # fn main() -> Result<()> {
+/// let mut client = KvsClientBuilder::new("127.0.0.1:4000".parse().unwrap())
+///     .connect_timeout(Duration::from_secs(1))
+///     .read_timeout(Duration::from_secs(2))
+///     .retries(3)
+///     .build()?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvsClientBuilder {
+    endpoint: Endpoint,
+    /// 集群中的所有节点，当前节点不可用时依次尝试其余节点
+    cluster: Vec<SocketAddr>,
+    codec: Codec,
+    tls: Option<ClientTls>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retries: u32,
+    retry_backoff: Duration,
+    credentials: Option<(String, String)>,
+}
+
+impl KvsClientBuilder {
+    /// 连接给定TCP地址，默认使用二进制编码、不设超时、不重试
+    pub fn new(addr: SocketAddr) -> Self {
+        Self::with_endpoint(Endpoint::Tcp(addr))
+    }
+
+    /// 连接给定路径上的Unix domain socket
+    #[cfg(unix)]
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self::with_endpoint(Endpoint::Unix(path.into()))
+    }
+
+    /// 连接Raft集群，先连接第一个可用的节点，之后自动重定向到领导者
+    ///
+    /// # Panics
+    ///
+    /// `addrs`为空时panic
+    pub fn cluster(addrs: Vec<SocketAddr>) -> Self {
+        let mut builder = Self::new(addrs[0]);
+        builder.cluster = addrs;
+        builder
+    }
+
+    /// 复制当前配置，改为连接给定TCP地址
+    pub(crate) fn with_addr(&self, addr: SocketAddr) -> Self {
+        let mut builder = self.clone();
+        builder.endpoint = Endpoint::Tcp(addr);
+        builder.cluster = Vec::new();
+        builder
+    }
+
+    fn with_endpoint(endpoint: Endpoint) -> Self {
+        KvsClientBuilder {
+            endpoint,
+            cluster: Vec::new(),
+            codec: Codec::Bincode,
+            tls: None,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            retries: 0,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            credentials: None,
+        }
+    }
+
+    /// 握手时请求使用的编码方式
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// 通过TLS加密TCP连接，对Unix socket无效
+    pub fn tls(mut self, tls: ClientTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// 建立TCP连接的超时时间，超时返回`KvsError::ConnectTimeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// 等待响应的超时时间，超时返回`KvsError::ReadTimeout`
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// 发送请求的超时时间，超时返回`KvsError::WriteTimeout`
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// 幂等请求因连接失败或超时而出错时的最大重试次数
+    ///
+    /// 重试次数大于0时，若服务器支持，`set`会携带幂等令牌发送，因而也会被重试。
+    /// `remove`与批量写入不会被重试。
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// 重试间隔，第n次重试前等待n倍的间隔
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// 连接后以给定用户身份认证，重连后会自动重新认证
+    pub fn auth(mut self, user: String, password: String) -> Self {
+        self.credentials = Some((user, password));
+        self
+    }
+
+    /// 连接服务器并完成握手与认证
+    pub fn build(self) -> Result<KvsClient> {
+        let mut client = KvsClient {
+            credentials: self.credentials.clone(),
+            options: self,
+            stream: None,
+            codec: Codec::Bincode,
+            version: PROTOCOL_VERSION,
+            features: Vec::new(),
+            client_id: random_id(),
+            next_token: 0,
+            next_request: 0,
+            request_id: None,
+            last_request_id: None,
+        };
+        client.ensure_connected()?;
+        Ok(client)
+    }
+
+    /// 建立连接并与服务器交换`Hello`帧，协商协议版本与编码方式
+    fn open(&self) -> Result<(BufStream<Box<dyn ReadWrite>>, HelloAck)> {
+        let stream: Box<dyn ReadWrite> = match &self.endpoint {
+            Endpoint::Tcp(addr) => {
+                let tcp = self.connect_tcp(*addr)?;
+                match &self.tls {
+                    Some(tls) => Box::new(tls.connect(tcp, addr.ip())?),
+                    None => Box::new(tcp),
+                }
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let unix = UnixStream::connect(path)?;
+                unix.set_read_timeout(self.read_timeout)?;
+                unix.set_write_timeout(self.write_timeout)?;
+                Box::new(unix)
+            }
+        };
+
+        let mut stream = BufStream::new(stream);
+        let hello = Hello::new(self.codec);
+        write_frame(&mut stream, Codec::Json, &hello).map_err(|e| timeout_as(e, KvsError::WriteTimeout))?;
+        stream.flush().map_err(|e| timeout_as(e.into(), KvsError::WriteTimeout))?;
+        let frame = read_frame(&mut stream)
+            .map_err(|e| timeout_as(e, KvsError::ReadTimeout))?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let ack: HelloAck = Codec::Json.decode(&frame)?;
+        if let Some(err) = ack.error {
+            return Err(err.into());
+        }
+        if !ack.is_accepted(&hello) {
+            return Err(KvsError::UnsupportedVersion(ack.version));
+        }
+        Ok((stream, ack))
+    }
+
+    fn connect_tcp(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let tcp = match self.connect_timeout {
+            Some(timeout) => {
+                TcpStream::connect_timeout(&addr, timeout).map_err(|e| timeout_as(e.into(), KvsError::ConnectTimeout))?
+            }
+            None => TcpStream::connect(addr)?,
+        };
+        // 流水线会连续发送多个小请求，关闭Nagle算法以免其与延迟确认相互等待
+        tcp.set_nodelay(true)?;
+        tcp.set_read_timeout(self.read_timeout)?;
+        tcp.set_write_timeout(self.write_timeout)?;
+        Ok(tcp)
+    }
+}
 
 /// Kvs客户端
+///
+/// 连接出错后客户端不会失效：下一个请求会先重新连接服务器。
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    options: KvsClientBuilder,
+    /// 连接出错后置为None，直到下一次请求时重连
+    stream: Option<BufStream<Box<dyn ReadWrite>>>,
+    codec: Codec,
+    version: u32,
+    features: Vec<String>,
+    credentials: Option<(String, String)>,
+    /// 与递增序号一起组成幂等令牌与请求ID
+    client_id: u64,
+    next_token: u64,
+    next_request: u64,
+    /// 由`set_request_id`指定、供下一个请求使用的请求ID
+    request_id: Option<String>,
+    last_request_id: Option<String>,
 }
 
 
 impl KvsClient {
-    /// 连接给定addr，生成Kvs客户端
+    /// 连接给定addr，生成使用二进制编码的Kvs客户端
     pub fn connect(addr: SocketAddr) -> Result<Self> {
-        let reader = TcpStream::connect(addr)?;
-        let writer = reader.try_clone()?;
+        KvsClientBuilder::new(addr).build()
+    }
 
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(reader)),
-            writer: BufWriter::new(writer),
-        })
+    /// 连接给定addr，并在握手时请求使用给定的编码方式
+    pub fn connect_with_codec(addr: SocketAddr, codec: Codec) -> Result<Self> {
+        KvsClientBuilder::new(addr).codec(codec).build()
     }
 
-    /// 从服务器获取给定键对应值
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+    /// 连接给定addr，并通过TLS加密之后的所有通信
+    pub fn connect_tls(addr: SocketAddr, codec: Codec, tls: &ClientTls) -> Result<Self> {
+        KvsClientBuilder::new(addr).codec(codec).tls(tls.clone()).build()
+    }
+
+    /// 连接监听在给定路径上的Unix domain socket
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>, codec: Codec) -> Result<Self> {
+        KvsClientBuilder::unix(path.as_ref()).codec(codec).build()
+    }
 
-        match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+    /// 握手时协商出的协议版本号
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// 握手时协商出的编码方式
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// 服务器与客户端均支持的可选功能
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    /// 以给定用户身份认证，之后的请求按该用户的权限检查
+    ///
+    /// 用户名或密码错误时返回`KvsError::AuthFailed`。
+    pub fn auth(&mut self, user: String, password: String) -> Result<()> {
+        let req = Request::Auth { user, password };
+        done_reply(Ok(self.request(&req)?))?;
+        if let Request::Auth { user, password } = req {
+            self.credentials = Some((user, password));
         }
+        Ok(())
     }
 
-    /// 删除服务器上的给定键
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Rm { key })?;
-        self.writer.flush()?;
-        let resp = RmResponse::deserialize(&mut self.reader)?;
+    /// 检查与服务器的连接是否可用
+    pub fn ping(&mut self) -> Result<()> {
+        done_reply(Ok(self.request(&Request::Ping)?))
+    }
 
-        match resp {
-            RmResponse::Ok(_) => Ok(()),
-            RmResponse::Err(msg) => Err(KvsError::StringError(msg)),
+    /// 当前连接或下次将要连接的服务器TCP地址，连接集群时会随重定向更新为领导者的地址
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        match self.options.endpoint {
+            Endpoint::Tcp(addr) => Some(addr),
+            #[cfg(unix)]
+            Endpoint::Unix(_) => None,
         }
     }
 
+    /// 让下一个请求使用给定的请求ID，而不是自动生成的ID
+    ///
+    /// 服务器在处理该请求时的日志中记录此ID，便于对照客户端与服务器的日志。
+    pub fn set_request_id(&mut self, request_id: impl Into<String>) {
+        self.request_id = Some(request_id.into());
+    }
+
+    /// 服务器在最近一个响应中返回的请求ID，服务器不支持请求ID时为None
+    pub fn last_request_id(&self) -> Option<&str> {
+        self.last_request_id.as_deref()
+    }
+
+    /// 当前是否持有与服务器的连接
+    ///
+    /// 通信出错后连接会被断开，直到下一个请求时才重新连接。
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// 从服务器获取给定键对应值
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let reply = self.request(&Request::Get { key })?;
+        value_reply(Ok(reply))
+    }
+
+    /// 删除服务器上的给定键
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let reply = self.request(&Request::Rm { key })?;
+        done_reply(Ok(reply))
+    }
+
     /// 设置服务器上的键值对
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = RmResponse::deserialize(&mut self.reader)?;
+        let req = if self.options.retries > 0 && self.features.iter().any(|f| f == "idempotency") {
+            Request::SetIdempotent {
+                key,
+                value,
+                token: self.next_token(),
+            }
+        } else {
+            Request::Set { key, value }
+        };
+        let reply = self.request(&req)?;
+        done_reply(Ok(reply))
+    }
+
+    /// 从服务器批量获取多个键对应的值
+    ///
+    /// 返回结果与给定键一一对应，单个键的错误不影响其余键。
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        let results = multi_reply(self.request(&Request::MGet { keys })?)?;
+        Ok(results.into_iter().map(value_reply).collect())
+    }
 
-        match resp {
-            RmResponse::Ok(_) => Ok(()),
-            RmResponse::Err(msg) => Err(KvsError::StringError(msg)),
+    /// 批量设置服务器上的键值对
+    ///
+    /// 返回结果与给定键值对一一对应，单个键的错误不影响其余键。
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        let results = multi_reply(self.request(&Request::MSet { pairs })?)?;
+        Ok(results.into_iter().map(done_reply).collect())
+    }
+
+    /// 批量删除服务器上的键
+    ///
+    /// 返回结果与给定键一一对应，不存在的键对应`KeyNotFound`错误。
+    pub fn mremove(&mut self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        let results = multi_reply(self.request(&Request::MRm { keys })?)?;
+        Ok(results.into_iter().map(done_reply).collect())
+    }
+
+    /// 创建一个请求流水线
+    ///
+    /// 流水线中的请求会被连续发送而无需逐个等待响应，
+    /// 服务器按接收顺序依次处理，因此响应的顺序与请求一致。
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
         }
     }
-}
\ No newline at end of file
+
+    /// 订阅以给定前缀开头的键的变更
+    ///
+    /// 返回的`Watch`独占当前连接，被丢弃后连接随之关闭，下一个请求会重新连接。
+    /// 服务器在没有事件时每秒发送一次心跳，因此读超时应大于1秒。
+    pub fn watch(&mut self, key_or_prefix: String) -> Result<Watch<'_>> {
+        let reply = self.request(&Request::Watch { key_or_prefix })?;
+        done_reply(Ok(reply))?;
+        Ok(Watch { client: self })
+    }
+
+    /// 订阅从给定序号开始的所有变更
+    ///
+    /// 服务器先推送保留的历史变更，再持续推送新的变更；只包含当前用户有权读取的键。
+    /// 连接的占用方式与`watch`相同。给定序号已被压缩删除时返回`KvsError::SequenceCompacted`。
+    pub fn changes(&mut self, from_seq: u64) -> Result<Changes<'_>> {
+        let reply = self.request(&Request::Changes { from_seq })?;
+        done_reply(Ok(reply))?;
+        Ok(Changes { client: self })
+    }
+
+    /// 获取服务器的版本、运行时间、连接数以及存储引擎的统计信息
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        match self.request(&Request::Stats)? {
+            Reply::Stats(stats) => Ok(*stats),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// 让服务器立即压缩存储引擎的日志，启用认证时须以管理员身份登录
+    pub fn compact(&mut self) -> Result<()> {
+        done_reply(Ok(self.request(&Request::Compact)?))
+    }
+
+    /// 让服务器把已写入的数据同步到磁盘
+    pub fn flush(&mut self) -> Result<()> {
+        done_reply(Ok(self.request(&Request::Flush)?))
+    }
+
+    /// 让服务器把数据的一致副本写入其所在主机上的给定目录，该目录须不存在或为空
+    pub fn backup(&mut self, dest: impl Into<PathBuf>) -> Result<()> {
+        done_reply(Ok(self.request(&Request::Backup { dest: dest.into() })?))
+    }
+
+    /// 获取服务器最近的慢请求记录，最新的在前；服务器未启用慢请求日志时为空
+    pub fn slow_log(&mut self) -> Result<Vec<SlowRequest>> {
+        match self.request(&Request::SlowLog)? {
+            Reply::SlowLog(entries) => Ok(entries),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// 获取服务器上全部键值对的一致快照，对每个键值对调用`f`
+    ///
+    /// 返回快照对应的序号，之后可以从该序号加一处调用`changes`继续同步。
+    /// 出错或`f`返回错误时停止读取并断开连接。
+    pub fn snapshot<F>(&mut self, mut f: F) -> Result<u64>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        // 先取出请求ID，以免重连时的认证请求用掉`set_request_id`指定的ID
+        let request_id = self.next_request_id();
+        self.ensure_connected()?;
+        let res = self.send(&Request::Snapshot, &request_id).and_then(|()| loop {
+            match self.read_response()?? {
+                Reply::SnapshotChunk(pairs) => {
+                    for (key, value) in pairs {
+                        f(key, value)?;
+                    }
+                }
+                Reply::SnapshotDone(seq) => return Ok(seq),
+                _ => return Err(KvsError::UnexpectedResponse),
+            }
+        });
+        if res.is_err() {
+            self.stream = None;
+        }
+        res
+    }
+
+    /// 发送单个请求并等待其响应，幂等请求在连接出错时按重试策略重试
+    ///
+    /// 集群节点不是领导者时请求未被执行，因此任何请求都会被重定向到领导者，
+    /// 或在选举期间等待后重试。
+    fn request(&mut self, req: &Request) -> Result<Reply> {
+        // 重试与重定向沿用同一个请求ID
+        let request_id = self.next_request_id();
+        let mut attempt = 0;
+        let mut redirects = 0;
+        loop {
+            match self.ensure_connected().and_then(|()| self.round_trip(req, &request_id)) {
+                Ok(Ok(reply)) => return Ok(reply),
+                Ok(Err(err)) => match KvsError::from(err) {
+                    KvsError::NotLeader(leader) if redirects < MAX_REDIRECTS => {
+                        // 刚失效的领导者可能仍被其他节点当作领导者，逐渐拉长等待以便完成选举
+                        thread::sleep(self.options.retry_backoff * redirects);
+                        redirects += 1;
+                        if let Some(addr) = leader {
+                            self.options.endpoint = Endpoint::Tcp(addr);
+                            self.stream = None;
+                        }
+                    }
+                    err => return Err(err),
+                },
+                Err(e) if attempt < self.options.retries && req.is_idempotent() && is_retryable(&e) => {
+                    attempt += 1;
+                    thread::sleep(self.options.retry_backoff * attempt);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 在当前连接上发送请求并读取响应，通信出错时断开连接
+    fn round_trip(&mut self, req: &Request, request_id: &str) -> Result<ReplyResult> {
+        let res = self.send(req, request_id).and_then(|()| self.read_response());
+        if res.is_err() {
+            self.stream = None;
+        }
+        res
+    }
+
+    fn send(&mut self, req: &Request, request_id: &str) -> Result<()> {
+        self.write_request(req, request_id)?;
+        self.flush_stream()
+    }
+
+    /// 写入一个请求，服务器支持请求ID时以附带给定ID的`Request::Traced`发送
+    fn write_request(&mut self, req: &Request, request_id: &str) -> Result<()> {
+        let codec = self.codec;
+        let written = if self.features.iter().any(|f| f == "request-id") {
+            write_frame(self.stream()?, codec, &TracedRequest { request_id, request: req })
+        } else {
+            write_frame(self.stream()?, codec, req)
+        };
+        written.map(|_| ()).map_err(|e| timeout_as(e, KvsError::WriteTimeout))
+    }
+
+    /// 若连接已断开则重新连接，并以之前认证过的身份重新认证
+    fn ensure_connected(&mut self) -> Result<()> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
+        let (stream, ack) = match self.options.open() {
+            Err(e) if !self.options.cluster.is_empty() && is_retryable(&e) => self.failover(e)?,
+            res => res?,
+        };
+        self.stream = Some(stream);
+        self.codec = ack.codec;
+        self.version = ack.version;
+        self.features = ack.features;
+
+        if let Some((user, password)) = self.credentials.clone() {
+            let request_id = self.next_request_id();
+            let result = self.round_trip(&Request::Auth { user, password }, &request_id);
+            if let Err(e) = result.and_then(done_reply) {
+                self.stream = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// 当前节点不可用时依次连接集群中的其余节点，全部失败时返回最后一个错误
+    fn failover(&mut self, mut err: KvsError) -> Result<(BufStream<Box<dyn ReadWrite>>, HelloAck)> {
+        let current = self.server_addr();
+        for addr in self.options.cluster.clone() {
+            if Some(addr) == current {
+                continue;
+            }
+            self.options.endpoint = Endpoint::Tcp(addr);
+            match self.options.open() {
+                Ok(opened) => return Ok(opened),
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+
+    fn flush_stream(&mut self) -> Result<()> {
+        self.stream()?
+            .flush()
+            .map_err(|e| timeout_as(e.into(), KvsError::WriteTimeout))
+    }
+
+    fn stream(&mut self) -> Result<&mut BufStream<Box<dyn ReadWrite>>> {
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected).into())
+    }
+
+    /// 读取一个响应
+    ///
+    /// 外层错误表示通信失败或协议版本不匹配，内层错误为服务器返回的错误。
+    fn read_response(&mut self) -> Result<ReplyResult> {
+        let frame = read_frame(self.stream()?)
+            .map_err(|e| timeout_as(e, KvsError::ReadTimeout))?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let resp: Response = self.codec.decode(&frame)?;
+        if resp.version != self.version {
+            return Err(KvsError::UnsupportedVersion(resp.version));
+        }
+        match resp.result {
+            Ok(Reply::Traced { request_id, result }) => {
+                self.last_request_id = Some(request_id);
+                Ok(*result)
+            }
+            result => Ok(result),
+        }
+    }
+
+    /// 读取订阅连接上推送的下一个事件，跳过心跳；出错时断开连接
+    fn read_pushed(&mut self) -> Option<Result<Reply>> {
+        if !self.is_connected() {
+            return None;
+        }
+        let res = loop {
+            match self.read_response().and_then(|result| Ok(result?)) {
+                Ok(Reply::Heartbeat) => continue,
+                res => break res,
+            }
+        };
+        if res.is_err() {
+            self.stream = None;
+        }
+        Some(res)
+    }
+
+    fn next_token(&mut self) -> String {
+        self.next_token += 1;
+        format!("{:016x}{:016x}", self.client_id, self.next_token)
+    }
+
+    /// 取出`set_request_id`指定的请求ID，未指定时生成新的ID
+    fn next_request_id(&mut self) -> String {
+        self.request_id.take().unwrap_or_else(|| {
+            self.next_request += 1;
+            format!("{:016x}-{}", self.client_id, self.next_request)
+        })
+    }
+
+    fn read_pipeline_response(&mut self, kind: PipelineKind) -> Result<Result<PipelineResponse>> {
+        let result = self.read_response()?;
+        let resp = match kind {
+            PipelineKind::Get => value_reply(result).map(PipelineResponse::Get),
+            PipelineKind::Set => done_reply(result).map(|_| PipelineResponse::Set),
+            PipelineKind::Remove => done_reply(result).map(|_| PipelineResponse::Remove),
+        };
+        Ok(resp)
+    }
+}
+
+/// 将socket超时产生的I/O错误转换为对应的超时错误
+fn timeout_as(err: KvsError, timeout: KvsError) -> KvsError {
+    match err {
+        // 设置了超时的socket在不同平台上分别返回WouldBlock或TimedOut
+        KvsError::Io(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => timeout,
+        err => err,
+    }
+}
+
+/// 连接失败或超时后重新连接可能成功，其余错误重试无益
+fn is_retryable(err: &KvsError) -> bool {
+    matches!(
+        err,
+        KvsError::Io(_) | KvsError::ConnectTimeout | KvsError::ReadTimeout | KvsError::WriteTimeout
+    )
+}
+
+/// 生成进程内各客户端互不相同的随机标识
+fn random_id() -> u64 {
+    // 每个RandomState使用不同的随机密钥
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(process::id());
+    if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+    hasher.finish()
+}
+
+/// 将`Get`请求的结果转换为对应的值
+fn value_reply(result: ReplyResult) -> Result<Option<String>> {
+    match result? {
+        Reply::Value(value) => Ok(value),
+        _ => Err(KvsError::UnexpectedResponse),
+    }
+}
+
+/// 将无返回值请求的结果转换为`()`
+fn done_reply(result: ReplyResult) -> Result<()> {
+    match result? {
+        Reply::Done => Ok(()),
+        _ => Err(KvsError::UnexpectedResponse),
+    }
+}
+
+/// 取出多键请求中各个键的结果
+fn multi_reply(reply: Reply) -> Result<Vec<ReplyResult>> {
+    match reply {
+        Reply::Multi(results) => Ok(results),
+        _ => Err(KvsError::UnexpectedResponse),
+    }
+}
+
+/// 变更事件流，由`KvsClient::watch`创建
+///
+/// 迭代时阻塞等待下一个事件，连接出错或订阅被服务器关闭时返回错误，之后迭代结束。
+pub struct Watch<'a> {
+    client: &'a mut KvsClient,
+}
+
+impl Iterator for Watch<'_> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        self.client.read_pushed().map(|reply| match reply? {
+            Reply::Event(event) => Ok(event),
+            _ => Err(KvsError::UnexpectedResponse),
+        })
+    }
+}
+
+impl Drop for Watch<'_> {
+    /// 连接已被订阅占用，无法再用于普通请求
+    fn drop(&mut self) {
+        self.client.stream = None;
+    }
+}
+
+/// 带序号的变更流，由`KvsClient::changes`创建
+///
+/// 与`Watch`相同，出错后迭代结束，被丢弃后连接随之关闭。
+pub struct Changes<'a> {
+    client: &'a mut KvsClient,
+}
+
+impl Iterator for Changes<'_> {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        self.client.read_pushed().map(|reply| match reply? {
+            Reply::Change(change) => Ok(change),
+            _ => Err(KvsError::UnexpectedResponse),
+        })
+    }
+}
+
+impl Drop for Changes<'_> {
+    fn drop(&mut self) {
+        self.client.stream = None;
+    }
+}
+
+/// 流水线中单个请求的响应
+#[derive(Debug, PartialEq, Eq)]
+pub enum PipelineResponse {
+    /// `get`请求返回的值
+    Get(Option<String>),
+    /// `set`请求成功
+    Set,
+    /// `remove`请求成功
+    Remove,
+}
+
+/// 已发送请求的类型，用于确定如何解析对应的响应
+#[derive(Clone, Copy)]
+enum PipelineKind {
+    Get,
+    Set,
+    Remove,
+}
+
+/// 请求流水线，由`KvsClient::pipeline`创建
+///
+/// 先通过`get`、`set`、`remove`将请求加入队列，
+/// 再调用`execute`一次性发送并按顺序收集所有响应。
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<(PipelineKind, Request)>,
+}
+
+impl Pipeline<'_> {
+    /// 将获取给定键的请求加入队列
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push((PipelineKind::Get, Request::Get { key }));
+        self
+    }
+
+    /// 将设置键值对的请求加入队列
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push((PipelineKind::Set, Request::Set { key, value }));
+        self
+    }
+
+    /// 将删除给定键的请求加入队列
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push((PipelineKind::Remove, Request::Rm { key }));
+        self
+    }
+
+    /// 返回队列中的请求数量
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// 队列中是否没有请求
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// 发送队列中的所有请求，并按请求顺序返回各自的响应
+    ///
+    /// # Errors
+    ///
+    /// 外层错误表示与服务器的通信失败，此时无法确定哪些请求已被执行；
+    /// 内层错误为服务器针对单个请求返回的错误，不影响其余请求。
+    pub fn execute(self) -> Result<Vec<Result<PipelineResponse>>> {
+        let client = self.client;
+        client.ensure_connected()?;
+        let res = Self::send_all(client, self.requests);
+        if res.is_err() {
+            client.stream = None;
+        }
+        res
+    }
+
+    fn send_all(client: &mut KvsClient, requests: Vec<(PipelineKind, Request)>) -> Result<Vec<Result<PipelineResponse>>> {
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut in_flight = VecDeque::with_capacity(PIPELINE_WINDOW);
+
+        for (kind, req) in requests {
+            let request_id = client.next_request_id();
+            client.write_request(&req, &request_id)?;
+            in_flight.push_back(kind);
+
+            if in_flight.len() >= PIPELINE_WINDOW {
+                client.flush_stream()?;
+                let kind = in_flight.pop_front().unwrap();
+                responses.push(client.read_pipeline_response(kind)?);
+            }
+        }
+        client.flush_stream()?;
+
+        while let Some(kind) = in_flight.pop_front() {
+            responses.push(client.read_pipeline_response(kind)?);
+        }
+
+        Ok(responses)
+    }
+}
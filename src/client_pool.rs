@@ -0,0 +1,297 @@
+//! 可在多个线程间共享的`KvsClient`连接池
+
+use crate::client::{KvsClient, KvsClientBuilder};
+use crate::error::{KvsError, Result};
+
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 默认的最大连接数
+const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+/// 默认的空闲连接保留时间
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// 默认的取连接等待时间
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 后台清理空闲连接的最长检查间隔
+const MAX_EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `KvsClientPool`的构建器
+pub struct KvsClientPoolBuilder {
+    client: KvsClientBuilder,
+    min_connections: usize,
+    max_connections: usize,
+    idle_timeout: Option<Duration>,
+    checkout_timeout: Duration,
+    health_check: bool,
+}
+
+impl KvsClientPoolBuilder {
+    /// 连接池保持的最少连接数，创建连接池时即建立这些连接
+    pub fn min_connections(mut self, min: usize) -> Self {
+        self.min_connections = min;
+        self
+    }
+
+    /// 连接池最多同时持有的连接数，包括已借出的连接
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// 空闲超过该时间的连接会被关闭，但连接数不会低于最少连接数
+    ///
+    /// 为None时不清理空闲连接。
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// 所有连接都被借出时等待归还的最长时间，超时返回`KvsError::PoolTimeout`
+    pub fn checkout_timeout(mut self, timeout: Duration) -> Self {
+        self.checkout_timeout = timeout;
+        self
+    }
+
+    /// 是否在借出空闲连接前发送`ping`检查其可用性
+    pub fn health_check(mut self, enabled: bool) -> Self {
+        self.health_check = enabled;
+        self
+    }
+
+    /// 建立最少数量的连接并启动后台清理线程
+    pub fn build(self) -> Result<Arc<KvsClientPool>> {
+        if self.max_connections == 0 || self.min_connections > self.max_connections {
+            return Err(KvsError::StringError(format!(
+                "Invalid pool size: min {} max {}",
+                self.min_connections, self.max_connections
+            )));
+        }
+
+        let mut idle = VecDeque::with_capacity(self.max_connections);
+        for _ in 0..self.min_connections {
+            idle.push_back(IdleClient::new(self.client.clone().build()?));
+        }
+        let pool = Arc::new(KvsClientPool {
+            state: Mutex::new(PoolState {
+                total: idle.len(),
+                idle,
+            }),
+            returned: Condvar::new(),
+            client: self.client,
+            min_connections: self.min_connections,
+            max_connections: self.max_connections,
+            idle_timeout: self.idle_timeout,
+            checkout_timeout: self.checkout_timeout,
+            health_check: self.health_check,
+        });
+
+        if let Some(idle_timeout) = pool.idle_timeout {
+            let weak = Arc::downgrade(&pool);
+            let interval = idle_timeout.min(MAX_EVICTION_INTERVAL);
+            thread::Builder::new()
+                .name("kvs-pool-evictor".to_owned())
+                .spawn(move || evict_loop(weak, interval))?;
+        }
+        Ok(pool)
+    }
+}
+
+/// 线程安全的`KvsClient`连接池
+///
+/// 通过`Arc`在线程间共享，`get`借出的连接在离开作用域时自动归还。
+///
+/// ```no_run
+/// # use kvs::{KvsClientBuilder, KvsClientPool, Result};
+/// # fn main() -> Result<()> {
+/// let pool = KvsClientPool::builder(KvsClientBuilder::new("127.0.0.1:4000".parse().unwrap()))
+///     .max_connections(8)
+///     .build()?;
+/// let mut client = pool.get()?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClientPool {
+    state: Mutex<PoolState>,
+    /// 有连接归还或连接数减少时通知等待者
+    returned: Condvar,
+    client: KvsClientBuilder,
+    min_connections: usize,
+    max_connections: usize,
+    idle_timeout: Option<Duration>,
+    checkout_timeout: Duration,
+    health_check: bool,
+}
+
+struct PoolState {
+    /// 最近归还的连接位于末尾
+    idle: VecDeque<IdleClient>,
+    /// 空闲与已借出的连接总数，包括正在建立的连接
+    total: usize,
+}
+
+struct IdleClient {
+    client: KvsClient,
+    since: Instant,
+}
+
+impl IdleClient {
+    fn new(client: KvsClient) -> Self {
+        IdleClient {
+            client,
+            since: Instant::now(),
+        }
+    }
+}
+
+impl KvsClientPool {
+    /// 使用给定的客户端配置创建连接池构建器
+    pub fn builder(client: KvsClientBuilder) -> KvsClientPoolBuilder {
+        KvsClientPoolBuilder {
+            client,
+            min_connections: 0,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            checkout_timeout: DEFAULT_CHECKOUT_TIMEOUT,
+            health_check: true,
+        }
+    }
+
+    /// 借出一个连接
+    ///
+    /// 优先复用最近归还的空闲连接，未通过健康检查的连接会被丢弃；
+    /// 没有空闲连接且未达到最大连接数时建立新连接，否则等待其他线程归还。
+    pub fn get(&self) -> Result<PooledClient<'_>> {
+        let deadline = Instant::now() + self.checkout_timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(idle) = state.idle.pop_back() {
+                drop(state);
+                let mut client = idle.client;
+                if !self.health_check || client.ping().is_ok() {
+                    return Ok(self.pooled(client));
+                }
+                state = self.lock();
+                self.discard(&mut state);
+                continue;
+            }
+
+            if state.total < self.max_connections {
+                state.total += 1;
+                drop(state);
+                return match self.client.clone().build() {
+                    Ok(client) => Ok(self.pooled(client)),
+                    Err(e) => {
+                        self.discard(&mut self.lock());
+                        Err(e)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KvsError::PoolTimeout);
+            }
+            state = self.returned.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// 当前持有的连接总数，包括已借出的连接
+    pub fn connections(&self) -> usize {
+        self.lock().total
+    }
+
+    /// 当前空闲的连接数
+    pub fn idle_connections(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    fn put_back(&self, client: KvsClient) {
+        let mut state = self.lock();
+        // 通信出错的连接已断开，直接丢弃以免下次借出时再重连
+        if client.is_connected() {
+            state.idle.push_back(IdleClient::new(client));
+            self.returned.notify_one();
+        } else {
+            self.discard(&mut state);
+        }
+    }
+
+    /// 关闭一个连接后更新连接数，并唤醒等待建立新连接的线程
+    fn discard(&self, state: &mut PoolState) {
+        state.total -= 1;
+        self.returned.notify_one();
+    }
+
+    /// 关闭空闲过久的连接
+    fn evict_idle(&self) {
+        let idle_timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let mut state = self.lock();
+        // 最早归还的连接位于队首
+        while state.total > self.min_connections
+            && state.idle.front().is_some_and(|idle| idle.since.elapsed() >= idle_timeout)
+        {
+            state.idle.pop_front();
+            self.discard(&mut state);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap()
+    }
+}
+
+fn evict_loop(pool: Weak<KvsClientPool>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        // 连接池已被释放时退出
+        match pool.upgrade() {
+            Some(pool) => pool.evict_idle(),
+            None => return,
+        }
+    }
+}
+
+/// 从连接池借出的连接，离开作用域时归还连接池
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.put_back(client);
+        }
+    }
+}
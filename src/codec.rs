@@ -0,0 +1,104 @@
+//! 网络协议的帧格式与编解码方式
+
+use crate::error::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// 单个帧负载的最大字节数
+///
+/// 超过该长度的帧头几乎可以肯定来自损坏的数据流，此时连接无法再同步，只能关闭。
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// 帧负载的编码方式
+///
+/// 每个帧由4字节大端序的负载长度和负载组成，负载按协商好的编码方式序列化。
+/// 握手帧始终使用JSON编码，之后的请求与响应使用握手时协商出的编码方式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Codec {
+    /// 紧凑的二进制编码，默认使用
+    Bincode,
+    /// 便于调试的JSON编码
+    Json,
+}
+
+impl Codec {
+    /// 序列化给定值
+    pub(crate) fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::Bincode => Ok(bincode::serialize(value)?),
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+        }
+    }
+
+    /// 反序列化给定负载
+    pub(crate) fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        match self {
+            Codec::Bincode => Ok(bincode::deserialize(payload)?),
+            Codec::Json => Ok(serde_json::from_slice(payload)?),
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Codec::Bincode => write!(f, "bincode"),
+            Codec::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bincode" => Ok(Codec::Bincode),
+            "json" => Ok(Codec::Json),
+            _ => Err(KvsError::StringError(format!("Unknown codec: {}", s))),
+        }
+    }
+}
+
+/// 将给定值按编码方式序列化后作为一个帧写入，不刷新写入器
+///
+/// 返回写入的字节数，包括帧头。
+pub(crate) fn write_frame<W: Write, T: Serialize>(writer: &mut W, codec: Codec, value: &T) -> Result<usize> {
+    let payload = codec.encode(value)?;
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(KvsError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes exceeds the limit", payload.len()),
+        )));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(4 + payload.len())
+}
+
+/// 读取一个帧的负载
+///
+/// 若对端在帧边界处关闭连接，则返回None。
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len_buf);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds the limit", len),
+        )));
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
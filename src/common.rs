@@ -1,26 +1,432 @@
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeStructVariant;
+use serde::{Deserialize, Serialize, Serializer};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::codec::Codec;
+use crate::engines::{Change, EngineStats, Event};
+use crate::error::KvsError;
+
+/// 当前协议版本号，随每个响应一同发送
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// 仍然支持的最低协议版本号
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// 服务器支持的可选功能，握手时取双方的交集
+pub const FEATURES: &[&str] = &["multi-key", "auth", "idempotency", "watch", "changes", "snapshot", "stats", "admin", "request-id"];
+
+/// 客户端建立连接后发送的第一个帧，始终使用JSON编码
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    /// 按优先级排列的编码方式
+    pub codecs: Vec<Codec>,
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(codec: Codec) -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            codecs: vec![codec],
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+/// 服务器对`Hello`的回复，始终使用JSON编码
+///
+/// 若`version`不在客户端支持的范围内，说明协商失败，服务器随后会关闭连接。
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HelloAck {
+    pub version: u32,
+    pub codec: Codec,
+    pub features: Vec<String>,
+    /// 服务器拒绝连接的原因，如连接数已满，随后服务器会关闭连接
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RemoteError>,
+}
+
+impl HelloAck {
+    /// 根据客户端的`Hello`协商协议版本、编码方式与功能
+    pub fn negotiate(hello: &Hello) -> Self {
+        let version = PROTOCOL_VERSION.min(hello.max_version);
+        if version < MIN_PROTOCOL_VERSION.max(hello.min_version) {
+            // 没有双方都支持的版本，返回服务器自身的版本告知客户端
+            return HelloAck {
+                version: PROTOCOL_VERSION,
+                codec: Codec::Json,
+                features: Vec::new(),
+                error: None,
+            };
+        }
+
+        // JSON编码始终可用，作为客户端未给出可用编码时的后备
+        let codec = hello.codecs.first().copied().unwrap_or(Codec::Json);
+        let features = hello
+            .features
+            .iter()
+            .filter(|f| FEATURES.contains(&f.as_str()))
+            .cloned()
+            .collect();
+        HelloAck {
+            version,
+            codec,
+            features,
+            error: None,
+        }
+    }
+
+    /// 拒绝连接时代替正常握手结果发送
+    pub fn rejected(error: RemoteError) -> Self {
+        HelloAck {
+            version: PROTOCOL_VERSION,
+            codec: Codec::Json,
+            features: Vec::new(),
+            error: Some(error),
+        }
+    }
+
+    /// 协商出的版本是否在客户端支持的范围内
+    pub fn is_accepted(&self, hello: &Hello) -> bool {
+        hello.min_version <= self.version && self.version <= hello.max_version
+    }
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
     Get { key: String },
     Set { key: String, value: String },
     Rm { key: String },
+    MGet { keys: Vec<String> },
+    MSet { pairs: Vec<(String, String)> },
+    MRm { keys: Vec<String> },
+    /// 以给定用户身份认证，之后的请求按该用户的权限检查
+    Auth { user: String, password: String },
+    /// 带幂等令牌的`Set`，服务器对重复的令牌不再重复执行
+    SetIdempotent { key: String, value: String, token: String },
+    /// 检查连接是否可用，服务器直接回复`Done`
+    Ping,
+    /// 订阅以给定前缀开头的键的变更
+    ///
+    /// 服务器先回复`Done`，之后该连接只用于推送`Event`与`Heartbeat`，直到连接关闭。
+    Watch { key_or_prefix: String },
+    /// 订阅从给定序号开始的所有变更，连接的用法与`Watch`相同，推送的是`Change`
+    Changes { from_seq: u64 },
+    /// 获取全部键值对的一致快照
+    ///
+    /// 服务器以若干`SnapshotChunk`回复，最后以带快照序号的`SnapshotDone`结束。
+    Snapshot,
+    /// 获取服务器与存储引擎的状态，启用认证时须先登录
+    Stats,
+    /// 立即压缩存储引擎的日志，须有管理权限
+    Compact,
+    /// 把存储引擎已写入的数据同步到磁盘，须有管理权限
+    Flush,
+    /// 把存储引擎数据的一致副本写入服务器上的给定目录，须有管理权限
+    Backup { dest: PathBuf },
+    /// 获取最近的慢请求记录，最新的在前，须有管理权限
+    SlowLog,
+    /// 附带请求ID的请求，服务器在日志中记录该ID，并在`Reply::Traced`中原样返回
+    Traced { request_id: String, request: Box<Request> },
 }
 
+impl Request {
+    /// 请求类型的名称，用于监控指标与日志
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Rm { .. } => "rm",
+            Request::MGet { .. } => "mget",
+            Request::MSet { .. } => "mset",
+            Request::MRm { .. } => "mrm",
+            Request::Auth { .. } => "auth",
+            Request::SetIdempotent { .. } => "set_idempotent",
+            Request::Ping => "ping",
+            Request::Watch { .. } => "watch",
+            Request::Changes { .. } => "changes",
+            Request::Snapshot => "snapshot",
+            Request::Stats => "stats",
+            Request::Compact => "compact",
+            Request::Flush => "flush",
+            Request::Backup { .. } => "backup",
+            Request::SlowLog => "slow_log",
+            Request::Traced { request, .. } => request.name(),
+        }
+    }
+
+    /// 请求涉及的键，多键请求只取第一个键并注明其余键的数量，用于日志
+    pub fn key_summary(&self) -> Option<String> {
+        let (first, count) = match self {
+            Request::Get { key } | Request::Set { key, .. } | Request::Rm { key } => (key, 1),
+            Request::SetIdempotent { key, .. } => (key, 1),
+            Request::Watch { key_or_prefix } => (key_or_prefix, 1),
+            Request::MGet { keys } | Request::MRm { keys } => (keys.first()?, keys.len()),
+            Request::MSet { pairs } => (&pairs.first()?.0, pairs.len()),
+            Request::Traced { request, .. } => return request.key_summary(),
+            _ => return None,
+        };
+        match count {
+            1 => Some(first.clone()),
+            count => Some(format!("{} (+{} more)", first, count - 1)),
+        }
+    }
+
+    /// 重复执行是否与执行一次效果相同，只有这类请求会在连接失败后被重试
+    pub fn is_idempotent(&self) -> bool {
+        if let Request::Traced { request, .. } = self {
+            return request.is_idempotent();
+        }
+        matches!(
+            self,
+            Request::Get { .. }
+                | Request::MGet { .. }
+                | Request::Auth { .. }
+                | Request::SetIdempotent { .. }
+                | Request::Ping
+                | Request::Watch { .. }
+                | Request::Changes { .. }
+                | Request::Snapshot
+                | Request::Stats
+                | Request::Compact
+                | Request::Flush
+                | Request::SlowLog
+        )
+    }
+}
+
+/// `Request::Traced`在枚举中的序号，变体只在末尾追加，因此该序号不会改变
+const TRACED_VARIANT_INDEX: u32 = 17;
+
+/// 借用形式的`Request::Traced`，编码结果与之相同，发送时无需克隆内层请求
+pub(crate) struct TracedRequest<'a> {
+    pub request_id: &'a str,
+    pub request: &'a Request,
+}
+
+impl Serialize for TracedRequest<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut variant = serializer.serialize_struct_variant("Request", TRACED_VARIANT_INDEX, "Traced", 2)?;
+        variant.serialize_field("request_id", self.request_id)?;
+        variant.serialize_field("request", self.request)?;
+        variant.end()
+    }
+}
+
+/// 所有请求共用的响应信封
 #[derive(Debug, Deserialize, Serialize)]
-pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String),
+pub struct Response {
+    pub version: u32,
+    pub result: ReplyResult,
+}
+
+impl Response {
+    pub fn new(result: ReplyResult) -> Self {
+        Response {
+            version: PROTOCOL_VERSION,
+            result,
+        }
+    }
 }
 
+/// 单个请求（或多键请求中单个键）的执行结果
+pub type ReplyResult = std::result::Result<Reply, RemoteError>;
+
 #[derive(Debug, Deserialize, Serialize)]
-pub enum SetResponse {
-    Ok(()),
-    Err(String),
+pub enum Reply {
+    /// 请求执行成功且无返回值，对应`Set`与`Rm`
+    Done,
+    /// `Get`返回的值
+    Value(Option<String>),
+    /// 多键请求中各个键的结果，顺序与请求中的键一致
+    Multi(Vec<ReplyResult>),
+    /// `Watch`推送的变更事件
+    Event(Event),
+    /// `Changes`推送的带序号的变更
+    Change(Change),
+    /// `Snapshot`回复中的一批键值对
+    SnapshotChunk(Vec<(String, String)>),
+    /// `Snapshot`回复的结尾，包含快照对应的序号
+    SnapshotDone(u64),
+    /// `Watch`与`Changes`在没有事件时定期推送，用于发现已断开的连接
+    Heartbeat,
+    /// `Stats`返回的服务器状态
+    Stats(Box<ServerStats>),
+    /// `SlowLog`返回的慢请求记录
+    SlowLog(Vec<SlowRequest>),
+    /// 对`Request::Traced`的回复，包含请求ID与内层请求的执行结果
+    Traced { request_id: String, result: Box<ReplyResult> },
 }
 
+/// 一条慢请求记录
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SlowRequest {
+    /// 请求完成的时间
+    pub at: SystemTime,
+    /// 请求类型
+    pub command: String,
+    /// 请求涉及的键，过长时被截断
+    pub key: Option<String>,
+    /// 客户端地址
+    pub peer: String,
+    /// 已登录的用户
+    pub user: Option<String>,
+    /// 从读到请求到写完响应的总耗时，包括连接的排队时间
+    pub total: Duration,
+    /// 连接等待工作线程的时间，只计入连接上的第一个请求
+    pub queue_wait: Duration,
+    /// 检查权限并执行请求的时间
+    pub engine: Duration,
+    /// 写回响应的时间
+    pub write_back: Duration,
+}
+
+/// `Stats`请求返回的服务器状态
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServerStats {
+    /// 服务器的版本号
+    pub version: String,
+    /// 服务器已运行的时间
+    pub uptime: Duration,
+    /// 存储引擎的统计信息，引擎不支持统计时为None
+    pub engine: Option<EngineStats>,
+    /// 当前的连接数，包括排队中的连接
+    pub connections: u64,
+    /// 排队等待工作线程的连接数
+    pub queued_connections: u64,
+    /// 启动以来接受的连接总数
+    pub accepted_connections: u64,
+    /// 启动以来因服务器繁忙被拒绝的连接数
+    pub rejected_connections: u64,
+}
+
+/// 错误类型编码，客户端据此还原出对应的`KvsError`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorCode {
+    KeyNotFound,
+    UnexpectedCommandType,
+    Io,
+    Serde,
+    Engine,
+    Utf8,
+    AuthFailed,
+    PermissionDenied,
+    ServerBusy,
+    Throttled,
+    WatchClosed,
+    SequenceCompacted,
+    ReadOnly,
+    NotLeader,
+    CommitTimeout,
+    Internal,
+    IdempotencyConflict,
+    Tls,
+    ConnectTimeout,
+    ReadTimeout,
+    WriteTimeout,
+    Unsupported,
+    PoolTimeout,
+    UnexpectedResponse,
+    UnsupportedVersion,
+}
+
+/// 服务器返回的错误
 #[derive(Debug, Deserialize, Serialize)]
-pub enum RmResponse {
-    Ok(()),
-    Err(String),
+pub struct RemoteError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl From<KvsError> for RemoteError {
+    // 不使用通配分支：新增的错误类型必须决定其在协议中的编码
+    fn from(err: KvsError) -> Self {
+        let code = match err {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::UnexpectedCommandType => ErrorCode::UnexpectedCommandType,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Serde(_) | KvsError::Bincode(_) => ErrorCode::Serde,
+            KvsError::Tls(_) => ErrorCode::Tls,
+            KvsError::SledError(_) => ErrorCode::Engine,
+            KvsError::Utf8(_) => ErrorCode::Utf8,
+            KvsError::AuthFailed => ErrorCode::AuthFailed,
+            KvsError::PermissionDenied => ErrorCode::PermissionDenied,
+            KvsError::ServerBusy => ErrorCode::ServerBusy,
+            KvsError::Throttled => ErrorCode::Throttled,
+            KvsError::ConnectTimeout => ErrorCode::ConnectTimeout,
+            KvsError::ReadTimeout => ErrorCode::ReadTimeout,
+            KvsError::WriteTimeout => ErrorCode::WriteTimeout,
+            KvsError::WatchClosed => ErrorCode::WatchClosed,
+            KvsError::SequenceCompacted => ErrorCode::SequenceCompacted,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::CommitTimeout => ErrorCode::CommitTimeout,
+            KvsError::PoolTimeout => ErrorCode::PoolTimeout,
+            KvsError::UnexpectedResponse => ErrorCode::UnexpectedResponse,
+            KvsError::IdempotencyConflict => ErrorCode::IdempotencyConflict,
+            KvsError::StringError(_) => ErrorCode::Internal,
+            // 以下错误的参数放在错误信息中，客户端据此还原；领导者地址用于重定向
+            KvsError::NotLeader(leader) => {
+                return RemoteError {
+                    code: ErrorCode::NotLeader,
+                    message: leader.map(|addr| addr.to_string()).unwrap_or_default(),
+                }
+            }
+            KvsError::Unsupported(operation) => {
+                return RemoteError {
+                    code: ErrorCode::Unsupported,
+                    message: operation,
+                }
+            }
+            KvsError::UnsupportedVersion(version) => {
+                return RemoteError {
+                    code: ErrorCode::UnsupportedVersion,
+                    message: version.to_string(),
+                }
+            }
+        };
+        RemoteError {
+            code,
+            message: format!("{}", err),
+        }
+    }
+}
+
+impl From<RemoteError> for KvsError {
+    fn from(err: RemoteError) -> Self {
+        match err.code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::UnexpectedCommandType => KvsError::UnexpectedCommandType,
+            ErrorCode::AuthFailed => KvsError::AuthFailed,
+            ErrorCode::PermissionDenied => KvsError::PermissionDenied,
+            ErrorCode::ServerBusy => KvsError::ServerBusy,
+            ErrorCode::Throttled => KvsError::Throttled,
+            ErrorCode::WatchClosed => KvsError::WatchClosed,
+            ErrorCode::SequenceCompacted => KvsError::SequenceCompacted,
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::NotLeader => KvsError::NotLeader(err.message.parse().ok()),
+            ErrorCode::CommitTimeout => KvsError::CommitTimeout,
+            ErrorCode::IdempotencyConflict => KvsError::IdempotencyConflict,
+            ErrorCode::ConnectTimeout => KvsError::ConnectTimeout,
+            ErrorCode::ReadTimeout => KvsError::ReadTimeout,
+            ErrorCode::WriteTimeout => KvsError::WriteTimeout,
+            ErrorCode::PoolTimeout => KvsError::PoolTimeout,
+            ErrorCode::UnexpectedResponse => KvsError::UnexpectedResponse,
+            ErrorCode::Unsupported => KvsError::Unsupported(err.message),
+            ErrorCode::UnsupportedVersion => match err.message.parse() {
+                Ok(version) => KvsError::UnsupportedVersion(version),
+                Err(_) => KvsError::StringError(err.message),
+            },
+            ErrorCode::Tls => KvsError::Tls(rustls::Error::General(err.message)),
+            ErrorCode::Io => KvsError::Io(io::Error::other(err.message)),
+            ErrorCode::Serde => KvsError::Serde(serde::de::Error::custom(err.message)),
+            // 以下错误无法在客户端重建原始类型，仅保留错误信息
+            ErrorCode::Engine | ErrorCode::Utf8 | ErrorCode::Internal => {
+                KvsError::StringError(err.message)
+            }
+        }
+    }
 }
@@ -1,14 +1,18 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use super::KvsEngine;
+use super::watch::{Change, Event, Subscribers};
+use super::{create_backup_dir, ChangeStream, EngineStats, KvsEngine, Snapshot, Watcher};
 use crate::{KvsError, Result};
 
 use serde::{Deserialize, Serialize};
@@ -18,15 +22,30 @@ use crossbeam_skiplist::SkipMap;
 /// 冗余log文件内存大小上限
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+/// 压缩时最多保留的近期记录数，使`changes`仍能从较近的序号开始读取
+const HISTORY_OPS: usize = 1000;
+
+/// 压缩时保留的近期记录最多占用的字节数
+const HISTORY_BYTES: u64 = COMPACTION_THRESHOLD;
+
+/// 只读模式下检查日志是否有新记录的间隔
+const TAIL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// KvStore多线程安全共享的实现
 #[derive(Clone)]
 pub struct KvStore {
-    // 存储log文件的目录路径
-    path: Arc<PathBuf>,
     // log文件编号到文件读取器的映射
     index: Arc<SkipMap<String, OperationPos>>,
     reader: KvStoreReader,
-    writer: Arc<Mutex<KvStoreWriter>>,
+    mode: Mode,
+    subscribers: Arc<Subscribers>,
+}
+
+/// 写入由本实例负责，或只跟踪其他进程写入的日志
+#[derive(Clone)]
+enum Mode {
+    Writable(Arc<Mutex<KvStoreWriter>>),
+    ReadOnly(Arc<Mutex<Tailer>>),
 }
 
 impl KvStore {
@@ -40,16 +59,19 @@ impl KvStore {
 
         let gen_list = sorted_gen_list(&path)?;
         let mut uncompacted = 0;
+        let mut seq = SeqState::default();
+        let mut history = History::default();
 
         for &gen in gen_list.iter() {
             let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+            uncompacted += load(gen, &mut reader, &index, &mut seq, &mut history)?;
             readers.insert(gen, reader);
         }
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
         let writer = new_log_file(&path, current_gen)?;
         let safe_point = Arc::new(AtomicU64::new(0));
+        let subscribers = Arc::new(Subscribers::default());
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
@@ -62,24 +84,79 @@ impl KvStore {
             writer,
             current_gen,
             uncompacted,
+            compactions: 0,
+            compaction_time: Duration::ZERO,
+            seq,
+            history,
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            subscribers: Arc::clone(&subscribers),
         };
 
         Ok(KvStore {
-            path,
             reader,
             index,
-            writer: Arc::new(Mutex::new(writer)),
+            mode: Mode::Writable(Arc::new(Mutex::new(writer))),
+            subscribers,
         })
     }
-}
 
-impl KvsEngine for KvStore {
-    /// 根据键返回对应值，若不包含该键值对，则返回None
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(op_pos) = self.index.get(&key) {
-            if let Operation::Set { key: _, value } = self.reader.read_operation(*op_pos.value())? {
+    /// 以只读方式打开给定路径上由其他进程写入的KvStore
+    ///
+    /// 只读实例从不写入或压缩日志，后台线程持续读取追加到日志中的新记录，
+    /// 写入方压缩日志后重新加载新的日志文件。所有修改操作返回`KvsError::ReadOnly`。
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = Arc::new(path.into());
+        let index = Arc::new(SkipMap::new());
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let subscribers = Arc::new(Subscribers::default());
+        let mut tailer = Tailer {
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            subscribers: Arc::clone(&subscribers),
+            safe_point: Arc::clone(&safe_point),
+            seq: SeqState::default(),
+            gens: BTreeSet::new(),
+            tail: None,
+        };
+        tailer.reload()?;
+        let tailer = Arc::new(Mutex::new(tailer));
+
+        let weak = Arc::downgrade(&tailer);
+        thread::Builder::new()
+            .name("kvs-tail".to_owned())
+            .spawn(move || follow(weak))?;
+
+        Ok(KvStore {
+            reader: KvStoreReader {
+                path,
+                safe_point,
+                readers: RefCell::new(BTreeMap::new()),
+            },
+            index,
+            mode: Mode::ReadOnly(tailer),
+            subscribers,
+        })
+    }
+
+    /// 立即读取其他进程新写入的记录，可写的实例无需调用
+    pub fn refresh(&self) -> Result<()> {
+        match &self.mode {
+            Mode::Writable(_) => Ok(()),
+            Mode::ReadOnly(tailer) => tailer.lock().unwrap().refresh(),
+        }
+    }
+
+    fn writer(&self) -> Result<&Mutex<KvStoreWriter>> {
+        match &self.mode {
+            Mode::Writable(writer) => Ok(writer),
+            Mode::ReadOnly(_) => Err(KvsError::ReadOnly),
+        }
+    }
+
+    fn read_value(&self, key: &str) -> Result<Option<String>> {
+        if let Some(op_pos) = self.index.get(key) {
+            if let Operation::Set { value, .. } = self.reader.read_operation(*op_pos.value())? {
                 Ok(Some(value))
             } else {
                 Err(KvsError::UnexpectedCommandType)
@@ -88,15 +165,110 @@ impl KvsEngine for KvStore {
             Ok(None)
         }
     }
+}
+
+impl KvsEngine for KvStore {
+    /// 根据键返回对应值，若不包含该键值对，则返回None
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.read_value(&key) {
+            // 只读实例的索引可能仍指向写入方刚压缩删除的日志，重新加载后再读一次
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound && matches!(self.mode, Mode::ReadOnly(_)) => {
+                self.refresh()?;
+                self.read_value(&key)
+            }
+            res => res,
+        }
+    }
 
     /// 移除键值对
     fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+        self.writer()?.lock().unwrap().remove(key)
     }
 
     /// 增加或修改键值对
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+        self.writer()?.lock().unwrap().set(key, value)
+    }
+
+    /// 索引按键有序，因此只需从前缀处开始遍历
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .index
+            .range(prefix.to_owned()..)
+            .take_while(|entry| entry.key().starts_with(prefix))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Watcher> {
+        Ok(Watcher::channel(self.subscribers.subscribe(prefix)))
+    }
+
+    /// 在写锁或跟踪锁内固定历史日志的范围并订阅之后的变更，保证两者既不重复也不遗漏
+    fn changes(&self, from_seq: u64) -> Result<ChangeStream> {
+        match &self.mode {
+            Mode::Writable(writer) => {
+                let writer = writer.lock().unwrap();
+                changes(&writer.path, writer.seq, &self.subscribers, from_seq)
+            }
+            Mode::ReadOnly(tailer) => {
+                let tailer = tailer.lock().unwrap();
+                changes(&tailer.path, tailer.seq, &self.subscribers, from_seq)
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        match &self.mode {
+            Mode::Writable(writer) => {
+                let writer = writer.lock().unwrap();
+                snapshot(&writer.path, &self.index, writer.seq.last)
+            }
+            Mode::ReadOnly(tailer) => {
+                let tailer = tailer.lock().unwrap();
+                snapshot(&tailer.path, &self.index, tailer.seq.last)
+            }
+        }
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.writer()?.lock().unwrap().compact()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer()?.lock().unwrap().flush()
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        self.writer()?.lock().unwrap().backup(dest)
+    }
+
+    /// 只读实例不知道写入方的冗余计数，以日志总大小减去有效部分估算
+    fn stats(&self) -> Result<EngineStats> {
+        let path = &self.reader.path;
+        let gens = sorted_gen_list(path)?;
+        let mut disk_bytes = 0;
+        for &gen in gens.iter() {
+            disk_bytes += fs::metadata(log_path(path, gen))?.len();
+        }
+        let live_bytes = self.index.iter().map(|entry| entry.value().len).sum();
+        let mut stats = EngineStats {
+            engine: "kvs".to_owned(),
+            data_dir: Some(path.to_path_buf()),
+            keys: self.index.len() as u64,
+            disk_bytes,
+            generations: gens.len() as u64,
+            live_bytes,
+            uncompacted_bytes: disk_bytes.saturating_sub(live_bytes),
+            ..EngineStats::default()
+        };
+        if let Mode::Writable(writer) = &self.mode {
+            let writer = writer.lock().unwrap();
+            stats.uncompacted_bytes = writer.uncompacted;
+            stats.compactions = writer.compactions;
+            stats.compaction_time = writer.compaction_time;
+        }
+        Ok(stats)
     }
 }
 
@@ -136,11 +308,13 @@ impl KvStoreReader {
         self.close_stale_handles();
 
         let mut readers = self.readers.borrow_mut();
-        if !readers.contains_key(&op_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, op_pos.gen))?)?;
-            readers.insert(op_pos.gen, reader);
-        }
-        let reader = readers.get_mut(&op_pos.gen).unwrap();
+        let reader = match readers.entry(op_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let reader = BufReaderWithPos::new(File::open(log_path(&self.path, op_pos.gen))?)?;
+                entry.insert(reader)
+            }
+        };
         reader.seek(SeekFrom::Start(op_pos.pos))?;
         let op_reader = reader.take(op_pos.len);
         f(op_reader)
@@ -171,22 +345,35 @@ struct KvStoreWriter {
     current_gen: u64,
     // 可以被压缩删除的冗余操作大小，按字节计数
     uncompacted: u64,
+    compactions: u64,
+    compaction_time: Duration,
+    seq: SeqState,
+    history: History,
     path: Arc<PathBuf>,
     index: Arc<SkipMap<String, OperationPos>>,
+    subscribers: Arc<Subscribers>,
 }
 
 impl KvStoreWriter {
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let op = Operation::Set { key, value };
+        let seq = self.seq.last + 1;
+        let op = Operation::Set { key, value, seq };
         let pos = self.writer.pos;
         serde_json::to_writer(&mut self.writer, &op)?;
         self.writer.flush()?;
-        if let Operation::Set { key, .. } = op {
+        self.seq.last = seq;
+        self.history.push(seq, (self.current_gen, pos..self.writer.pos).into());
+        if let Operation::Set { key, value, .. } = op {
             if let Some(old_op) = self.index.get(&key) {
                 self.uncompacted += old_op.value().len;
             }
             self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
+                .insert(key.clone(), (self.current_gen, pos..self.writer.pos).into());
+            // 持有写锁时发布，保证订阅者看到的顺序与日志一致
+            self.subscribers.publish(&Change {
+                seq,
+                event: Event::Set { key, value },
+            });
         }
 
         if self.uncompacted > COMPACTION_THRESHOLD {
@@ -197,15 +384,22 @@ impl KvStoreWriter {
 
     fn remove(&mut self, key: String) -> Result<()> {
         if self.index.contains_key(&key) {
-            let op = Operation::Rm { key };
+            let seq = self.seq.last + 1;
+            let op = Operation::Rm { key, seq };
             let pos = self.writer.pos;
             serde_json::to_writer(&mut self.writer, &op)?;
             self.writer.flush()?;
-            if let Operation::Rm { key } = op {
+            self.seq.last = seq;
+            self.history.push(seq, (self.current_gen, pos..self.writer.pos).into());
+            if let Operation::Rm { key, .. } = op {
                 let old_op = self.index.remove(&key).expect("key not found");
                 self.uncompacted += old_op.value().len;
                 // "remove"命令本身也可以在压缩操作时被删除
                 self.uncompacted += self.writer.pos - pos;
+                self.subscribers.publish(&Change {
+                    seq,
+                    event: Event::Remove { key },
+                });
             }
 
             if self.uncompacted > COMPACTION_THRESHOLD {
@@ -217,23 +411,74 @@ impl KvStoreWriter {
         }
     }
 
-    /// 删除冗余日志
+    /// 把已写入的日志同步到磁盘
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// 复制全部日志文件，持有写锁期间日志既不会追加也不会被压缩，因此副本是一致的
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        self.flush()?;
+        create_backup_dir(dest)?;
+        for gen in sorted_gen_list(&self.path)? {
+            fs::copy(log_path(&self.path, gen), log_path(dest, gen))?;
+        }
+        Ok(())
+    }
+
+    /// 删除冗余日志，近期的记录原样保留
     fn compact(&mut self) -> Result<()> {
+        let start = Instant::now();
         // 当前版本号加二。其中一个是由于压缩文件
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
         self.writer = new_log_file(&self.path, self.current_gen)?;
 
         let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
-
-        let mut new_pos = 0; // 新日志文件中的偏移量
+        // 保留的记录之前的序号都被删除，记下压缩点以便重启后仍能判断哪些序号已不完整
+        let compacted = self.history.ops.front().map_or(self.seq.last, |(seq, _)| seq - 1);
+        serde_json::to_writer(&mut compaction_writer, &Operation::Compacted { seq: compacted })?;
+
+        // 其余键只保留最新记录
+        let retained: HashSet<(u64, u64)> = self
+            .history
+            .ops
+            .iter()
+            .map(|(_, op_pos)| (op_pos.gen, op_pos.pos))
+            .collect();
+        let mut new_pos = compaction_writer.pos; // 新日志文件中的偏移量
         for entry in self.index.iter() {
+            if retained.contains(&(entry.value().gen, entry.value().pos)) {
+                continue;
+            }
             let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
                 Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
             })?;
             self.index.insert(entry.key().clone(), (compaction_gen, new_pos..new_pos + len).into());
             new_pos += len;
         }
+
+        // 保留的记录按序号追加在最后，重新加载时依次回放即得到最新状态
+        let mut history = History::default();
+        let mut moved = HashMap::new();
+        for &(seq, op_pos) in self.history.ops.iter() {
+            let len = self.reader.read_and(op_pos, |mut entry_reader| {
+                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
+            })?;
+            let new_op_pos = (compaction_gen, new_pos..new_pos + len).into();
+            moved.insert((op_pos.gen, op_pos.pos), new_op_pos);
+            history.push(seq, new_op_pos);
+            new_pos += len;
+        }
+        let mut live_bytes = 0;
+        for entry in self.index.iter() {
+            if let Some(&new_op_pos) = moved.get(&(entry.value().gen, entry.value().pos)) {
+                self.index.insert(entry.key().clone(), new_op_pos);
+                live_bytes += new_op_pos.len;
+            }
+        }
         compaction_writer.flush()?;
 
         self.reader
@@ -255,14 +500,246 @@ impl KvStoreWriter {
                 println!("{:?} cannot be deleted: {}", file_path, e);
             }
         }
-        self.uncompacted = 0;
+        // 保留的记录中被覆盖或删除的部分留待之后的压缩回收
+        self.uncompacted = history.bytes - live_bytes;
+        self.seq.compacted = compacted;
+        self.history = history;
+        self.compactions += 1;
+        self.compaction_time += start.elapsed();
 
         Ok(())
     }
 }
 
+/// 读取序号不小于`from_seq`的历史日志，之后接着返回订阅到的新变更
+///
+/// 调用方须持有写锁或跟踪锁，使`seq`与日志文件的内容一致。
+fn changes(path: &Path, seq: SeqState, subscribers: &Subscribers, from_seq: u64) -> Result<ChangeStream> {
+    if seq.compacted > 0 && from_seq <= seq.compacted {
+        return Err(KvsError::SequenceCompacted);
+    }
+    let end_seq = seq.last + 1;
+
+    // 之后的压缩会删除这些文件，提前打开以保留句柄；
+    // 当前日志仍在追加，只读取到此刻的长度，只读实例还可能读到写了一半的记录
+    let mut files = Vec::new();
+    for gen in sorted_gen_list(path)? {
+        let file = File::open(log_path(path, gen))?;
+        let len = file.metadata()?.len();
+        files.push(BufReader::new(file.take(len)));
+    }
+    let history = files
+        .into_iter()
+        .flat_map(|reader| Deserializer::from_reader(reader).into_iter::<Operation>())
+        .filter_map(move |op| match op {
+            Ok(op) => op
+                .into_change()
+                .filter(|change| from_seq <= change.seq && change.seq < end_seq)
+                .map(Ok),
+            Err(e) if e.is_eof() => None,
+            Err(e) => Some(Err(e.into())),
+        });
+
+    let live = subscribers.subscribe("");
+    Ok(ChangeStream::new(Box::new(history), live, from_seq))
+}
+
+/// 复制索引并打开其引用的日志文件，值在迭代时才读取
+fn snapshot(path: &Path, index: &SkipMap<String, OperationPos>, seq: u64) -> Result<Snapshot> {
+    let entries: Vec<(String, OperationPos)> = index
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect();
+    let mut files = BTreeMap::new();
+    for (_, op_pos) in entries.iter() {
+        if let Entry::Vacant(entry) = files.entry(op_pos.gen) {
+            entry.insert(File::open(log_path(path, op_pos.gen))?);
+        }
+    }
+
+    let pairs = entries.into_iter().map(move |(key, op_pos)| {
+        let file = files.get_mut(&op_pos.gen).expect("log file not opened");
+        file.seek(SeekFrom::Start(op_pos.pos))?;
+        match serde_json::from_reader(file.take(op_pos.len))? {
+            Operation::Set { value, .. } => Ok((key, value)),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    });
+    Ok(Snapshot::new(seq, Box::new(pairs)))
+}
+
+/// 只读实例跟踪日志的状态
+struct Tailer {
+    path: Arc<PathBuf>,
+    index: Arc<SkipMap<String, OperationPos>>,
+    subscribers: Arc<Subscribers>,
+    safe_point: Arc<AtomicU64>,
+    seq: SeqState,
+    /// 已读取过的日志编号，其中任一被删除说明写入方完成了一次压缩
+    gens: BTreeSet<u64>,
+    /// 正在跟踪的最新日志，及其中已读取的完整记录的末尾位置
+    tail: Option<(u64, BufReaderWithPos<File>, u64)>,
+}
+
+impl Tailer {
+    /// 读取新追加的记录，写入方切换到新日志后继续跟踪新日志
+    fn refresh(&mut self) -> Result<()> {
+        self.read_tail()?;
+        let gens = sorted_gen_list(&self.path)?;
+        if self.gens.iter().any(|gen| gens.binary_search(gen).is_err()) {
+            return self.reload();
+        }
+
+        let current = self.tail.as_ref().map_or(0, |(gen, ..)| *gen);
+        for gen in gens.into_iter().filter(|gen| *gen > current) {
+            // 写入方只追加最新的日志，出现更新的日志说明之前的日志不会再变化，先读完其剩余部分
+            self.read_tail()?;
+            let mut reader = BufReaderWithPos::new(File::open(log_path(&self.path, gen))?)?;
+            let published = self.seq.last;
+            let pos = tail(gen, &mut reader, 0, &self.index, &mut self.seq, published, &self.subscribers)?;
+            self.gens.insert(gen);
+            self.tail = Some((gen, reader, pos));
+        }
+        Ok(())
+    }
+
+    fn read_tail(&mut self) -> Result<()> {
+        if let Some((gen, reader, pos)) = &mut self.tail {
+            let published = self.seq.last;
+            *pos = tail(*gen, reader, *pos, &self.index, &mut self.seq, published, &self.subscribers)?;
+        }
+        Ok(())
+    }
+
+    /// 重新读取所有日志并更新索引，用于打开时以及写入方压缩日志之后
+    ///
+    /// 先在新索引中完整加载，再逐个替换共享索引中的条目，读者不会看到键暂时消失。
+    fn reload(&mut self) -> Result<()> {
+        let gens = sorted_gen_list(&self.path)?;
+        let index = SkipMap::new();
+        let mut seq = SeqState::default();
+        let mut last = None;
+        for &gen in gens.iter() {
+            let mut reader = BufReaderWithPos::new(File::open(log_path(&self.path, gen))?)?;
+            let pos = tail(gen, &mut reader, 0, &index, &mut seq, self.seq.last, &self.subscribers)?;
+            last = Some((gen, reader, pos));
+        }
+
+        for entry in index.iter() {
+            self.index.insert(entry.key().clone(), *entry.value());
+        }
+        let removed: Vec<String> = self
+            .index
+            .iter()
+            .filter(|entry| !index.contains_key(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in removed {
+            self.index.remove(&key);
+        }
+
+        // 关闭已被删除的旧日志的句柄
+        self.safe_point.store(gens.first().copied().unwrap_or(0), Ordering::SeqCst);
+        self.seq = seq;
+        self.gens = gens.into_iter().collect();
+        self.tail = last;
+        Ok(())
+    }
+}
+
+/// 后台定期刷新只读实例，所有实例被丢弃后退出
+fn follow(tailer: Weak<Mutex<Tailer>>) {
+    loop {
+        thread::sleep(TAIL_INTERVAL);
+        match tailer.upgrade() {
+            // 出错时可能恰逢写入方压缩日志，下次刷新时重试
+            Some(tailer) => {
+                let _ = tailer.lock().unwrap().refresh();
+            }
+            None => return,
+        }
+    }
+}
+
+/// 从给定位置读取日志中完整的记录并更新索引与序号，序号大于`published`的变更发布给订阅者
+///
+/// 返回最后一条完整记录的末尾位置，写了一半的记录留待下次读取。
+fn tail(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    start: u64,
+    index: &SkipMap<String, OperationPos>,
+    seq: &mut SeqState,
+    published: u64,
+    subscribers: &Subscribers,
+) -> Result<u64> {
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Operation>();
+    while let Some(op) = stream.next() {
+        let op = match op {
+            Ok(op) => op,
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        };
+        let new_pos = start + stream.byte_offset() as u64;
+        let op_seq = match &op {
+            Operation::Set { key, seq, .. } => {
+                index.insert(key.clone(), (gen, pos..new_pos).into());
+                *seq
+            }
+            Operation::Rm { key, seq } => {
+                index.remove(key);
+                *seq
+            }
+            Operation::Compacted { seq: op_seq } => {
+                seq.compacted = seq.compacted.max(*op_seq);
+                *op_seq
+            }
+        };
+        seq.last = seq.last.max(op_seq);
+        if op_seq > published {
+            if let Some(change) = op.into_change() {
+                subscribers.publish(&change);
+            }
+        }
+        pos = new_pos;
+    }
+    Ok(pos)
+}
+
+/// 日志记录的序号状态
+#[derive(Debug, Default, Clone, Copy)]
+struct SeqState {
+    /// 最近写入的记录的序号
+    last: u64,
+    /// 最近一次压缩删除的最大序号，不大于该值的记录可能已被删除
+    compacted: u64,
+}
+
+/// 最近写入的记录，序号连续，数量和大小都有上限
+#[derive(Default)]
+struct History {
+    ops: VecDeque<(u64, OperationPos)>,
+    bytes: u64,
+}
+
+impl History {
+    /// 追加一条记录，超出上限时丢弃最早的记录
+    fn push(&mut self, seq: u64, op_pos: OperationPos) {
+        self.ops.push_back((seq, op_pos));
+        self.bytes += op_pos.len;
+        while self.ops.len() > HISTORY_OPS || self.bytes > HISTORY_BYTES {
+            if let Some((_, dropped)) = self.ops.pop_front() {
+                self.bytes -= dropped.len;
+            }
+        }
+    }
+}
+
 
 /// 保存在磁盘上的操作
+///
+/// 旧版本写入的记录没有序号，读取时视为0。
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 enum Operation {
@@ -272,14 +749,41 @@ enum Operation {
         key: String,
         /// 值
         value: String,
+        /// 序号
+        #[serde(default)]
+        seq: u64,
     },
     /// 删除键
     Rm {
         /// 键
         key: String,
+        /// 序号
+        #[serde(default)]
+        seq: u64,
+    },
+    /// 压缩文件开头的标记，记录压缩时已分配的最大序号
+    Compacted {
+        /// 序号
+        seq: u64,
     },
 }
 
+impl Operation {
+    fn into_change(self) -> Option<Change> {
+        match self {
+            Operation::Set { key, value, seq } => Some(Change {
+                seq,
+                event: Event::Set { key, value },
+            }),
+            Operation::Rm { key, seq } => Some(Change {
+                seq,
+                event: Event::Remove { key },
+            }),
+            Operation::Compacted { .. } => None,
+        }
+    }
+}
+
 /// 记录操作在log文件中的位置及长度
 #[derive(Debug, Clone, Copy)]
 pub struct OperationPos {
@@ -326,11 +830,13 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
-/// 读取单个log文件，并在index中存入值所在位置。返回压缩后可以节约多少字节
+/// 读取单个log文件，并在index中存入值所在位置，同时更新序号状态和近期记录。返回压缩后可以节约多少字节
 fn load(
     gen: u64,
     reader: &mut BufReaderWithPos<File>,
     index: &SkipMap<String, OperationPos>,
+    seq: &mut SeqState,
+    history: &mut History,
 ) -> Result<u64> {
     let mut pos = reader.seek(SeekFrom::Start(0))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Operation>();
@@ -338,19 +844,31 @@ fn load(
     while let Some(cmd) = stream.next() {
         let new_pos = stream.byte_offset() as u64;
         match cmd? {
-            Operation::Set { key, .. } => {
+            Operation::Set { key, seq: op_seq, .. } => {
+                seq.last = seq.last.max(op_seq);
+                if op_seq > seq.compacted {
+                    history.push(op_seq, (gen, pos..new_pos).into());
+                }
                 if let Some(old_cmd) = index.get(&key) {
                     uncompacted += old_cmd.value().len;
                 }
                 index.insert(key, (gen, pos..new_pos).into());
             }
-            Operation::Rm { key } => {
+            Operation::Rm { key, seq: op_seq } => {
+                seq.last = seq.last.max(op_seq);
+                if op_seq > seq.compacted {
+                    history.push(op_seq, (gen, pos..new_pos).into());
+                }
                 if let Some(old_cmd) = index.remove(&key) {
                     uncompacted += old_cmd.value().len;
                 }
                 // "remove"命令本身也可以被压缩删除
                 uncompacted += new_pos - pos;
             }
+            Operation::Compacted { seq: op_seq } => {
+                seq.last = seq.last.max(op_seq);
+                seq.compacted = seq.compacted.max(op_seq);
+            }
         }
         pos = new_pos;
     }
@@ -1,6 +1,11 @@
 //! 该模块包含各个键值对存储引擎
 
-use crate::error::Result;
+use crate::error::{KvsError, Result};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// 键值对存储引擎特征
 pub trait KvsEngine: Clone + Send + 'static {
@@ -18,10 +23,119 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// 
     /// 若给定键不存在，则返回'KvsError::KeyNotFound'
     fn remove(&self, key: String) -> Result<()>;
+
+    /// 批量获取多个键对应的值，结果与给定键一一对应
+    ///
+    /// 默认逐个调用`get`，需经网络转发的引擎可以将其合并为更少的往返。
+    fn get_many(&self, keys: Vec<String>) -> Vec<Result<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// 批量设置键值对，结果与给定键值对一一对应
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Vec<Result<()>> {
+        pairs.into_iter().map(|(key, value)| self.set(key, value)).collect()
+    }
+
+    /// 批量删除键，结果与给定键一一对应
+    fn remove_many(&self, keys: Vec<String>) -> Vec<Result<()>> {
+        keys.into_iter().map(|key| self.remove(key)).collect()
+    }
+
+    /// 按字典序返回以给定前缀开头的所有键
+    fn keys(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// 订阅以给定前缀开头的键之后发生的变更
+    ///
+    /// 事件按写入顺序产生，不包含订阅之前已存在的键值对。
+    fn watch(&self, prefix: &str) -> Result<Watcher>;
+
+    /// 返回从给定序号开始的所有变更，先读取保留的历史日志，之后持续返回新的变更
+    ///
+    /// # Errors
+    ///
+    /// 若给定序号的记录已被压缩删除，则返回`KvsError::SequenceCompacted`；
+    /// 不记录序号的引擎返回`KvsError::Unsupported`
+    fn changes(&self, from_seq: u64) -> Result<ChangeStream>;
+
+    /// 返回当前全部键值对的一致快照及其对应的序号
+    ///
+    /// # Errors
+    ///
+    /// 不记录序号的引擎返回`KvsError::Unsupported`
+    fn snapshot(&self) -> Result<Snapshot>;
+
+    /// 立即压缩日志，回收被覆盖或删除的记录占用的空间
+    ///
+    /// # Errors
+    ///
+    /// 不支持手动压缩的引擎返回`KvsError::Unsupported`
+    fn compact(&self) -> Result<()> {
+        Err(KvsError::Unsupported("Compaction".to_owned()))
+    }
+
+    /// 把已写入的数据同步到磁盘
+    fn flush(&self) -> Result<()> {
+        Err(KvsError::Unsupported("Flush".to_owned()))
+    }
+
+    /// 把当前全部数据的一致副本写入给定目录，该目录须不存在或为空
+    ///
+    /// 副本可以直接作为同类引擎的数据目录打开。
+    fn backup(&self, _dest: &Path) -> Result<()> {
+        Err(KvsError::Unsupported("Backup".to_owned()))
+    }
+
+    /// 返回引擎当前的统计信息
+    ///
+    /// 默认只统计键的数量，不记录日志大小与压缩的引擎其余各项为0。
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            engine: "unknown".to_owned(),
+            keys: self.keys("")?.len() as u64,
+            ..EngineStats::default()
+        })
+    }
+}
+
+/// 存储引擎的统计信息
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct EngineStats {
+    /// 引擎类型
+    pub engine: String,
+    /// 数据目录
+    pub data_dir: Option<PathBuf>,
+    /// 键的数量
+    pub keys: u64,
+    /// 数据在磁盘上占用的字节数
+    pub disk_bytes: u64,
+    /// 日志文件的数量
+    pub generations: u64,
+    /// 仍被索引引用的日志字节数
+    pub live_bytes: u64,
+    /// 可被压缩删除的冗余日志字节数
+    pub uncompacted_bytes: u64,
+    /// 启动以来完成的压缩次数
+    pub compactions: u64,
+    /// 启动以来压缩的总耗时
+    pub compaction_time: Duration,
+}
+
+/// 创建备份目录，已存在且不为空时返回错误，以免覆盖其他数据
+fn create_backup_dir(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "Backup destination {} is not empty",
+            dest.display()
+        )));
+    }
+    Ok(())
 }
 
 mod kvs;
 mod sled;
+mod watch;
 
 pub use kvs::KvStore;
-pub use sled::SledEngine;
\ No newline at end of file
+pub use sled::SledEngine;
+pub use watch::{Change, ChangeStream, Event, Snapshot, Watcher};
@@ -1,6 +1,7 @@
 use sled::{Db, Tree};
-use super::KvsEngine;
+use super::{create_backup_dir, ChangeStream, EngineStats, KvsEngine, Snapshot, Watcher};
 use crate::{KvsError, Result};
+use std::path::Path;
 
 /// sled::Db包装
 #[derive(Clone)]
@@ -42,5 +43,49 @@ impl KvsEngine for SledEngine {
         tree.flush()?;
         Ok(())
     }
-}
 
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let tree: &Tree = &self.db;
+        tree.scan_prefix(prefix.as_bytes())
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Watcher> {
+        let tree: &Tree = &self.db;
+        Ok(Watcher::sled(tree.watch_prefix(prefix.as_bytes())))
+    }
+
+    /// sled的日志格式不对外公开，无法按序号读取历史变更
+    fn changes(&self, _from_seq: u64) -> Result<ChangeStream> {
+        Err(KvsError::Unsupported("Change data capture".to_owned()))
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Err(KvsError::Unsupported("Snapshot".to_owned()))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// 导出全部数据并导入到目标目录中新建的数据库
+    fn backup(&self, dest: &Path) -> Result<()> {
+        create_backup_dir(dest)?;
+        let backup = sled::open(dest)?;
+        backup.import(self.db.export());
+        backup.flush()?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            engine: "sled".to_owned(),
+            keys: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+}
@@ -0,0 +1,236 @@
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{KvsError, Result};
+
+/// 每个订阅者最多缓存的未读事件数，超出时订阅被关闭，避免写入被慢速订阅者阻塞
+const WATCH_BUFFER_SIZE: usize = 4096;
+
+/// 键值对的变更事件
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Event {
+    /// 键被设置为给定值
+    Set {
+        /// 键
+        key: String,
+        /// 新值
+        value: String,
+    },
+    /// 键被删除
+    Remove {
+        /// 键
+        key: String,
+    },
+}
+
+impl Event {
+    /// 事件对应的键
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set { key, .. } | Event::Remove { key } => key,
+        }
+    }
+}
+
+/// 带有序号的变更，由`KvsEngine::changes`产生
+///
+/// 序号在写入日志时按顺序分配，严格递增。
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Change {
+    /// 记录在日志中的序号
+    pub seq: u64,
+    /// 变更内容
+    pub event: Event,
+}
+
+/// 通过`KvsEngine::watch`订阅的变更事件流
+///
+/// 作为迭代器使用时会阻塞等待下一个事件，订阅被关闭后返回`KvsError::WatchClosed`。
+pub struct Watcher {
+    inner: WatcherInner,
+}
+
+enum WatcherInner {
+    Channel(Receiver<Change>),
+    Sled(sled::Subscriber),
+}
+
+impl Watcher {
+    pub(super) fn channel(rx: Receiver<Change>) -> Self {
+        Watcher {
+            inner: WatcherInner::Channel(rx),
+        }
+    }
+
+    pub(super) fn sled(subscriber: sled::Subscriber) -> Self {
+        Watcher {
+            inner: WatcherInner::Sled(subscriber),
+        }
+    }
+
+    /// 最多等待`timeout`，期间没有事件时返回None
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<Event>> {
+        match &mut self.inner {
+            WatcherInner::Channel(rx) => match rx.recv_timeout(timeout) {
+                Ok(change) => Ok(Some(change.event)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(RecvTimeoutError::Disconnected) => Err(KvsError::WatchClosed),
+            },
+            WatcherInner::Sled(subscriber) => match subscriber.next_timeout(timeout) {
+                Ok(event) => from_sled(event).map(Some),
+                Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+                Err(mpsc::RecvTimeoutError::Disconnected) => Err(KvsError::WatchClosed),
+            },
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        let event = match &mut self.inner {
+            WatcherInner::Channel(rx) => rx
+                .recv()
+                .map(|change| change.event)
+                .map_err(|_| KvsError::WatchClosed),
+            WatcherInner::Sled(subscriber) => subscriber
+                .next()
+                .ok_or(KvsError::WatchClosed)
+                .and_then(from_sled),
+        };
+        Some(event)
+    }
+}
+
+fn from_sled(event: sled::Event) -> Result<Event> {
+    Ok(match event {
+        sled::Event::Insert { key, value } => Event::Set {
+            key: String::from_utf8(key.to_vec())?,
+            value: String::from_utf8(value.to_vec())?,
+        },
+        sled::Event::Remove { key } => Event::Remove {
+            key: String::from_utf8(key.to_vec())?,
+        },
+    })
+}
+
+/// 通过`KvsEngine::changes`订阅的变更流
+///
+/// 先按顺序返回订阅前已写入日志的历史变更，再返回订阅后的新变更。
+/// 新变更积压过多时订阅会被关闭并返回`KvsError::WatchClosed`，
+/// 此时可以从最后收到的序号加一处重新订阅。
+pub struct ChangeStream {
+    history: Box<dyn Iterator<Item = Result<Change>> + Send>,
+    live: Receiver<Change>,
+    from_seq: u64,
+}
+
+impl ChangeStream {
+    pub(super) fn new(
+        history: Box<dyn Iterator<Item = Result<Change>> + Send>,
+        live: Receiver<Change>,
+        from_seq: u64,
+    ) -> Self {
+        ChangeStream {
+            history,
+            live,
+            from_seq,
+        }
+    }
+
+    /// 最多等待`timeout`，期间没有新变更时返回None
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<Change>> {
+        if let Some(change) = self.history.next() {
+            return change.map(Some);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.live.recv_timeout(timeout) {
+                Ok(change) if change.seq < self.from_seq => continue,
+                Ok(change) => return Ok(Some(change)),
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => return Err(KvsError::WatchClosed),
+            }
+        }
+    }
+}
+
+impl Iterator for ChangeStream {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        if let Some(change) = self.history.next() {
+            return Some(change);
+        }
+        loop {
+            match self.live.recv() {
+                Ok(change) if change.seq < self.from_seq => continue,
+                Ok(change) => return Some(Ok(change)),
+                Err(_) => return Some(Err(KvsError::WatchClosed)),
+            }
+        }
+    }
+}
+
+/// 某一序号时刻的全部键值对，由`KvsEngine::snapshot`产生
+///
+/// 迭代时按键的字典序逐个读取值，之后的写入与压缩不影响迭代结果。
+pub struct Snapshot {
+    seq: u64,
+    pairs: Box<dyn Iterator<Item = Result<(String, String)>> + Send>,
+}
+
+impl Snapshot {
+    pub(super) fn new(seq: u64, pairs: Box<dyn Iterator<Item = Result<(String, String)>> + Send>) -> Self {
+        Snapshot { seq, pairs }
+    }
+
+    /// 快照包含序号不大于该值的所有变更，可以从该值加一处继续订阅变更
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Iterator for Snapshot {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Result<(String, String)>> {
+        self.pairs.next()
+    }
+}
+
+/// `KvStore`的订阅者列表，由写入路径在每次写入日志后发布变更
+#[derive(Default)]
+pub(super) struct Subscribers {
+    senders: Mutex<Vec<(String, Sender<Change>)>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&self, prefix: &str) -> Receiver<Change> {
+        let (tx, rx) = crossbeam_channel::bounded(WATCH_BUFFER_SIZE);
+        self.senders.lock().unwrap().push((prefix.to_owned(), tx));
+        rx
+    }
+
+    /// 向前缀匹配的订阅者发送变更，同时移除已关闭或积压过多的订阅
+    pub fn publish(&self, change: &Change) {
+        let mut senders = self.senders.lock().unwrap();
+        if senders.is_empty() {
+            return;
+        }
+        senders.retain(|(prefix, tx)| {
+            if !change.event.key().starts_with(prefix.as_str()) {
+                return true;
+            }
+            match tx.try_send(change.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
@@ -1,35 +1,115 @@
-use failure::Fail;
+use std::error::Error;
+use std::fmt;
 use std::io;
+use std::net::SocketAddr;
 use std::string::FromUtf8Error;
 use std::sync::PoisonError;
 use rayon::ThreadPoolBuildError;
 
 /// kvs 错误类型.
-#[derive(Debug, Fail)]
+#[derive(Debug)]
 pub enum KvsError {
     /// IO 错误.
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
+    Io(io::Error),
     /// 序列化与反序列化错误.
-    #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
+    Serde(serde_json::Error),
+    /// 二进制编解码错误.
+    Bincode(bincode::Error),
+    /// TLS错误.
+    Tls(rustls::Error),
     /// 移除不存在的键.
-    #[fail(display = "Key not found")]
     KeyNotFound,
     /// 无效命令.
-    #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
     /// sled引擎错误
-    #[fail(display = "{}", _0)]
-    SledError(#[cause] sled::Error),
+    SledError(sled::Error),
     /// 字符串转化错误
-    #[fail(display = "{}", _0)]
-    Utf8(#[cause] FromUtf8Error),
+    Utf8(FromUtf8Error),
+    /// 用户名或密码错误.
+    AuthFailed,
+    /// 未认证或无权访问给定键.
+    PermissionDenied,
+    /// 请求超出了客户端的限流额度.
+    Throttled,
+    /// 服务器连接数已满，拒绝了连接.
+    ServerBusy,
+    /// 在超时时间内未能连接服务器.
+    ConnectTimeout,
+    /// 在超时时间内未收到服务器的响应.
+    ReadTimeout,
+    /// 在超时时间内未能发送请求.
+    WriteTimeout,
+    /// 订阅的变更事件流已关闭，如订阅者处理过慢.
+    WatchClosed,
+    /// 请求的变更序号已被压缩删除.
+    SequenceCompacted,
+    /// 只读的存储或从节点拒绝了写入.
+    ReadOnly,
+    /// 集群中的非领导者节点拒绝了请求，附带已知的领导者地址.
+    NotLeader(Option<SocketAddr>),
+    /// 在超时时间内写入未能被集群多数节点提交.
+    CommitTimeout,
+    /// 存储引擎不支持该操作.
+    Unsupported(String),
+    /// 在超时时间内未能从连接池取得连接.
+    PoolTimeout,
+    /// 服务器返回的响应与请求不匹配.
+    UnexpectedResponse,
+    /// 对端使用了不支持的协议版本.
+    UnsupportedVersion(u32),
+    /// 幂等令牌已被用于写入不同的键值.
+    IdempotencyConflict,
     /// 附带string信息的错误.
-    #[fail(display = "{}", _0)]
     StringError(String),
 }
 
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::Io(err) => write!(f, "{}", err),
+            KvsError::Serde(err) => write!(f, "{}", err),
+            KvsError::Bincode(err) => write!(f, "{}", err),
+            KvsError::Tls(err) => write!(f, "{}", err),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::UnexpectedCommandType => write!(f, "Unexpected command type"),
+            KvsError::SledError(err) => write!(f, "{}", err),
+            KvsError::Utf8(err) => write!(f, "{}", err),
+            KvsError::AuthFailed => write!(f, "Authentication failed"),
+            KvsError::PermissionDenied => write!(f, "Permission denied"),
+            KvsError::Throttled => write!(f, "Request throttled"),
+            KvsError::ServerBusy => write!(f, "Server busy"),
+            KvsError::ConnectTimeout => write!(f, "Timed out connecting to server"),
+            KvsError::ReadTimeout => write!(f, "Timed out waiting for response"),
+            KvsError::WriteTimeout => write!(f, "Timed out sending request"),
+            KvsError::WatchClosed => write!(f, "Watch stream closed"),
+            KvsError::SequenceCompacted => write!(f, "Requested sequence has been compacted away"),
+            KvsError::ReadOnly => write!(f, "Store is read-only"),
+            KvsError::NotLeader(_) => write!(f, "Not the cluster leader"),
+            KvsError::CommitTimeout => write!(f, "Timed out waiting for the write to be committed"),
+            KvsError::Unsupported(operation) => write!(f, "{} is not supported by this engine", operation),
+            KvsError::PoolTimeout => write!(f, "Timed out waiting for a pooled connection"),
+            KvsError::UnexpectedResponse => write!(f, "Unexpected response"),
+            KvsError::UnsupportedVersion(version) => write!(f, "Unsupported protocol version: {}", version),
+            KvsError::IdempotencyConflict => write!(f, "Idempotency token reused for a different request"),
+            KvsError::StringError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(err) => Some(err),
+            KvsError::Serde(err) => Some(err),
+            KvsError::Bincode(err) => Some(err),
+            KvsError::Tls(err) => Some(err),
+            KvsError::SledError(err) => Some(err),
+            KvsError::Utf8(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> KvsError {
         KvsError::Io(err)
@@ -42,6 +122,18 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> KvsError {
+        KvsError::Tls(err)
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(err: sled::Error) -> Self {
         KvsError::SledError(err)
@@ -0,0 +1,173 @@
+//! HTTP/JSON网关
+//!
+//! 提供以下接口：
+//! - `GET /keys/{key}`：获取键对应的值，返回`{"key": ..., "value": ...}`
+//! - `PUT /keys/{key}`：设置键值，请求体为`{"value": ...}`
+//! - `DELETE /keys/{key}`：删除键
+//! - `GET /keys?prefix={prefix}`：按字典序列出以给定前缀开头的键，返回`{"keys": [...]}`
+//!
+//! 出错时返回`{"error": ...}`，键不存在时状态码为404。
+
+use crate::engines::KvsEngine;
+use crate::error::{KvsError, Result};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::Logger;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// 处理HTTP请求的工作线程数
+const HTTP_WORKERS: usize = 4;
+
+/// 带状态码的错误响应
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        HttpError {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<KvsError> for HttpError {
+    fn from(err: KvsError) -> Self {
+        let status = match err {
+            KvsError::KeyNotFound => 404,
+            KvsError::ReadOnly => 403,
+            KvsError::NotLeader(_) | KvsError::CommitTimeout => 503,
+            _ => 500,
+        };
+        match err {
+            KvsError::NotLeader(Some(leader)) => HttpError::new(status, format!("{}, leader is {}", err, leader)),
+            err => HttpError::new(status, err.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+/// 绑定给定地址，并在后台线程中处理HTTP请求
+///
+/// 绑定失败时直接返回错误，绑定成功后立即返回。
+pub(crate) fn spawn<E: KvsEngine>(engine: E, addr: SocketAddr, logger: Arc<Logger>) -> Result<()> {
+    let server = Server::http(addr).map_err(|e| KvsError::StringError(e.to_string()))?;
+    let server = Arc::new(server);
+
+    for _ in 0..HTTP_WORKERS {
+        let server = Arc::clone(&server);
+        let engine = engine.clone();
+        let logger = logger.clone();
+        thread::Builder::new().spawn(move || {
+            // 仅当服务器被关闭时recv才会出错
+            while let Ok(req) = server.recv() {
+                if let Err(e) = handle(&engine, req, &logger) {
+                    error!(logger, "Error on serving HTTP client: {}", e);
+                }
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+fn handle<E: KvsEngine>(engine: &E, mut req: Request, logger: &Logger) -> io::Result<()> {
+    let peer_addr = req.remote_addr().copied();
+    debug!(logger, "Receive HTTP request from {:?}: {} {}", peer_addr, req.method(), req.url());
+
+    let (status, body) = match route(engine, &mut req) {
+        Ok(resp) => resp,
+        Err(e) => (e.status, Some(json!({ "error": e.message }))),
+    };
+    debug!(logger, "HTTP response sent to the {:?}: {} {:?}", peer_addr, status, body);
+
+    match body {
+        Some(body) => {
+            let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+            let resp = Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(content_type);
+            req.respond(resp)
+        }
+        None => req.respond(Response::empty(status)),
+    }
+}
+
+fn route<E: KvsEngine>(engine: &E, req: &mut Request) -> std::result::Result<(u16, Option<Value>), HttpError> {
+    let url = req.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+    if path == "/keys" {
+        if *req.method() != Method::Get {
+            return Err(HttpError::new(405, "Method not allowed"));
+        }
+        let prefix = query_param(query, "prefix")?.unwrap_or_default();
+        let keys = engine.keys(&prefix)?;
+        return Ok((200, Some(json!({ "keys": keys }))));
+    }
+
+    let key = match path.strip_prefix("/keys/") {
+        Some("") => return Err(HttpError::new(400, "Missing key")),
+        Some(key) => percent_decode(key, false)?,
+        None => return Err(HttpError::new(404, "Not found")),
+    };
+
+    match req.method() {
+        Method::Get => match engine.get(key.clone())? {
+            Some(value) => Ok((200, Some(json!({ "key": key, "value": value })))),
+            None => Err(KvsError::KeyNotFound.into()),
+        },
+        Method::Put => {
+            let body: PutBody = serde_json::from_reader(req.as_reader())
+                .map_err(|e| HttpError::new(400, format!("Invalid body: {}", e)))?;
+            engine.set(key, body.value)?;
+            Ok((204, None))
+        }
+        Method::Delete => {
+            engine.remove(key)?;
+            Ok((204, None))
+        }
+        _ => Err(HttpError::new(405, "Method not allowed")),
+    }
+}
+
+/// 从查询字符串中取出给定参数的值
+fn query_param(query: &str, name: &str) -> std::result::Result<Option<String>, HttpError> {
+    for pair in query.split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if key == name {
+            return percent_decode(value, true).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// 解码URL中的百分号编码，查询字符串中的`+`表示空格
+fn percent_decode(s: &str, plus_as_space: bool) -> std::result::Result<String, HttpError> {
+    let invalid = || HttpError::new(400, format!("Invalid percent-encoding: {}", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next().ok_or_else(invalid)?, iter.next().ok_or_else(invalid)?];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
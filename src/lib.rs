@@ -2,9 +2,18 @@
 //! 一个简单的用于存储键值对的库。
 
 pub use error::{KvsError, Result};
-pub use engines::{KvStore, KvsEngine, SledEngine};
-pub use client::KvsClient;
-pub use server::KvsServer;
+pub use engines::{Change, ChangeStream, EngineStats, Event, KvStore, KvsEngine, SledEngine, Snapshot, Watcher};
+pub use client::{Changes, KvsClient, KvsClientBuilder, Pipeline, PipelineResponse, Watch};
+pub use client_pool::{KvsClientPool, KvsClientPoolBuilder, PooledClient};
+pub use codec::Codec;
+pub use common::{ServerStats, SlowRequest};
+pub use tls::{ClientTls, ServerTls};
+pub use server::{KvsServer, Protocol};
+pub use auth::AuthConfig;
+pub use rate_limit::RateLimit;
+pub use raft::ClusterConfig;
+pub use sharding::{HashRing, ShardedKvsClient};
+pub use proxy::{PrefixTable, ProxyEngine, Routing};
 
 #[macro_use]
 extern crate slog;
@@ -15,5 +24,18 @@ mod error;
 mod engines;
 mod server;
 mod client;
+mod client_pool;
 mod common;
+mod codec;
+mod resp;
+mod http;
+mod metrics;
+mod stream;
+mod tls;
+mod auth;
+mod rate_limit;
+mod replication;
+mod raft;
+mod sharding;
+mod proxy;
 pub mod thread_pool;
\ No newline at end of file
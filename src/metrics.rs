@@ -0,0 +1,155 @@
+//! Prometheus文本格式的监控指标
+//!
+//! `GET /metrics`返回以下指标：
+//! - `kvs_requests_total{command, result}`：按请求类型与结果（ok/error）统计的请求数
+//! - `kvs_request_duration_seconds{command}`：按请求类型统计的处理耗时直方图
+//! - `kvs_connections`：当前的连接数，包括排队中的连接
+//! - `kvs_thread_pool_queue_depth`：已交给线程池但尚未被工作线程处理的连接数
+//! - `kvs_engine_*`：存储引擎的键数量、有效与冗余日志字节数、压缩次数与总耗时
+
+use crate::engines::KvsEngine;
+use crate::error::{KvsError, Result};
+use crate::server::ServerState;
+
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Response, Server};
+
+/// 请求耗时直方图各个桶的上限，单位为秒
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// 服务器运行以来按请求类型累计的指标
+#[derive(Default)]
+pub(crate) struct Metrics {
+    commands: Mutex<BTreeMap<&'static str, CommandMetrics>>,
+}
+
+#[derive(Default)]
+struct CommandMetrics {
+    ok: u64,
+    errors: u64,
+    /// 每个桶中耗时不超过其上限且超过前一个上限的请求数，最后一项为超过所有上限的请求数
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    seconds: f64,
+}
+
+impl Metrics {
+    /// 记录一个请求的结果与处理耗时
+    pub(crate) fn record(&self, command: &'static str, ok: bool, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        let mut commands = self.commands.lock().unwrap();
+        let metrics = commands.entry(command).or_default();
+        if ok {
+            metrics.ok += 1;
+        } else {
+            metrics.errors += 1;
+        }
+        metrics.buckets[bucket] += 1;
+        metrics.seconds += seconds;
+    }
+
+    fn render(&self, out: &mut String) {
+        let commands = self.commands.lock().unwrap();
+        header(out, "kvs_requests_total", "counter", "Requests handled, by command and result.");
+        for (command, metrics) in commands.iter() {
+            let _ = writeln!(out, "kvs_requests_total{{command=\"{}\",result=\"ok\"}} {}", command, metrics.ok);
+            let _ = writeln!(out, "kvs_requests_total{{command=\"{}\",result=\"error\"}} {}", command, metrics.errors);
+        }
+
+        header(out, "kvs_request_duration_seconds", "histogram", "Time spent handling requests, by command.");
+        for (command, metrics) in commands.iter() {
+            // Prometheus的桶是累计的，每个桶包含耗时不超过其上限的全部请求
+            let mut count = 0;
+            for (le, n) in LATENCY_BUCKETS.iter().zip(metrics.buckets.iter()) {
+                count += n;
+                let _ = writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, le, count
+                );
+            }
+            count += metrics.buckets[LATENCY_BUCKETS.len()];
+            let _ = writeln!(out, "kvs_request_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}", command, count);
+            let _ = writeln!(out, "kvs_request_duration_seconds_sum{{command=\"{}\"}} {}", command, metrics.seconds);
+            let _ = writeln!(out, "kvs_request_duration_seconds_count{{command=\"{}\"}} {}", command, count);
+        }
+    }
+}
+
+/// 绑定给定地址，并在后台线程中响应指标请求
+///
+/// 绑定失败时直接返回错误，绑定成功后立即返回。
+pub(crate) fn spawn<E: KvsEngine>(
+    engine: E,
+    state: Arc<ServerState>,
+    addr: SocketAddr,
+    logger: Arc<Logger>,
+) -> Result<()> {
+    let server = Server::http(addr).map_err(|e| KvsError::StringError(e.to_string()))?;
+    thread::Builder::new().name("kvs-metrics".to_owned()).spawn(move || {
+        // 仅当服务器被关闭时recv才会出错
+        while let Ok(req) = server.recv() {
+            let res = if *req.method() == Method::Get && req.url() == "/metrics" {
+                let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+                req.respond(Response::from_string(render(&engine, &state, &logger)).with_header(content_type))
+            } else {
+                req.respond(Response::empty(404))
+            };
+            if let Err(e) = res {
+                debug!(logger, "Failed to send metrics: {}", e);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn render<E: KvsEngine>(engine: &E, state: &ServerState, logger: &Logger) -> String {
+    let mut out = String::new();
+    state.metrics.render(&mut out);
+
+    header(&mut out, "kvs_connections", "gauge", "Open connections, including queued ones.");
+    let _ = writeln!(out, "kvs_connections {}", state.connections.load(Ordering::SeqCst));
+    header(&mut out, "kvs_thread_pool_queue_depth", "gauge", "Connections waiting for a worker thread.");
+    let _ = writeln!(out, "kvs_thread_pool_queue_depth {}", state.queued.load(Ordering::SeqCst));
+
+    // 不支持统计的引擎只输出服务器自身的指标
+    let stats = match engine.stats() {
+        Ok(stats) => stats,
+        Err(e) => {
+            debug!(logger, "Engine stats unavailable: {}", e);
+            return out;
+        }
+    };
+    let gauges = [
+        ("kvs_engine_keys", "Number of keys.", stats.keys),
+        ("kvs_engine_live_bytes", "Log bytes referenced by the index.", stats.live_bytes),
+        ("kvs_engine_uncompacted_bytes", "Log bytes that compaction would reclaim.", stats.uncompacted_bytes),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    header(&mut out, "kvs_engine_compactions_total", "counter", "Compactions completed since startup.");
+    let _ = writeln!(out, "kvs_engine_compactions_total {}", stats.compactions);
+    header(&mut out, "kvs_engine_compaction_seconds_total", "counter", "Time spent compacting since startup.");
+    let _ = writeln!(out, "kvs_engine_compaction_seconds_total {}", stats.compaction_time.as_secs_f64());
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
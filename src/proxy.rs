@@ -0,0 +1,224 @@
+//! 把请求转发到后端kvs-server的代理引擎，供`kvs-proxy`使用
+
+use crate::client::{KvsClient, KvsClientBuilder};
+use crate::client_pool::KvsClientPool;
+use crate::engines::{ChangeStream, KvsEngine, Snapshot, Watcher};
+use crate::error::{KvsError, Result};
+use crate::sharding::HashRing;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+/// 代理选择后端的方式
+#[derive(Debug, Clone)]
+pub enum Routing {
+    /// 按一致性哈希环把键分布到各后端
+    HashRing(HashRing),
+    /// 按最长匹配的键前缀选择后端
+    Prefix(PrefixTable),
+}
+
+impl Routing {
+    fn backend_for(&self, key: &str) -> Option<SocketAddr> {
+        match self {
+            Routing::HashRing(ring) => ring.node_for(key),
+            Routing::Prefix(table) => table.backend_for(key),
+        }
+    }
+
+    fn backends(&self) -> Vec<SocketAddr> {
+        let mut backends: Vec<SocketAddr> = match self {
+            Routing::HashRing(ring) => ring.nodes().collect(),
+            Routing::Prefix(table) => table.routes.values().copied().collect(),
+        };
+        backends.sort_unstable();
+        backends.dedup();
+        backends
+    }
+}
+
+/// 键前缀到后端的路由表
+#[derive(Debug, Clone, Default)]
+pub struct PrefixTable {
+    routes: BTreeMap<String, SocketAddr>,
+}
+
+impl PrefixTable {
+    /// 创建空的路由表
+    pub fn new() -> Self {
+        PrefixTable::default()
+    }
+
+    /// 以给定前缀开头的键路由到`backend`，空前缀匹配所有键，可用作默认路由
+    pub fn route(mut self, prefix: impl Into<String>, backend: SocketAddr) -> Self {
+        self.routes.insert(prefix.into(), backend);
+        self
+    }
+
+    /// 匹配给定键的最长前缀对应的后端
+    pub fn backend_for(&self, key: &str) -> Option<SocketAddr> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, backend)| *backend)
+    }
+}
+
+/// 把每个请求转发到对应后端的存储引擎
+///
+/// 与`KvsServer`组合即成为对客户端使用kvs协议的代理。到每个后端的连接放在各自的连接池中复用；
+/// 批量请求按后端拆分后并行发送，每个后端只需一次往返。
+/// 各后端上的数据不能合并为一个整体，因此不支持订阅变更、快照与列出键。
+#[derive(Clone)]
+pub struct ProxyEngine {
+    routing: Arc<Routing>,
+    pools: Arc<HashMap<SocketAddr, Arc<KvsClientPool>>>,
+}
+
+impl ProxyEngine {
+    /// 按给定方式路由，以`options`中的超时、TLS与认证配置连接后端，其中的地址被忽略
+    ///
+    /// 到每个后端最多同时保持`max_connections`个连接，连接在首次使用时建立。
+    pub fn new(routing: Routing, options: KvsClientBuilder, max_connections: usize) -> Result<Self> {
+        let mut pools = HashMap::new();
+        for backend in routing.backends() {
+            let pool = KvsClientPool::builder(options.with_addr(backend))
+                .max_connections(max_connections)
+                // 出错的连接会在下一个请求时自动重连，无需每次借出前检查
+                .health_check(false)
+                .build()?;
+            pools.insert(backend, pool);
+        }
+        Ok(ProxyEngine {
+            routing: Arc::new(routing),
+            pools: Arc::new(pools),
+        })
+    }
+
+    fn pool_for(&self, key: &str) -> Result<&KvsClientPool> {
+        let backend = self.routing.backend_for(key).ok_or_else(|| no_backend(key))?;
+        Ok(&self.pools[&backend])
+    }
+
+    /// 按后端拆分批量请求并行发送，再按原顺序合并结果
+    fn fan_out<T, R, F>(&self, items: Vec<T>, key: fn(&T) -> &str, send: F) -> Vec<Result<R>>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(&mut KvsClient, Vec<T>) -> Result<Vec<Result<R>>> + Sync,
+    {
+        let mut results: Vec<Option<Result<R>>> = (0..items.len()).map(|_| None).collect();
+        let mut groups: HashMap<SocketAddr, (Vec<usize>, Vec<T>)> = HashMap::new();
+        for (i, item) in items.into_iter().enumerate() {
+            match self.routing.backend_for(key(&item)) {
+                Some(backend) => {
+                    let group = groups.entry(backend).or_default();
+                    group.0.push(i);
+                    group.1.push(item);
+                }
+                None => results[i] = Some(Err(no_backend(key(&item)))),
+            }
+        }
+
+        let replies = thread::scope(|scope| {
+            let handles: Vec<_> = groups
+                .into_iter()
+                .map(|(backend, (indexes, items))| {
+                    let pool = &self.pools[&backend];
+                    let send = &send;
+                    let handle = scope.spawn(move || send(&mut *pool.get()?, items));
+                    (indexes, handle)
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|(indexes, handle)| {
+                    let reply = handle
+                        .join()
+                        .unwrap_or_else(|_| Err(KvsError::StringError("Backend request panicked".to_owned())));
+                    (indexes, reply)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        for (indexes, reply) in replies {
+            match reply {
+                Ok(group) if group.len() == indexes.len() => {
+                    for (i, result) in indexes.into_iter().zip(group) {
+                        results[i] = Some(result);
+                    }
+                }
+                Ok(_) => {
+                    for i in indexes {
+                        results[i] = Some(Err(KvsError::UnexpectedResponse));
+                    }
+                }
+                // 整个后端请求失败时，其中每一项都得到同样的错误
+                Err(e) => {
+                    for i in indexes {
+                        results[i] = Some(Err(duplicate(&e)));
+                    }
+                }
+            }
+        }
+        results.into_iter().map(|result| result.expect("every item has a result")).collect()
+    }
+}
+
+impl KvsEngine for ProxyEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.pool_for(&key)?.get()?.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.pool_for(&key)?.get()?.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.pool_for(&key)?.get()?.remove(key)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Vec<Result<Option<String>>> {
+        self.fan_out(keys, |key| key, |client, keys| client.mget(keys))
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Vec<Result<()>> {
+        self.fan_out(pairs, |(key, _)| key, |client, pairs| client.mset(pairs))
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Vec<Result<()>> {
+        self.fan_out(keys, |key| key, |client, keys| client.mremove(keys))
+    }
+
+    fn keys(&self, _prefix: &str) -> Result<Vec<String>> {
+        Err(KvsError::Unsupported("Listing keys".to_owned()))
+    }
+
+    fn watch(&self, _prefix: &str) -> Result<Watcher> {
+        Err(KvsError::Unsupported("Watch".to_owned()))
+    }
+
+    fn changes(&self, _from_seq: u64) -> Result<ChangeStream> {
+        Err(KvsError::Unsupported("Change data capture".to_owned()))
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Err(KvsError::Unsupported("Snapshot".to_owned()))
+    }
+}
+
+fn no_backend(key: &str) -> KvsError {
+    KvsError::StringError(format!("No backend for key {}", key))
+}
+
+/// 为批量请求中的每一项复制同一个错误，I/O错误保留其类型
+fn duplicate(err: &KvsError) -> KvsError {
+    match err {
+        KvsError::Io(e) => KvsError::Io(io::Error::new(e.kind(), e.to_string())),
+        err => KvsError::StringError(err.to_string()),
+    }
+}
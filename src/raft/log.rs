@@ -0,0 +1,187 @@
+use crate::error::Result;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const LOG_FILE: &str = "raft.log";
+const STATE_FILE: &str = "raft.state";
+
+/// 复制到各节点并按顺序应用到存储引擎的命令
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) enum Command {
+    /// 新领导者在任期开始时追加的空条目，用于提交之前任期的条目
+    Noop,
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// Raft日志条目，第一个条目的下标为1
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Entry {
+    pub term: u64,
+    pub command: Command,
+}
+
+/// 必须在回复其他节点前持久化的任期与投票，以及已应用到存储引擎的位置
+#[derive(Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<u64>,
+    /// 该下标及之前的条目已应用到存储引擎，重启后从其后继续应用
+    #[serde(default)]
+    applied: u64,
+}
+
+/// 持久化的Raft日志与任期状态
+///
+/// 条目以JSON逐条追加到日志文件，同时全部保存在内存中；追加与截断在返回前同步到磁盘。
+/// 任期与投票每次变化时整体写入临时文件后重命名，保证不会读到写了一半的状态。
+pub(super) struct RaftLog {
+    dir: PathBuf,
+    state: HardState,
+    entries: Vec<Entry>,
+    /// 每个条目在日志文件中的起始位置，用于截断
+    offsets: Vec<u64>,
+    writer: BufWriter<File>,
+    len: u64,
+}
+
+impl RaftLog {
+    /// 打开给定目录中的Raft日志，不存在时创建
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let state = match File::open(dir.join(STATE_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let path = dir.join(LOG_FILE);
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let mut stream = Deserializer::from_reader(BufReader::new(&file)).into_iter::<Entry>();
+        let mut len = 0;
+        // 进程在写入条目的中途退出时只会留下不完整的最后一条，将其截掉；
+        // 其他解码错误说明日志已损坏，直接返回而不是丢弃之后的条目
+        while let Some(entry) = stream.next() {
+            match entry {
+                Ok(entry) => {
+                    offsets.push(len);
+                    entries.push(entry);
+                    len = stream.byte_offset() as u64;
+                }
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        file.set_len(len)?;
+
+        Ok(RaftLog {
+            dir: dir.to_owned(),
+            state,
+            entries,
+            offsets,
+            writer: BufWriter::new(file),
+            len,
+        })
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub fn voted_for(&self) -> Option<u64> {
+        self.state.voted_for
+    }
+
+    /// 已应用到存储引擎的最后一个条目的下标
+    pub fn applied(&self) -> u64 {
+        self.state.applied
+    }
+
+    /// 持久化新的任期与投票
+    pub fn save_state(&mut self, term: u64, voted_for: Option<u64>) -> Result<()> {
+        self.persist(HardState {
+            term,
+            voted_for,
+            applied: self.state.applied,
+        })
+    }
+
+    /// 持久化已应用到存储引擎的位置，调用前引擎须已把这些条目写入磁盘
+    pub fn save_applied(&mut self, applied: u64) -> Result<()> {
+        self.persist(HardState {
+            term: self.state.term,
+            voted_for: self.state.voted_for,
+            applied,
+        })
+    }
+
+    fn persist(&mut self, state: HardState) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &state)?;
+        file.sync_data()?;
+        fs::rename(tmp, self.dir.join(STATE_FILE))?;
+        self.state = state;
+        Ok(())
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| entry.term)
+    }
+
+    /// 给定下标处条目的任期，下标0对应日志开始之前，任期为0
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            index => self.entry(index).map(|entry| entry.term),
+        }
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        index.checked_sub(1).and_then(|i| self.entries.get(i as usize))
+    }
+
+    /// 从给定下标开始最多`max`个条目
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = (index.max(1) - 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// 在日志末尾追加条目并同步到磁盘，之后才能确认这些条目
+    pub fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        for entry in entries {
+            let bytes = serde_json::to_vec(&entry)?;
+            self.writer.write_all(&bytes)?;
+            self.offsets.push(self.len);
+            self.len += bytes.len() as u64;
+            self.entries.push(entry);
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// 删除给定下标及之后的所有条目
+    pub fn truncate(&mut self, index: u64) -> Result<()> {
+        let start = (index.max(1) - 1) as usize;
+        if start >= self.entries.len() {
+            return Ok(());
+        }
+        self.len = self.offsets[start];
+        self.writer.get_ref().set_len(self.len)?;
+        self.writer.get_ref().sync_data()?;
+        self.entries.truncate(start);
+        self.offsets.truncate(start);
+        Ok(())
+    }
+}
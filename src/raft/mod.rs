@@ -0,0 +1,767 @@
+//! 基于Raft共识算法的集群模式
+//!
+//! 写入作为条目追加到领导者的Raft日志，复制到多数节点后才提交并按顺序应用到每个节点的存储引擎。
+//! 读取同样只由领导者处理，其余节点返回`KvsError::NotLeader`并附带已知的领导者地址。
+//! Raft日志不做快照压缩，节点重启后从头重放已提交的条目。
+//!
+//! 节点之间以集群共享密钥互相验证身份，但之后的通信既不加密也不做完整性校验，
+//! 节点间通信的端口只能暴露在可信的私有网络中。
+
+mod log;
+mod rpc;
+
+use self::log::{Command, Entry, RaftLog};
+use self::rpc::{AppendReply, AppendRequest, Message, MessageReply, Peer, VoteReply, VoteRequest};
+use crate::engines::{ChangeStream, EngineStats, KvsEngine, Snapshot, Watcher};
+use crate::error::{KvsError, Result};
+
+use crossbeam_channel::Sender;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use slog::Logger;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 默认的选举超时下限，实际超时在下限与其两倍之间随机选取
+const DEFAULT_ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// 默认的心跳间隔，须远小于选举超时
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 检查选举超时的间隔
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 写入等待提交、读取等待领导者确认的最长时间
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 单个AppendEntries请求最多携带的条目数
+const MAX_APPEND_ENTRIES: usize = 256;
+
+/// 每个其他节点最多同时保持的入站连接数，超出的连接直接关闭
+const CONNECTIONS_PER_PEER: usize = 4;
+
+/// 集群中的一个节点
+#[derive(Debug, Clone)]
+struct Node {
+    /// 对客户端提供服务的地址，用于重定向
+    addr: SocketAddr,
+    /// 节点之间通信的地址
+    raft_addr: SocketAddr,
+}
+
+/// Raft集群的配置
+///
+/// 集群中每个节点使用相同的节点列表与共享密钥，并以各自的`id`启动：
+///
+/// ```no_run
+/// # use kvs::ClusterConfig;
+/// let config = ClusterConfig::new(1, "data/raft", b"cluster secret")
+///     .node(1, "127.0.0.1:4000".parse().unwrap(), "127.0.0.1:5000".parse().unwrap())
+///     .node(2, "127.0.0.1:4001".parse().unwrap(), "127.0.0.1:5001".parse().unwrap())
+///     .node(3, "127.0.0.1:4002".parse().unwrap(), "127.0.0.1:5002".parse().unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    id: u64,
+    dir: PathBuf,
+    key: hmac::Key,
+    nodes: BTreeMap<u64, Node>,
+    election_timeout: Duration,
+    heartbeat_interval: Duration,
+}
+
+impl ClusterConfig {
+    /// 以给定节点编号运行，Raft日志与任期状态保存在`dir`中
+    ///
+    /// 节点之间建立连接时用`secret`互相验证，只有持有相同密钥的节点才能参与选举与复制。
+    pub fn new(id: u64, dir: impl Into<PathBuf>, secret: &[u8]) -> Self {
+        ClusterConfig {
+            id,
+            dir: dir.into(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            nodes: BTreeMap::new(),
+            election_timeout: DEFAULT_ELECTION_TIMEOUT,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+        }
+    }
+
+    /// 添加一个节点，包括本节点；`addr`为其服务客户端的地址，`raft_addr`为节点间通信的地址
+    pub fn node(mut self, id: u64, addr: SocketAddr, raft_addr: SocketAddr) -> Self {
+        self.nodes.insert(id, Node { addr, raft_addr });
+        self
+    }
+
+    /// 选举超时的下限，跟随者超过该时间未收到领导者的消息即发起选举
+    pub fn election_timeout(mut self, timeout: Duration) -> Self {
+        self.election_timeout = timeout;
+        self
+    }
+
+    /// 领导者发送心跳的间隔
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    fn peers(&self) -> impl Iterator<Item = (u64, &Node)> {
+        let id = self.id;
+        self.nodes.iter().map(|(id, node)| (*id, node)).filter(move |(peer, _)| *peer != id)
+    }
+
+    fn is_majority(&self, count: usize) -> bool {
+        count > self.nodes.len() / 2
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// 由同一把锁保护的节点状态
+struct State {
+    role: Role,
+    log: RaftLog,
+    leader: Option<u64>,
+    /// 最近一次收到当前领导者消息的时间
+    last_heard: Option<Instant>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    votes: HashSet<u64>,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    /// 各节点最近一次确认本节点领导地位的请求的发送时间
+    acked: HashMap<u64, Instant>,
+    /// 成为领导者的时间，刚当选时尚未收到任何确认
+    leader_since: Instant,
+    /// 领导者在本任期追加的第一个条目，应用到该条目后才能保证读到所有已提交的写入
+    term_start: u64,
+    /// 等待条目被应用的写入，以条目下标为键，记录追加时的任期
+    waiters: HashMap<u64, (u64, Sender<Result<()>>)>,
+}
+
+struct Raft {
+    config: ClusterConfig,
+    state: Mutex<State>,
+    /// 状态变化时通知复制、应用线程与等待中的请求
+    changed: Condvar,
+    rng: SystemRandom,
+    logger: Arc<Logger>,
+}
+
+/// 通过Raft复制写入的引擎包装
+///
+/// 写入在多数节点提交后才返回；读取只在确认自己仍是领导者后由本地引擎处理。
+/// 订阅变更与快照直接使用本节点的引擎，其内容与已应用的日志一致。
+#[derive(Clone)]
+pub(crate) struct RaftEngine<E: KvsEngine> {
+    raft: Arc<Raft>,
+    engine: E,
+}
+
+impl<E: KvsEngine> RaftEngine<E> {
+    /// 打开Raft日志，开始监听其他节点并启动后台线程
+    pub fn start(engine: E, config: ClusterConfig, logger: Arc<Logger>) -> Result<Self> {
+        let node = config.nodes.get(&config.id).cloned().ok_or_else(|| {
+            KvsError::StringError(format!("Node {} is not in the cluster configuration", config.id))
+        })?;
+        let log = RaftLog::open(&config.dir)?;
+        // 已应用的条目必然已提交，重启后无需再次应用
+        let applied = log.applied();
+        let listener = TcpListener::bind(node.raft_addr)?;
+        info!(logger, "Raft node {} listening on {}", config.id, node.raft_addr;
+            "term" => log.term(), "log length" => log.last_index());
+
+        let raft = Arc::new(Raft {
+            state: Mutex::new(State {
+                role: Role::Follower,
+                log,
+                leader: None,
+                last_heard: None,
+                commit_index: applied,
+                last_applied: applied,
+                election_deadline: Instant::now(),
+                votes: HashSet::new(),
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                acked: HashMap::new(),
+                leader_since: Instant::now(),
+                term_start: 0,
+                waiters: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+            rng: SystemRandom::new(),
+            config,
+            logger,
+        });
+        raft.reset_election_deadline(&mut raft.lock());
+
+        spawn("kvs-raft-listener", {
+            let raft = raft.clone();
+            move || raft.listen(listener)
+        })?;
+        spawn("kvs-raft-ticker", {
+            let raft = raft.clone();
+            move || raft.tick()
+        })?;
+        spawn("kvs-raft-apply", {
+            let raft = raft.clone();
+            let engine = engine.clone();
+            move || raft.apply_committed(engine)
+        })?;
+        for (peer, node) in raft.config.peers() {
+            let raft = raft.clone();
+            let addr = node.raft_addr;
+            spawn("kvs-raft-replicate", move || raft.replicate(peer, addr))?;
+        }
+        Ok(RaftEngine { raft, engine })
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.raft.propose(Command::Set { key, value })
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.raft.read_barrier()?;
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.raft.propose(Command::Remove { key })
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.raft.read_barrier()?;
+        self.engine.keys(prefix)
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Watcher> {
+        self.engine.watch(prefix)
+    }
+
+    fn changes(&self, from_seq: u64) -> Result<ChangeStream> {
+        self.engine.changes(from_seq)
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        self.engine.snapshot()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        self.engine.backup(dest)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) -> Result<()> {
+    thread::Builder::new().name(name.to_owned()).spawn(f)?;
+    Ok(())
+}
+
+impl Raft {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn wait_timeout<'a>(&self, st: MutexGuard<'a, State>, timeout: Duration) -> MutexGuard<'a, State> {
+        self.changed.wait_timeout(st, timeout).unwrap().0
+    }
+
+    /// 在选举超时下限与其两倍之间随机选取下一次选举的时间，避免各节点同时发起选举
+    fn reset_election_deadline(&self, st: &mut State) {
+        let mut buf = [0; 4];
+        let jitter = match self.rng.fill(&mut buf) {
+            Ok(()) => u32::from_le_bytes(buf) as f64 / u32::MAX as f64,
+            Err(_) => 0.5,
+        };
+        st.election_deadline = Instant::now() + self.config.election_timeout.mul_f64(1.0 + jitter);
+    }
+
+    fn not_leader(&self, st: &State) -> KvsError {
+        let leader = st
+            .leader
+            .filter(|id| *id != self.config.id)
+            .and_then(|id| self.config.nodes.get(&id))
+            .map(|node| node.addr);
+        KvsError::NotLeader(leader)
+    }
+
+    /// 转为跟随者，遇到更高的任期时同时更新任期并清除投票
+    fn step_down(&self, st: &mut State, term: u64) -> Result<()> {
+        if term > st.log.term() {
+            st.log.save_state(term, None)?;
+            st.leader = None;
+        }
+        if st.role == Role::Leader {
+            info!(self.logger, "Stepping down as leader"; "term" => st.log.term());
+            st.leader = None;
+        }
+        st.role = Role::Follower;
+        st.votes.clear();
+        Ok(())
+    }
+
+    fn become_leader(&self, st: &mut State) -> Result<()> {
+        info!(self.logger, "Elected leader"; "term" => st.log.term());
+        st.role = Role::Leader;
+        st.leader = Some(self.config.id);
+        let next = st.log.last_index() + 1;
+        for (peer, _) in self.config.peers() {
+            st.next_index.insert(peer, next);
+            st.match_index.insert(peer, 0);
+        }
+        st.acked.clear();
+        st.leader_since = Instant::now();
+        // 新领导者只能通过提交本任期的条目来间接提交之前任期的条目
+        let term = st.log.term();
+        st.log.append(vec![Entry {
+            term,
+            command: Command::Noop,
+        }])?;
+        st.term_start = st.log.last_index();
+        self.advance_commit(st);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// 把多数节点都已复制的本任期条目标记为已提交
+    fn advance_commit(&self, st: &mut State) {
+        let term = st.log.term();
+        for index in (st.commit_index + 1..=st.log.last_index()).rev() {
+            if st.log.term_at(index) != Some(term) {
+                break;
+            }
+            let replicas = 1 + st.match_index.values().filter(|m| **m >= index).count();
+            if self.config.is_majority(replicas) {
+                st.commit_index = index;
+                self.changed.notify_all();
+                break;
+            }
+        }
+    }
+
+    /// 领导者最近一次得到多数节点确认的请求的发送时间
+    fn quorum_acked(&self, st: &State) -> Option<Instant> {
+        let mut acked: Vec<Instant> = st.acked.values().copied().collect();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        // 加上自身后达到多数所需的其他节点数
+        let needed = self.config.nodes.len() / 2;
+        match needed {
+            0 => Some(Instant::now()),
+            n => acked.get(n - 1).copied(),
+        }
+    }
+
+    /// 写入日志并等待条目被应用，返回应用到本地引擎的结果
+    fn propose(&self, command: Command) -> Result<()> {
+        let (index, rx) = {
+            let mut st = self.lock();
+            if st.role != Role::Leader {
+                return Err(self.not_leader(&st));
+            }
+            let term = st.log.term();
+            st.log.append(vec![Entry { term, command }])?;
+            let index = st.log.last_index();
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            st.waiters.insert(index, (term, tx));
+            self.advance_commit(&mut st);
+            self.changed.notify_all();
+            (index, rx)
+        };
+        match rx.recv_timeout(PROPOSE_TIMEOUT) {
+            Ok(result) => result,
+            Err(_) => {
+                self.lock().waiters.remove(&index);
+                Err(KvsError::CommitTimeout)
+            }
+        }
+    }
+
+    /// 确认本节点仍是领导者且已应用之前所有已提交的写入
+    ///
+    /// 多数节点在一个选举超时内确认过领导地位时，其他节点不会在此期间选出新的领导者，
+    /// 因此无需为每次读取额外发送一轮心跳。
+    fn read_barrier(&self) -> Result<()> {
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        let mut st = self.lock();
+        loop {
+            if st.role != Role::Leader {
+                return Err(self.not_leader(&st));
+            }
+            let lease_valid = self
+                .quorum_acked(&st)
+                .is_some_and(|acked| acked.elapsed() < self.config.election_timeout);
+            if lease_valid && st.last_applied >= st.term_start {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KvsError::CommitTimeout);
+            }
+            st = self.wait_timeout(st, deadline - now);
+        }
+    }
+
+    /// 接受其他节点的连接，每个连接由单独的线程处理，连接数达到上限时拒绝新连接
+    fn listen(self: Arc<Self>, listener: TcpListener) {
+        let max_connections = CONNECTIONS_PER_PEER * self.config.peers().count();
+        let connections = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!(self.logger, "Failed to accept raft connection: {}", e);
+                    continue;
+                }
+            };
+            if connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                warn!(self.logger, "Too many raft connections, rejecting {:?}", stream.peer_addr().ok());
+                continue;
+            }
+            let raft = self.clone();
+            let active = connections.clone();
+            let res = spawn("kvs-raft-peer", move || {
+                if let Err(e) = rpc::serve(stream, &raft.config.key, |msg| raft.handle(msg)) {
+                    match e {
+                        KvsError::AuthFailed => warn!(raft.logger, "Raft peer failed authentication"),
+                        e => debug!(raft.logger, "Raft connection closed: {}", e),
+                    }
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+            if let Err(e) = res {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                error!(self.logger, "Failed to spawn raft connection thread: {}", e);
+            }
+        }
+    }
+
+    fn handle(&self, msg: Message) -> Result<MessageReply> {
+        match msg {
+            Message::Vote(req) => self.handle_vote(req).map(MessageReply::Vote),
+            Message::Append(req) => self.handle_append(req).map(MessageReply::Append),
+        }
+    }
+
+    fn handle_vote(&self, req: VoteRequest) -> Result<VoteReply> {
+        let mut st = self.lock();
+        // 仍能收到领导者消息时忽略投票请求，以免重新加入集群的节点打断正常的领导者
+        let leader_alive = st.role == Role::Leader
+            || st.last_heard.is_some_and(|heard| heard.elapsed() < self.config.election_timeout);
+        if leader_alive {
+            return Ok(VoteReply {
+                term: st.log.term(),
+                granted: false,
+            });
+        }
+        if req.term > st.log.term() {
+            self.step_down(&mut st, req.term)?;
+        }
+        let term = st.log.term();
+        let up_to_date = (req.last_log_term, req.last_log_index) >= (st.log.last_term(), st.log.last_index());
+        let granted = req.term == term && up_to_date && st.log.voted_for().is_none_or(|id| id == req.candidate);
+        if granted {
+            st.log.save_state(term, Some(req.candidate))?;
+            self.reset_election_deadline(&mut st);
+        }
+        Ok(VoteReply { term, granted })
+    }
+
+    fn handle_append(&self, req: AppendRequest) -> Result<AppendReply> {
+        let mut st = self.lock();
+        let term = st.log.term();
+        if req.term < term {
+            return Ok(AppendReply {
+                term,
+                success: false,
+                index: 0,
+            });
+        }
+        if req.term > term || st.role != Role::Follower {
+            self.step_down(&mut st, req.term)?;
+        }
+        if st.leader != Some(req.leader) {
+            info!(self.logger, "Following leader {}", req.leader; "term" => req.term);
+            st.leader = Some(req.leader);
+        }
+        st.last_heard = Some(Instant::now());
+        self.reset_election_deadline(&mut st);
+
+        match st.log.term_at(req.prev_log_index) {
+            Some(prev_term) if prev_term == req.prev_log_term => {}
+            // 跳过整个冲突的任期，而不是逐条回退
+            Some(conflict_term) => {
+                let mut index = req.prev_log_index;
+                while index > 1 && st.log.term_at(index - 1) == Some(conflict_term) {
+                    index -= 1;
+                }
+                return Ok(AppendReply {
+                    term: req.term,
+                    success: false,
+                    index,
+                });
+            }
+            None => {
+                return Ok(AppendReply {
+                    term: req.term,
+                    success: false,
+                    index: st.log.last_index() + 1,
+                })
+            }
+        }
+
+        let last_new = req.prev_log_index + req.entries.len() as u64;
+        let mut index = req.prev_log_index;
+        let mut new_entries = Vec::new();
+        for entry in req.entries {
+            index += 1;
+            if new_entries.is_empty() {
+                match st.log.term_at(index) {
+                    Some(term) if term == entry.term => continue,
+                    Some(_) => st.log.truncate(index)?,
+                    None => {}
+                }
+            }
+            new_entries.push(entry);
+        }
+        st.log.append(new_entries)?;
+
+        if req.leader_commit > st.commit_index {
+            st.commit_index = req.leader_commit.min(last_new);
+            self.changed.notify_all();
+        }
+        Ok(AppendReply {
+            term: req.term,
+            success: true,
+            index: last_new,
+        })
+    }
+
+    /// 定期检查选举超时，领导者则检查是否仍与多数节点保持联系
+    fn tick(self: Arc<Self>) {
+        loop {
+            thread::sleep(TICK_INTERVAL);
+            if let Err(e) = self.check_timeouts() {
+                error!(self.logger, "Raft state error: {}", e);
+            }
+        }
+    }
+
+    fn check_timeouts(self: &Arc<Self>) -> Result<()> {
+        let mut st = self.lock();
+        if st.role == Role::Leader {
+            // 与多数节点失联的领导者主动退位，让客户端尽快转向新的领导者
+            let contact = self.quorum_acked(&st).map_or(st.leader_since, |acked| acked.max(st.leader_since));
+            if contact.elapsed() >= self.config.election_timeout * 2 {
+                let term = st.log.term();
+                self.step_down(&mut st, term)?;
+                self.reset_election_deadline(&mut st);
+                self.changed.notify_all();
+            }
+            return Ok(());
+        }
+        if Instant::now() < st.election_deadline {
+            return Ok(());
+        }
+
+        let term = st.log.term() + 1;
+        st.log.save_state(term, Some(self.config.id))?;
+        debug!(self.logger, "Starting election"; "term" => term);
+        st.role = Role::Candidate;
+        st.leader = None;
+        st.last_heard = None;
+        st.votes = HashSet::from([self.config.id]);
+        self.reset_election_deadline(&mut st);
+        if self.config.is_majority(st.votes.len()) {
+            return self.become_leader(&mut st);
+        }
+
+        let req = VoteRequest {
+            term,
+            candidate: self.config.id,
+            last_log_index: st.log.last_index(),
+            last_log_term: st.log.last_term(),
+        };
+        for (peer, node) in self.config.peers() {
+            let raft = self.clone();
+            let req = req.clone();
+            let addr = node.raft_addr;
+            spawn("kvs-raft-vote", move || {
+                let reply = Peer::new(addr, &raft.config.key)
+                    .call(&Message::Vote(req))
+                    .and_then(MessageReply::into_vote);
+                match reply {
+                    Ok(reply) => {
+                        if let Err(e) = raft.on_vote_reply(peer, term, reply) {
+                            error!(raft.logger, "Raft state error: {}", e);
+                        }
+                    }
+                    Err(e) => debug!(raft.logger, "Vote request to node {} failed: {}", peer, e),
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn on_vote_reply(&self, peer: u64, term: u64, reply: VoteReply) -> Result<()> {
+        let mut st = self.lock();
+        if reply.term > st.log.term() {
+            return self.step_down(&mut st, reply.term);
+        }
+        if st.role != Role::Candidate || st.log.term() != term || !reply.granted {
+            return Ok(());
+        }
+        st.votes.insert(peer);
+        if self.config.is_majority(st.votes.len()) {
+            self.become_leader(&mut st)?;
+        }
+        Ok(())
+    }
+
+    /// 作为领导者时向给定节点复制日志，没有新条目时定期发送心跳
+    fn replicate(self: Arc<Self>, peer_id: u64, addr: SocketAddr) {
+        let mut peer = Peer::new(addr, &self.config.key);
+        let heartbeat = self.config.heartbeat_interval;
+        let mut last_sent = Instant::now() - heartbeat;
+        loop {
+            let req = {
+                let mut st = self.lock();
+                loop {
+                    if st.role == Role::Leader {
+                        let pending = st.next_index[&peer_id] <= st.log.last_index();
+                        let since = last_sent.elapsed();
+                        if pending || since >= heartbeat {
+                            break;
+                        }
+                        st = self.wait_timeout(st, heartbeat - since);
+                    } else {
+                        st = self.wait_timeout(st, heartbeat);
+                    }
+                }
+                let next = st.next_index[&peer_id];
+                AppendRequest {
+                    term: st.log.term(),
+                    leader: self.config.id,
+                    prev_log_index: next - 1,
+                    prev_log_term: st.log.term_at(next - 1).unwrap_or(0),
+                    entries: st.log.entries_from(next, MAX_APPEND_ENTRIES),
+                    leader_commit: st.commit_index,
+                }
+            };
+
+            let term = req.term;
+            last_sent = Instant::now();
+            let sent_at = last_sent;
+            match peer.call(&Message::Append(req)).and_then(MessageReply::into_append) {
+                Ok(reply) => {
+                    if let Err(e) = self.on_append_reply(peer_id, term, sent_at, reply) {
+                        error!(self.logger, "Raft state error: {}", e);
+                    }
+                }
+                Err(e) => {
+                    debug!(self.logger, "Replication to node {} failed: {}", peer_id, e);
+                    // 对方不可用时等到下一次心跳再重试，不必反复连接
+                    thread::sleep(heartbeat);
+                }
+            }
+        }
+    }
+
+    fn on_append_reply(&self, peer: u64, term: u64, sent_at: Instant, reply: AppendReply) -> Result<()> {
+        let mut st = self.lock();
+        if reply.term > st.log.term() {
+            return self.step_down(&mut st, reply.term);
+        }
+        if st.role != Role::Leader || st.log.term() != term {
+            return Ok(());
+        }
+        st.acked.insert(peer, sent_at);
+        if reply.success {
+            let matched = st.match_index[&peer].max(reply.index);
+            st.match_index.insert(peer, matched);
+            st.next_index.insert(peer, matched + 1);
+            self.advance_commit(&mut st);
+        } else {
+            let next = reply.index.clamp(1, st.log.last_index() + 1);
+            st.next_index.insert(peer, next);
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// 按顺序把已提交的条目应用到存储引擎，并通知等待该条目的写入
+    fn apply_committed<E: KvsEngine>(self: Arc<Self>, engine: E) {
+        loop {
+            let (start, entries) = {
+                let mut st = self.lock();
+                while st.last_applied >= st.commit_index {
+                    st = self.changed.wait(st).unwrap();
+                }
+                let count = (st.commit_index - st.last_applied) as usize;
+                (st.last_applied + 1, st.log.entries_from(st.last_applied + 1, count))
+            };
+            for (index, entry) in (start..).zip(entries) {
+                let result = apply(&engine, entry.command);
+                let mut st = self.lock();
+                st.last_applied = index;
+                if let Some((term, tx)) = st.waiters.remove(&index) {
+                    // 等待的条目已被新领导者的条目覆盖，写入没有生效
+                    let result = if term == entry.term { result } else { Err(KvsError::NotLeader(None)) };
+                    let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    debug!(self.logger, "Failed to apply raft entry {}: {}", index, e);
+                }
+                self.changed.notify_all();
+            }
+            self.save_applied(&engine);
+        }
+    }
+
+    /// 在引擎把已应用的条目写入磁盘后记录应用位置
+    ///
+    /// 记录失败时重启后会从更早的位置重新应用，写入与删除重复执行不影响结果。
+    fn save_applied<E: KvsEngine>(&self, engine: &E) {
+        match engine.flush() {
+            Ok(()) | Err(KvsError::Unsupported(_)) => {}
+            Err(e) => {
+                warn!(self.logger, "Failed to flush applied raft entries: {}", e);
+                return;
+            }
+        }
+        let mut st = self.lock();
+        let applied = st.last_applied;
+        if let Err(e) = st.log.save_applied(applied) {
+            warn!(self.logger, "Failed to save raft applied index {}: {}", applied, e);
+        }
+    }
+}
+
+fn apply<E: KvsEngine>(engine: &E, command: Command) -> Result<()> {
+    match command {
+        Command::Noop => Ok(()),
+        Command::Set { key, value } => engine.set(key, value),
+        Command::Remove { key } => engine.remove(key),
+    }
+}
@@ -0,0 +1,205 @@
+use super::log::Entry;
+use crate::codec::{read_frame, write_frame, Codec};
+use crate::error::{KvsError, Result};
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// 节点间请求的连接与读写超时，超时的节点在本次请求中视为不可用；握手同样须在该时间内完成
+const RPC_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// 节点间连接的最长空闲时间，超时后关闭，发起方在下次请求时重新连接
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 握手使用的随机数长度
+const NONCE_LEN: usize = 32;
+
+/// 连接建立后的握手，双方各出一个随机数，并以集群共享密钥的HMAC证明自己属于同一集群
+#[derive(Serialize, Deserialize)]
+enum Handshake {
+    /// 发起方的随机数
+    Hello([u8; NONCE_LEN]),
+    /// 接收方的随机数及其证明
+    Challenge([u8; NONCE_LEN], Vec<u8>),
+    /// 发起方的证明
+    Proof(Vec<u8>),
+}
+
+/// 节点之间的请求，使用二进制编码的帧发送
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum Message {
+    Vote(VoteRequest),
+    Append(AppendRequest),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum MessageReply {
+    Vote(VoteReply),
+    Append(AppendReply),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct VoteRequest {
+    pub term: u64,
+    pub candidate: u64,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct VoteReply {
+    pub term: u64,
+    pub granted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct AppendRequest {
+    pub term: u64,
+    pub leader: u64,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<Entry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct AppendReply {
+    pub term: u64,
+    pub success: bool,
+    /// 成功时为已与领导者一致的最后一个下标，失败时为领导者下次应发送的起始下标
+    pub index: u64,
+}
+
+/// 到另一节点的连接，出错后在下一次请求时重新连接
+pub(super) struct Peer {
+    addr: SocketAddr,
+    key: hmac::Key,
+    stream: Option<TcpStream>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr, key: &hmac::Key) -> Self {
+        Peer {
+            addr,
+            key: key.clone(),
+            stream: None,
+        }
+    }
+
+    /// 发送请求并等待回复
+    ///
+    /// 复用的连接可能已被对方因空闲而关闭，此时重新连接后再试一次。
+    pub fn call(&mut self, msg: &Message) -> Result<MessageReply> {
+        let reused = self.stream.is_some();
+        match self.try_call(msg) {
+            Err(_) if reused => self.try_call(msg),
+            res => res,
+        }
+    }
+
+    fn try_call(&mut self, msg: &Message) -> Result<MessageReply> {
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let res = self.round_trip(msg);
+        if res.is_err() {
+            self.stream = None;
+        }
+        res
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect_timeout(&self.addr, RPC_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+        stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+
+        let nonce = random_nonce()?;
+        write_frame(&mut stream, Codec::Bincode, &Handshake::Hello(nonce))?;
+        let (peer_nonce, proof) = match read_message(&mut stream)? {
+            Handshake::Challenge(peer_nonce, proof) => (peer_nonce, proof),
+            _ => return Err(KvsError::UnexpectedResponse),
+        };
+        hmac::verify(&self.key, &proof_input(b"accept", &nonce, &peer_nonce), &proof)
+            .map_err(|_| KvsError::AuthFailed)?;
+        let proof = hmac::sign(&self.key, &proof_input(b"connect", &nonce, &peer_nonce));
+        write_frame(&mut stream, Codec::Bincode, &Handshake::Proof(proof.as_ref().to_vec()))?;
+        Ok(stream)
+    }
+
+    fn round_trip(&mut self, msg: &Message) -> Result<MessageReply> {
+        let stream = self.stream.as_mut().expect("connected above");
+        write_frame(stream, Codec::Bincode, msg)?;
+        read_message(stream)
+    }
+}
+
+/// 验证对方持有集群密钥后，依次处理一个连接上其他节点发来的请求，直到对方关闭连接或空闲超时
+pub(super) fn serve<F>(mut stream: TcpStream, key: &hmac::Key, mut handle: F) -> Result<()>
+where
+    F: FnMut(Message) -> Result<MessageReply>,
+{
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+    stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+
+    let peer_nonce = match read_message(&mut stream)? {
+        Handshake::Hello(peer_nonce) => peer_nonce,
+        _ => return Err(KvsError::UnexpectedResponse),
+    };
+    let nonce = random_nonce()?;
+    let proof = hmac::sign(key, &proof_input(b"accept", &peer_nonce, &nonce));
+    write_frame(&mut stream, Codec::Bincode, &Handshake::Challenge(nonce, proof.as_ref().to_vec()))?;
+    match read_message(&mut stream)? {
+        Handshake::Proof(proof) => hmac::verify(key, &proof_input(b"connect", &peer_nonce, &nonce), &proof)
+            .map_err(|_| KvsError::AuthFailed)?,
+        _ => return Err(KvsError::UnexpectedResponse),
+    }
+
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    while let Some(frame) = read_frame(&mut stream)? {
+        let msg: Message = Codec::Bincode.decode(&frame)?;
+        let reply = handle(msg)?;
+        write_frame(&mut stream, Codec::Bincode, &reply)?;
+    }
+    Ok(())
+}
+
+fn read_message<T: DeserializeOwned>(stream: &mut TcpStream) -> Result<T> {
+    let frame = read_frame(stream)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    Codec::Bincode.decode(&frame)
+}
+
+fn random_nonce() -> Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| KvsError::StringError("Failed to generate nonce".to_owned()))?;
+    Ok(nonce)
+}
+
+/// 证明的内容包含角色，一方的证明不能被反射回去冒充另一方
+fn proof_input(role: &[u8], connect_nonce: &[u8], accept_nonce: &[u8]) -> Vec<u8> {
+    [role, connect_nonce, accept_nonce].concat()
+}
+
+impl MessageReply {
+    pub fn into_vote(self) -> Result<VoteReply> {
+        match self {
+            MessageReply::Vote(reply) => Ok(reply),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    pub fn into_append(self) -> Result<AppendReply> {
+        match self {
+            MessageReply::Append(reply) => Ok(reply),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+}
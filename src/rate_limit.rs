@@ -0,0 +1,127 @@
+//! 按客户端限流的令牌桶
+
+use crate::auth::Session;
+use crate::error::{KvsError, Result};
+use crate::stream::PeerAddr;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 令牌桶数量超过该值时清理长时间未使用的桶
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// 超过该时间未使用的桶已经装满，删除后再次创建的效果相同
+const BUCKET_IDLE_TIME: Duration = Duration::from_secs(60);
+
+/// 每个客户端的限流配置
+///
+/// 已认证的连接按用户名限流，否则按客户端IP限流，同一用户或IP的所有连接共享额度。
+/// 每个桶最多积攒1秒的额度，用于应对短时突发；请求数额度至少能容纳一个请求。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    ops_per_sec: Option<f64>,
+    bytes_per_sec: Option<f64>,
+}
+
+impl RateLimit {
+    /// 不做任何限制的配置
+    pub fn new() -> Self {
+        RateLimit::default()
+    }
+
+    /// 每秒最多处理的请求数，可以小于1，如0.5表示每2秒一个请求
+    pub fn ops_per_sec(mut self, rate: f64) -> Self {
+        self.ops_per_sec = Some(rate);
+        self
+    }
+
+    /// 每秒最多收发的字节数，包括请求与响应
+    ///
+    /// 大于剩余额度的请求仍会被处理，超出的部分从之后的额度中扣除。
+    pub fn bytes_per_sec(mut self, rate: f64) -> Self {
+        self.bytes_per_sec = Some(rate);
+        self
+    }
+}
+
+/// 所有连接共享的限流状态
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, Buckets>>,
+}
+
+/// 单个客户端的剩余额度，字节额度可以为负数
+struct Buckets {
+    ops: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 为一个大小为`bytes`的请求扣除额度，额度不足时返回`KvsError::Throttled`
+    pub fn acquire(&self, client: &str, bytes: usize) -> Result<()> {
+        let limit = self.limit;
+        self.with_buckets(client, |buckets| {
+            let ops_ok = limit.ops_per_sec.is_none() || buckets.ops >= 1.0;
+            let bytes_ok = limit.bytes_per_sec.is_none() || buckets.bytes >= 0.0;
+            if !ops_ok || !bytes_ok {
+                return Err(KvsError::Throttled);
+            }
+            buckets.ops -= 1.0;
+            buckets.bytes -= bytes as f64;
+            Ok(())
+        })
+    }
+
+    /// 扣除响应占用的字节额度
+    pub fn charge(&self, client: &str, bytes: usize) {
+        self.with_buckets(client, |buckets| buckets.bytes -= bytes as f64)
+    }
+
+    fn with_buckets<T>(&self, client: &str, f: impl FnOnce(&mut Buckets) -> T) -> T {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, b| b.updated.elapsed() < BUCKET_IDLE_TIME);
+        }
+
+        let now = Instant::now();
+        let (ops_cap, bytes_cap) = self.capacity();
+        let entry = buckets.entry(client.to_owned()).or_insert_with(|| Buckets {
+            ops: ops_cap,
+            bytes: bytes_cap,
+            updated: now,
+        });
+        let elapsed = now.duration_since(entry.updated).as_secs_f64();
+        entry.ops = (entry.ops + elapsed * self.limit.ops_per_sec.unwrap_or(0.0)).min(ops_cap);
+        entry.bytes = (entry.bytes + elapsed * self.limit.bytes_per_sec.unwrap_or(0.0)).min(bytes_cap);
+        entry.updated = now;
+        f(entry)
+    }
+
+    /// 桶的容量为1秒的额度，每秒不足一个请求时容量仍为一个请求，否则永远无法积攒到足够的额度
+    fn capacity(&self) -> (f64, f64) {
+        (
+            self.limit.ops_per_sec.map_or(0.0, |rate| rate.max(1.0)),
+            self.limit.bytes_per_sec.unwrap_or(0.0),
+        )
+    }
+}
+
+/// 限流所依据的客户端标识：已认证时为用户名，否则为客户端IP
+pub(crate) fn client_key(session: &Session, peer_addr: &PeerAddr) -> String {
+    match (session.user(), peer_addr) {
+        (Some(user), _) => format!("user:{}", user),
+        (None, PeerAddr::Tcp(addr)) => format!("ip:{}", addr.ip()),
+        // Unix socket的对端没有可区分的地址，共享同一份额度
+        #[cfg(unix)]
+        (None, PeerAddr::Unix(_)) => "unix".to_owned(),
+    }
+}
@@ -0,0 +1,246 @@
+//! 主从复制中从节点一侧的实现
+//!
+//! 从节点通过普通的客户端连接订阅主节点的变更流并写入本地引擎；
+//! 首次启动或所需的变更已被主节点压缩删除时，先加载主节点的完整快照。
+//! 复制进度保存在从节点的数据目录中，重启后从上次的位置继续。
+
+use crate::client::{KvsClient, KvsClientBuilder};
+use crate::engines::{ChangeStream, EngineStats, Event, KvsEngine, Snapshot, Watcher};
+use crate::error::{KvsError, Result};
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use slog::Logger;
+
+/// 与主节点的连接断开后重新连接前的等待时间，连续失败时逐次翻倍
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 重新连接前等待时间的上限
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 保存复制进度的间隔，重启后最多重放这段时间内的变更
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 拒绝写入的引擎包装，从节点对外只提供该包装，复制线程直接写入内部引擎
+#[derive(Clone)]
+pub(crate) struct ReadOnly<E: KvsEngine>(E);
+
+impl<E: KvsEngine> ReadOnly<E> {
+    pub fn new(engine: E) -> Self {
+        ReadOnly(engine)
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReadOnly<E> {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.0.keys(prefix)
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Watcher> {
+        self.0.watch(prefix)
+    }
+
+    fn changes(&self, from_seq: u64) -> Result<ChangeStream> {
+        self.0.changes(from_seq)
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        self.0.snapshot()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.0.compact()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        self.0.backup(dest)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.0.stats()
+    }
+}
+
+/// 在后台线程中持续从主节点复制变更，复制进度保存在`state_path`文件中
+pub(crate) fn spawn<E: KvsEngine>(
+    engine: E,
+    primary: KvsClientBuilder,
+    state_path: PathBuf,
+    logger: Arc<Logger>,
+) -> Result<()> {
+    let progress = Progress::load(state_path)?;
+    if let Some(seq) = progress.next_seq {
+        info!(logger, "Resuming replication from sequence {}", seq);
+    }
+    let progress = Arc::new(Mutex::new(progress));
+
+    // 复制线程可能长时间阻塞在变更流上，由单独的线程定期保存进度
+    let (saved, saver_engine, saver_logger) = (progress.clone(), engine.clone(), logger.clone());
+    thread::Builder::new()
+        .name("kvs-replication-save".to_owned())
+        .spawn(move || loop {
+            thread::sleep(SAVE_INTERVAL);
+            if let Err(e) = saved.lock().unwrap().save(&saver_engine) {
+                warn!(saver_logger, "Failed to save replication progress: {}", e);
+            }
+        })?;
+
+    thread::Builder::new()
+        .name("kvs-replication".to_owned())
+        .spawn(move || {
+            let mut interval = RECONNECT_INTERVAL;
+            loop {
+                let start_seq = progress.lock().unwrap().next_seq;
+                if let Err(e) = replicate(&engine, &primary, &progress, &logger) {
+                    warn!(logger, "Replication interrupted: {}", e; "retry in" => format!("{:?}", interval));
+                }
+                // 有进展说明连接曾经可用，从最短的间隔重新开始
+                if progress.lock().unwrap().next_seq != start_seq {
+                    interval = RECONNECT_INTERVAL;
+                }
+                thread::sleep(interval);
+                interval = (interval * 2).min(MAX_RECONNECT_INTERVAL);
+            }
+        })?;
+    Ok(())
+}
+
+/// 建立一次连接并应用变更，直到连接出错
+fn replicate<E: KvsEngine>(
+    engine: &E,
+    primary: &KvsClientBuilder,
+    progress: &Mutex<Progress>,
+    logger: &Logger,
+) -> Result<()> {
+    let mut client = primary.clone().build()?;
+    loop {
+        let next_seq = progress.lock().unwrap().next_seq;
+        let (from_seq, reloaded) = match next_seq {
+            Some(seq) => (seq, false),
+            None => {
+                let seq = load_snapshot(engine, &mut client)?;
+                info!(logger, "Loaded snapshot from primary at sequence {}", seq);
+                // 立即保存，重启后不必再次加载快照
+                let mut progress = progress.lock().unwrap();
+                progress.next_seq = Some(seq + 1);
+                progress.save(engine)?;
+                (seq + 1, true)
+            }
+        };
+
+        let changes = match client.changes(from_seq) {
+            Ok(changes) => changes,
+            // 刚加载的快照也已被压缩，说明主节点写入过快，等待后重试以免反复加载快照
+            Err(KvsError::SequenceCompacted) if reloaded => return Err(KvsError::SequenceCompacted),
+            // 落后于主节点的压缩点，只能重新加载快照
+            Err(KvsError::SequenceCompacted) => {
+                info!(logger, "Sequence {} compacted on primary, reloading snapshot", from_seq);
+                progress.lock().unwrap().next_seq = None;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        debug!(logger, "Streaming changes from primary since {}", from_seq);
+        for change in changes {
+            let change = change?;
+            apply(engine, change.event)?;
+            progress.lock().unwrap().next_seq = Some(change.seq + 1);
+        }
+    }
+}
+
+/// 保存在从节点数据目录中的复制进度
+///
+/// 先把引擎写入磁盘再保存序号，文件中的序号不会超前于引擎中的数据；
+/// 重启后重放已应用过的变更是幂等的。
+struct Progress {
+    path: PathBuf,
+    /// 下一个需要从主节点获取的序号，为None时需要重新加载快照
+    next_seq: Option<u64>,
+    /// 已写入文件的序号
+    saved: Option<u64>,
+}
+
+impl Progress {
+    /// 读取上次保存的进度，文件不存在时从快照开始
+    fn load(path: PathBuf) -> Result<Self> {
+        let next_seq = match fs::read_to_string(&path) {
+            Ok(content) => Some(content.trim().parse().map_err(|_| {
+                KvsError::StringError(format!("Invalid replication state in {}", path.display()))
+            })?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Progress {
+            path,
+            next_seq,
+            saved: next_seq,
+        })
+    }
+
+    /// 序号有变化时写入文件
+    fn save<E: KvsEngine>(&mut self, engine: &E) -> Result<()> {
+        let seq = match self.next_seq {
+            Some(seq) if self.saved != Some(seq) => seq,
+            _ => return Ok(()),
+        };
+        engine.flush()?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, seq.to_string())?;
+        File::open(&tmp)?.sync_data()?;
+        fs::rename(tmp, &self.path)?;
+        self.saved = Some(seq);
+        Ok(())
+    }
+}
+
+/// 用主节点的快照替换本地数据，返回快照对应的序号
+fn load_snapshot<E: KvsEngine>(engine: &E, client: &mut KvsClient) -> Result<u64> {
+    let mut keys = HashSet::new();
+    let seq = client.snapshot(|key, value| {
+        // 跳过未变化的键，避免本地日志无谓增长
+        if engine.get(key.clone())?.as_ref() != Some(&value) {
+            engine.set(key.clone(), value)?;
+        }
+        keys.insert(key);
+        Ok(())
+    })?;
+    for key in engine.keys("")? {
+        if !keys.contains(&key) {
+            apply(engine, Event::Remove { key })?;
+        }
+    }
+    Ok(seq)
+}
+
+fn apply<E: KvsEngine>(engine: &E, event: Event) -> Result<()> {
+    match event {
+        Event::Set { key, value } => engine.set(key, value),
+        // 本地不存在该键说明已经是删除后的状态
+        Event::Remove { key } => match engine.remove(key) {
+            Err(KvsError::KeyNotFound) => Ok(()),
+            res => res,
+        },
+    }
+}
@@ -0,0 +1,417 @@
+//! Redis RESP2协议兼容层
+//!
+//! 支持GET、SET、DEL、EXISTS、KEYS、PING、INFO与AUTH命令，
+//! 便于使用`redis-cli`或现有的Redis客户端库访问存储引擎。
+
+use crate::auth::{Access, Session};
+use crate::engines::KvsEngine;
+use crate::error::{KvsError, Result};
+use crate::rate_limit::client_key;
+use crate::server::ServerState;
+use crate::stream::{wait_for_request, Deadline, PeerAddr};
+
+use slog::Logger;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// 单个批量字符串的最大字节数
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+
+/// 单条命令的最大参数个数
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// 内联命令或长度头所在行的最大字节数
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// `KEYS`模式的最大字节数
+const MAX_PATTERN_LEN: usize = 1024;
+
+/// RESP2协议中的值
+#[derive(Debug)]
+enum RespValue {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<RespValue>),
+}
+
+impl RespValue {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            RespValue::Simple(s) => write!(writer, "+{}\r\n", s),
+            RespValue::Error(msg) => write!(writer, "-{}\r\n", msg),
+            RespValue::Integer(n) => write!(writer, ":{}\r\n", n),
+            RespValue::Bulk(None) => write!(writer, "$-1\r\n"),
+            RespValue::Bulk(Some(data)) => {
+                write!(writer, "${}\r\n", data.len())?;
+                writer.write_all(data)?;
+                writer.write_all(b"\r\n")
+            }
+            RespValue::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write_to(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<KvsError> for RespValue {
+    fn from(err: KvsError) -> Self {
+        // 与Redis一致，认证与权限错误使用专门的错误前缀
+        match err {
+            KvsError::AuthFailed => RespValue::Error("WRONGPASS invalid username-password pair".to_owned()),
+            KvsError::PermissionDenied => RespValue::Error(format!("NOPERM {}", err)),
+            KvsError::ReadOnly => RespValue::Error("READONLY You can't write against a read only replica.".to_owned()),
+            KvsError::NotLeader(Some(leader)) => RespValue::Error(format!("ERR {}, leader is {}", err, leader)),
+            err => RespValue::Error(format!("ERR {}", err)),
+        }
+    }
+}
+
+/// 处理一个使用RESP2协议的连接
+pub(crate) fn serve<E: KvsEngine, S: BufRead + Write>(
+    engine: E,
+    mut stream: S,
+    mut session: Session,
+    state: &ServerState,
+    peer_addr: PeerAddr,
+    logger: Arc<Logger>,
+) -> Result<()> {
+    loop {
+        if !wait_for_request(&mut stream, state.idle_timeout)? {
+            debug!(logger, "Closing idle connection from {}", peer_addr);
+            break;
+        }
+        let args = match read_command(&mut Deadline::new(&mut stream, state.read_timeout)) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            // 与Redis一致：协议错误时回复错误并关闭连接
+            Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!(logger, "Protocol error from {}: {}", peer_addr, e);
+                RespValue::Error(format!("ERR Protocol error: {}", e)).write_to(&mut stream)?;
+                stream.flush()?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        debug!(logger, "Receive RESP command from {}: {}", peer_addr, String::from_utf8_lossy(&args[0]));
+        let start = Instant::now();
+        let command = command_name(&args[0]);
+
+        let limited = state
+            .rate_limiter
+            .as_ref()
+            .map(|limiter| (limiter, client_key(&session, &peer_addr)));
+        // 按参数的总长度近似计算请求大小
+        let request_len = args.iter().map(Vec::len).sum();
+        let reply = match &limited {
+            Some((limiter, client)) => match limiter.acquire(client, request_len) {
+                Ok(()) => execute(&engine, &mut session, args),
+                Err(e) => e.into(),
+            },
+            None => execute(&engine, &mut session, args),
+        };
+
+        state.metrics.record(command, !matches!(reply, RespValue::Error(_)), start.elapsed());
+        let mut buf = Vec::new();
+        reply.write_to(&mut buf)?;
+        stream.write_all(&buf)?;
+        stream.flush()?;
+        if let Some((limiter, client)) = &limited {
+            limiter.charge(client, buf.len());
+        }
+        debug!(logger, "Response sent to the {}: {:?}", peer_addr, reply);
+    }
+
+    Ok(())
+}
+
+/// 执行一条命令，第一个参数为命令名
+fn execute<E: KvsEngine>(engine: &E, session: &mut Session, args: Vec<Vec<u8>>) -> RespValue {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let mut args = args.into_iter().skip(1);
+
+    macro_rules! string_arg {
+        () => {
+            match args.next().map(String::from_utf8) {
+                Some(Ok(arg)) => arg,
+                Some(Err(e)) => return KvsError::from(e).into(),
+                None => return wrong_arity(&name),
+            }
+        };
+    }
+
+    macro_rules! checked_key {
+        ($access:expr) => {{
+            let key = string_arg!();
+            try_engine!(session.check(&key, $access));
+            key
+        }};
+    }
+
+    macro_rules! try_engine {
+        ($res:expr) => {
+            match $res {
+                Ok(value) => value,
+                Err(e) => return e.into(),
+            }
+        };
+    }
+
+    match name.as_str() {
+        "PING" => match args.next() {
+            None => RespValue::Simple("PONG"),
+            Some(msg) if args.len() == 0 => RespValue::Bulk(Some(msg)),
+            Some(_) => wrong_arity(&name),
+        },
+        "AUTH" => {
+            // `AUTH password`为旧版单密码形式，对应default用户
+            let (user, password) = match args.len() {
+                1 => ("default".to_owned(), string_arg!()),
+                2 => (string_arg!(), string_arg!()),
+                _ => return wrong_arity(&name),
+            };
+            try_engine!(session.login(user, &password));
+            RespValue::Simple("OK")
+        }
+        "GET" => {
+            let key = checked_key!(Access::Read);
+            if args.len() != 0 {
+                return wrong_arity(&name);
+            }
+            RespValue::Bulk(try_engine!(engine.get(key)).map(String::into_bytes))
+        }
+        "SET" => {
+            let key = checked_key!(Access::Write);
+            let value = string_arg!();
+            if args.len() != 0 {
+                return RespValue::Error("ERR syntax error".to_owned());
+            }
+            try_engine!(engine.set(key, value));
+            RespValue::Simple("OK")
+        }
+        "DEL" => {
+            if args.len() == 0 {
+                return wrong_arity(&name);
+            }
+            let mut removed = 0;
+            while args.len() != 0 {
+                match engine.remove(checked_key!(Access::Write)) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return e.into(),
+                }
+            }
+            RespValue::Integer(removed)
+        }
+        "EXISTS" => {
+            if args.len() == 0 {
+                return wrong_arity(&name);
+            }
+            let mut found = 0;
+            while args.len() != 0 {
+                if try_engine!(engine.get(checked_key!(Access::Read))).is_some() {
+                    found += 1;
+                }
+            }
+            RespValue::Integer(found)
+        }
+        "KEYS" => {
+            let pattern = string_arg!();
+            if args.len() != 0 {
+                return wrong_arity(&name);
+            }
+            if pattern.len() > MAX_PATTERN_LEN {
+                return RespValue::Error("ERR pattern too long".to_owned());
+            }
+            let keys = try_engine!(engine.keys(literal_prefix(&pattern)));
+            RespValue::Array(
+                keys.into_iter()
+                    .filter(|key| glob_match(pattern.as_bytes(), key.as_bytes()))
+                    // 无读权限的键视为不存在
+                    .filter(|key| session.check(key, Access::Read).is_ok())
+                    .map(|key| RespValue::Bulk(Some(key.into_bytes())))
+                    .collect(),
+            )
+        }
+        "INFO" => {
+            let info = format!(
+                "# Server\r\nkvs_version:{}\r\nredis_mode:standalone\r\n",
+                env!("CARGO_PKG_VERSION")
+            );
+            RespValue::Bulk(Some(info.into_bytes()))
+        }
+        _ => RespValue::Error(format!("ERR unknown command '{}'", name)),
+    }
+}
+
+/// 监控指标中使用的命令名，未知命令统一记为unknown，以免任意输入产生大量指标
+fn command_name(name: &[u8]) -> &'static str {
+    const COMMANDS: [&str; 8] = ["ping", "auth", "get", "set", "del", "exists", "keys", "info"];
+    COMMANDS
+        .iter()
+        .find(|command| command.as_bytes().eq_ignore_ascii_case(name))
+        .copied()
+        .unwrap_or("unknown")
+}
+
+fn wrong_arity(name: &str) -> RespValue {
+    RespValue::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+/// 读取一条命令，返回命令名及其参数
+///
+/// 同时支持客户端库发送的批量字符串数组，以及telnet等工具发送的内联命令。
+/// 若对端在命令边界处关闭连接，则返回None。
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_len(&header[1..], MAX_BULK_LEN)?;
+
+        let mut data = vec![0; len + 2];
+        reader.read_exact(&mut data)?;
+        if !data.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is not terminated by CRLF"));
+        }
+        data.truncate(len);
+        args.push(data);
+    }
+    Ok(Some(args))
+}
+
+/// 读取一行并去掉结尾的CRLF，超过`MAX_LINE_LEN`的行视为协议错误
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.by_ref().take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if line.len() >= MAX_LINE_LEN {
+            return Err(protocol_error("line too long"));
+        }
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// 返回模式中第一个通配符之前的部分，用于缩小需要匹配的键的范围
+fn literal_prefix(pattern: &str) -> &str {
+    let end = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Redis风格的通配符匹配，支持`*`、`?`、`[abc]`、`[^a]`、`[a-z]`与`\`转义
+///
+/// 除`*`外每个记号恰好匹配一个字符，因此只需记住最近一个`*`的位置用于回溯，
+/// 耗时至多为模式长度与字符串长度之积，且不使用递归。
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // 最近一个`*`之后的模式位置，以及该`*`当前吞掉的字符串末尾
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        if let Some(next) = match_token(pattern, p, s[i]) {
+            p = next;
+            i += 1;
+            continue;
+        }
+        // 失配时让最近的`*`多吞一个字符，从其后重新匹配
+        match star {
+            Some((star_p, star_i)) => {
+                p = star_p;
+                i = star_i + 1;
+                star = Some((star_p, i));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// 用模式中`p`处的单个记号匹配字符`c`，匹配时返回下一个记号的位置
+fn match_token(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match &pattern[p.min(pattern.len())..] {
+        [] => None,
+        [b'?', ..] => Some(p + 1),
+        [b'\\', esc, ..] => (*esc == c).then_some(p + 2),
+        [b'[', class @ ..] => {
+            let negate = class.first() == Some(&b'^');
+            let mut rest = if negate { &class[1..] } else { class };
+            let mut matched = false;
+            loop {
+                match rest {
+                    // 未闭合的字符类：将`[`按字面量匹配，其后的部分照常解析
+                    [] => return (c == b'[').then_some(p + 1),
+                    [b']', tail @ ..] => {
+                        rest = tail;
+                        break;
+                    }
+                    [b'\\', esc, tail @ ..] => {
+                        matched |= *esc == c;
+                        rest = tail;
+                    }
+                    [lo, b'-', hi, tail @ ..] if *hi != b']' => {
+                        let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                        matched |= *lo <= c && c <= *hi;
+                        rest = tail;
+                    }
+                    [ch, tail @ ..] => {
+                        matched |= *ch == c;
+                        rest = tail;
+                    }
+                }
+            }
+            (matched != negate).then_some(pattern.len() - rest.len())
+        }
+        [ch, ..] => (*ch == c).then_some(p + 1),
+    }
+}
@@ -1,86 +1,1141 @@
-use crate::engines::KvsEngine;
-use crate::error::Result;
+use crate::auth::{Access, AuthConfig, Session};
+use crate::engines::{KvsEngine, Snapshot};
+use crate::client::KvsClientBuilder;
+use crate::rate_limit::{client_key, RateLimit, RateLimiter};
+use crate::raft::{ClusterConfig, RaftEngine};
+use crate::replication::{self, ReadOnly};
+use crate::codec::{read_frame, write_frame, Codec};
+use crate::error::{KvsError, Result};
+use crate::stream::{wait_for_request, BufStream, Deadline, PeerAddr};
+use crate::tls::ServerTls;
+use crate::metrics::{self, Metrics};
+use crate::{http, resp};
 use crate::common::*;
 use crate::thread_pool::ThreadPool;
 
+use std::fmt::{self, Display};
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::io::{BufReader, BufWriter, Write};
-use std::sync::Arc;
-use serde_json::de::Deserializer;
+use std::str::FromStr;
+use std::io::{self, BufRead, Write};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use crossbeam_channel::{Sender, TrySendError};
+use std::thread;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Component, Path, PathBuf};
+use ring::digest::{Context, Digest, SHA256};
 use slog::Logger;
 
+/// 服务器对外提供的协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// kvs自身的帧协议，供`KvsClient`使用
+    Kvs,
+    /// Redis RESP2协议，供`redis-cli`及Redis客户端库使用
+    Resp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Protocol::Kvs => write!(f, "kvs"),
+            Protocol::Resp => write!(f, "resp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(KvsError::StringError(format!("Unknown protocol: {}", s))),
+        }
+    }
+}
+
 /// Kvs服务器
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
+    protocol: Protocol,
+    http_addr: Option<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    tls: Option<ServerTls>,
+    auth: Option<Arc<AuthConfig>>,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+    max_connections: Option<usize>,
+    queue_size: usize,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    replica_of: Option<(KvsClientBuilder, PathBuf)>,
+    cluster: Option<ClusterConfig>,
+    slow_log: Option<(Duration, usize)>,
+    backup_dir: Option<PathBuf>,
+}
+
+/// 默认最多排队等待工作线程的连接数
+const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// 最多排队等待发送"server busy"响应的连接数，超出时直接关闭连接
+const REJECT_QUEUE_SIZE: usize = 64;
+
+/// 发送"server busy"响应时读写客户端的超时时间
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 订阅变更的连接在没有事件时发送心跳的间隔，写入失败即说明客户端已断开
+const WATCH_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `Snapshot`回复中每批键值对的大致字节数
+const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// 慢请求日志中键的最大字符数
+const SLOW_LOG_KEY_LEN: usize = 64;
+
+/// 所有连接共享的服务器配置与状态
+pub(crate) struct ServerState {
+    protocol: Protocol,
+    tls: Option<ServerTls>,
+    auth: Option<Arc<AuthConfig>>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    idempotency: IdempotencyCache,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    /// 已接受且尚未关闭的连接数，包括排队中的连接
+    pub(crate) connections: AtomicUsize,
+    /// 已交给线程池但尚未被工作线程处理的连接数
+    pub(crate) queued: AtomicUsize,
+    /// 启动以来接受与因繁忙拒绝的连接数
+    accepted: AtomicUsize,
+    rejected: AtomicUsize,
+    started: Instant,
+    pub(crate) metrics: Metrics,
+    slow_log: Option<SlowLog>,
+    backup_dir: Option<PathBuf>,
+}
+
+impl ServerState {
+    /// socket的读超时，决定检查空闲超时与请求截止时间的间隔，因此取两者中较小的值
+    ///
+    /// 空闲超时在请求边界处反复等待实现，读请求的截止时间由`Deadline`检查，两者互不影响。
+    fn socket_timeout(&self) -> Option<Duration> {
+        match (self.idle_timeout, self.read_timeout) {
+            (Some(idle), Some(read)) => Some(idle.min(read)),
+            (idle, read) => idle.or(read),
+        }
+    }
+
+    fn stats<E: KvsEngine>(&self, engine: &E) -> ServerStats {
+        ServerStats {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime: self.started.elapsed(),
+            // 不支持统计的引擎（如代理）仍可返回服务器自身的状态
+            engine: engine.stats().ok(),
+            connections: self.connections.load(Ordering::SeqCst) as u64,
+            queued_connections: self.queued.load(Ordering::SeqCst) as u64,
+            accepted_connections: self.accepted.load(Ordering::SeqCst) as u64,
+            rejected_connections: self.rejected.load(Ordering::SeqCst) as u64,
+        }
+    }
+}
+
+/// 连接关闭时减少连接数
+struct ConnectionGuard(Arc<ServerState>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 已交给线程池的连接
+///
+/// 任务未被执行就被丢弃时（如线程池的等待队列已满），连接转交给拒绝线程回复"server busy"。
+struct QueuedConnection {
+    conn: Option<Connection>,
+    rejecter: Sender<Connection>,
+}
+
+impl Drop for QueuedConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let _ = self.rejecter.try_send(conn);
+        }
+    }
+}
+
+/// 把监听器接受的连接交给分发线程，分发队列已满时直接拒绝，返回false表示服务器已停止
+fn forward(
+    tx: &Sender<io::Result<Connection>>,
+    conn: io::Result<Connection>,
+    state: &ServerState,
+    rejecter: &Sender<Connection>,
+) -> bool {
+    match tx.try_send(conn) {
+        Ok(()) => true,
+        Err(TrySendError::Full(Ok(conn))) => {
+            state.rejected.fetch_add(1, Ordering::SeqCst);
+            // 拒绝队列也已满时直接关闭连接
+            let _ = rejecter.try_send(conn);
+            true
+        }
+        Err(TrySendError::Full(Err(_))) => true,
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+/// 耗时超过阈值的最近若干个请求，超出容量时淘汰最早的记录
+struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    entries: Mutex<VecDeque<SlowRequest>>,
+}
+
+impl SlowLog {
+    fn push(&self, entry: SlowRequest) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// 最新的记录在前
+    fn entries(&self) -> Vec<SlowRequest> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+/// 最多记住的幂等令牌数量
+const IDEMPOTENCY_CACHE_SIZE: usize = 64 * 1024;
+
+/// 最近执行过的幂等令牌，超出容量时淘汰最早的令牌
+///
+/// 客户端重连后重试的请求会到达另一个连接，因此需要在连接之间共享。
+/// 令牌按用户区分，并记录原请求键值的摘要，同一令牌用于不同的写入时返回错误。
+#[derive(Default)]
+struct IdempotencyCache {
+    inner: Mutex<IdempotencyEntries>,
+    /// 原请求执行完毕时唤醒等待中的重试
+    finished: Condvar,
+}
+
+/// 以登录的用户与令牌区分的请求
+type IdempotencyKey = (Option<String>, String);
+
+#[derive(Default)]
+struct IdempotencyEntries {
+    entries: HashMap<IdempotencyKey, IdempotentRequest>,
+    /// 按登记顺序排列的令牌与登记序号，序号不一致说明该令牌已被移除或重新登记，淘汰时跳过
+    order: VecDeque<(u64, IdempotencyKey)>,
+    next_seq: u64,
+}
+
+struct IdempotentRequest {
+    seq: u64,
+    digest: Digest,
+    completed: bool,
+}
+
+impl IdempotencyCache {
+    /// 登记令牌，返回true时调用者须执行请求并调用`finish`
+    ///
+    /// 令牌已登记时说明这是重试：原请求仍在执行则等待其结束，已成功则返回false；
+    /// 原请求失败时令牌被移除，由重试重新执行。
+    fn begin(&self, id: &IdempotencyKey, digest: Digest) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            match inner.entries.get(id) {
+                Some(entry) if entry.digest.as_ref() != digest.as_ref() => return Err(KvsError::IdempotencyConflict),
+                Some(entry) if entry.completed => return Ok(false),
+                Some(_) => inner = self.finished.wait(inner).unwrap(),
+                None => break,
+            }
+        }
+
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.entries.insert(
+            id.clone(),
+            IdempotentRequest {
+                seq,
+                digest,
+                completed: false,
+            },
+        );
+        inner.order.push_back((seq, id.clone()));
+        if inner.order.len() > IDEMPOTENCY_CACHE_SIZE {
+            if let Some((seq, oldest)) = inner.order.pop_front() {
+                if inner.entries.get(&oldest).is_some_and(|entry| entry.seq == seq) {
+                    inner.entries.remove(&oldest);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// 记录请求的执行结果，失败时移除令牌，使重试能够再次执行
+    fn finish(&self, id: &IdempotencyKey, ok: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if ok {
+            if let Some(entry) = inner.entries.get_mut(id) {
+                entry.completed = true;
+            }
+        } else {
+            inner.entries.remove(id);
+        }
+        drop(inner);
+        self.finished.notify_all();
+    }
+}
+
+/// 把`Backup`请求的目标解析为备份目录下的路径，拒绝绝对路径与跳出备份目录的路径
+fn backup_path(state: &ServerState, dest: &Path) -> Result<PathBuf> {
+    let dir = state.backup_dir.as_ref().ok_or_else(|| {
+        KvsError::StringError("Backups are disabled, the server has no backup directory".to_owned())
+    })?;
+    let relative = dest.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !relative || dest.as_os_str().is_empty() {
+        return Err(KvsError::StringError(format!(
+            "Backup destination {} must be a relative path inside the backup directory",
+            dest.display()
+        )));
+    }
+    Ok(dir.join(dest))
+}
+
+/// 幂等请求所写入键值的摘要，键的长度在前，避免不同的键值拼接后相同
+fn idempotency_digest(key: &str, value: &str) -> Digest {
+    let mut context = Context::new(&SHA256);
+    context.update(&(key.len() as u64).to_le_bytes());
+    context.update(key.as_bytes());
+    context.update(value.as_bytes());
+    context.finish()
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
-    /// 根据给定存储引擎生成一个Kvs服务器
+    /// 根据给定存储引擎生成一个使用kvs协议的Kvs服务器
     pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+        KvsServer {
+            engine,
+            pool,
+            protocol: Protocol::Kvs,
+            http_addr: None,
+            metrics_addr: None,
+            tls: None,
+            auth: None,
+            #[cfg(unix)]
+            unix_socket: None,
+            max_connections: None,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            idle_timeout: None,
+            read_timeout: None,
+            rate_limit: None,
+            replica_of: None,
+            cluster: None,
+            slow_log: None,
+            backup_dir: None,
+        }
+    }
+
+    /// 设置服务器对外提供的协议
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// 额外在给定地址上提供HTTP/JSON网关，与主协议共享同一存储引擎
+    pub fn http_addr(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// 额外在给定地址上以Prometheus文本格式提供`/metrics`监控指标
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// 使用TLS加密主协议的连接
+    pub fn tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// 要求客户端先认证，并按用户的前缀规则检查每个键的读写权限
+    pub fn auth(mut self, auth: AuthConfig) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// 同时保持的最大连接数，超出时新连接会收到"server busy"响应
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// 最多排队等待工作线程的连接数，超出时新连接会收到"server busy"响应
+    ///
+    /// 每个连接在关闭前独占一个工作线程，因此工作线程都被占用时新连接需要排队。
+    pub fn queue_size(mut self, size: usize) -> Self {
+        self.queue_size = size;
+        self
+    }
+
+    /// 连接在两个请求之间最长的空闲时间，超时后服务器关闭连接
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// 从收到请求的第一个字节起读完整个请求的最长时间，超时后服务器关闭连接
+    ///
+    /// 该时间限制整个请求而不是单次读取，持续缓慢发送数据的客户端同样会超时。
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// 按用户或客户端IP限制请求速率，超出额度的请求返回`KvsError::Throttled`
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// 额外在给定路径上监听Unix domain socket，与TCP共用同一请求循环
+    ///
+    /// Unix socket上的连接不使用TLS，访问控制由socket文件的权限与认证配置负责。
+    #[cfg(unix)]
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    /// 作为给定主节点的从节点运行
+    ///
+    /// 服务器在后台持续复制主节点的变更，首次启动时以及落后于主节点的压缩点时先加载完整快照。
+    /// 复制进度保存在`state_path`文件中，应与引擎数据放在同一目录，重启后从该位置继续。
+    /// 从节点拒绝所有写入请求，返回`KvsError::ReadOnly`。主节点需使用`KvStore`引擎。
+    pub fn replica_of(mut self, primary: KvsClientBuilder, state_path: impl Into<PathBuf>) -> Self {
+        self.replica_of = Some((primary, state_path.into()));
+        self
+    }
+
+    /// 作为Raft集群的一个节点运行
+    ///
+    /// 写入复制到多数节点后才返回，读写请求都只由领导者处理，
+    /// 其余节点返回`KvsError::NotLeader`，`KvsClient`据此自动重定向到领导者。
+    pub fn cluster(mut self, config: ClusterConfig) -> Self {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// 记录耗时超过`threshold`的请求，以warn级别输出日志并保留最近`capacity`条，
+    /// 可通过`SlowLog`请求获取
+    ///
+    /// 耗时分为连接等待工作线程、执行请求与写回响应三部分。
+    pub fn slow_log(mut self, threshold: Duration, capacity: usize) -> Self {
+        self.slow_log = Some((threshold, capacity));
+        self
+    }
+
+    /// 允许`Backup`请求把副本写入该目录，请求中的目标须为其中的相对路径
+    ///
+    /// 未设置时拒绝所有`Backup`请求，避免客户端让服务器写入任意路径。
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
     }
 
     /// 运行监听给定addr的Kvs服务器
     pub fn run(self, addr: SocketAddr, logger: Arc<Logger>) -> Result<()> {
+        self.check_config()?;
         let listener = TcpListener::bind(addr)?;
+        self.serve_listeners(Some(listener), logger)
+    }
 
-        for stream in listener.incoming() {
-            let engine = self.engine.clone();
-            let connection_logger = logger.clone();
+    /// 运行只监听给定路径上Unix domain socket的Kvs服务器
+    #[cfg(unix)]
+    pub fn run_unix(self, path: impl Into<PathBuf>, logger: Arc<Logger>) -> Result<()> {
+        let server = self.unix_socket(path);
+        server.check_config()?;
+        server.serve_listeners(None, logger)
+    }
+
+    fn check_config(&self) -> Result<()> {
+        if self.http_addr.is_some() && self.auth.is_some() {
+            // HTTP网关没有认证机制，同时启用会绕过访问控制
+            return Err(KvsError::StringError(
+                "The HTTP gateway cannot be used together with authentication".to_owned(),
+            ));
+        }
+        if self.replica_of.is_some() && self.cluster.is_some() {
+            return Err(KvsError::StringError(
+                "A cluster node cannot also be a replica".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 从节点对外只提供只读的引擎，复制线程写入原始引擎；集群节点对外提供经Raft复制的引擎
+    fn serve_listeners(mut self, tcp: Option<TcpListener>, logger: Arc<Logger>) -> Result<()> {
+        let engine = self.engine.clone();
+        if let Some(config) = self.cluster.take() {
+            let engine = RaftEngine::start(engine, config, logger.clone())?;
+            return self.accept(engine, tcp, logger);
+        }
+        match self.replica_of.take() {
+            Some((primary, state_path)) => {
+                replication::spawn(engine.clone(), primary, state_path, logger.clone())?;
+                self.accept(ReadOnly::new(engine), tcp, logger)
+            }
+            None => self.accept(engine, tcp, logger),
+        }
+    }
+
+    /// 每个监听器在独立线程中接受连接，统一交给线程池处理
+    fn accept<F: KvsEngine>(self, engine: F, tcp: Option<TcpListener>, logger: Arc<Logger>) -> Result<()> {
+        if let Some(http_addr) = self.http_addr {
+            http::spawn(engine.clone(), http_addr, logger.clone())?;
+        }
+
+        let state = Arc::new(ServerState {
+            protocol: self.protocol,
+            tls: self.tls,
+            auth: self.auth,
+            idle_timeout: self.idle_timeout,
+            read_timeout: self.read_timeout,
+            idempotency: IdempotencyCache::default(),
+            rate_limiter: self.rate_limit.map(RateLimiter::new),
+            connections: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            accepted: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            started: Instant::now(),
+            metrics: Metrics::default(),
+            slow_log: self.slow_log.map(|(threshold, capacity)| SlowLog {
+                threshold,
+                capacity,
+                entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            }),
+            backup_dir: self.backup_dir,
+        });
+        if let Some(metrics_addr) = self.metrics_addr {
+            metrics::spawn(engine.clone(), state.clone(), metrics_addr, logger.clone())?;
+        }
+        let rejecter = spawn_rejecter(state.clone(), logger.clone())?;
 
-            self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream, connection_logger.clone()) {
-                        error!(connection_logger, "Error on serving client: {}", e);
+        let (tx, rx) = crossbeam_channel::bounded(self.queue_size);
+        if let Some(listener) = tcp {
+            let (tx, state, rejecter) = (tx.clone(), state.clone(), rejecter.clone());
+            thread::Builder::new().spawn(move || {
+                for stream in listener.incoming() {
+                    if !forward(&tx, stream.map(Connection::Tcp), &state, &rejecter) {
+                        break;
+                    }
+                }
+            })?;
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.unix_socket {
+            let listener = bind_unix(path)?;
+            let (tx, state, rejecter) = (tx.clone(), state.clone(), rejecter.clone());
+            thread::Builder::new().spawn(move || {
+                for stream in listener.incoming() {
+                    if !forward(&tx, stream.map(Connection::Unix), &state, &rejecter) {
+                        break;
                     }
                 }
-                Err(e) => error!(connection_logger, "Connection failed: {}", e),
+            })?;
+        }
+        drop(tx);
+
+        for conn in rx {
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!(logger, "Connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            let connections = state.connections.load(Ordering::SeqCst);
+            if self.max_connections.is_some_and(|max| connections >= max)
+                || state.queued.load(Ordering::SeqCst) >= self.queue_size
+            {
+                warn!(logger, "Server busy, rejecting connection"; "connections" => connections);
+                state.rejected.fetch_add(1, Ordering::SeqCst);
+                // 拒绝队列已满时直接关闭连接，避免接受连接的线程被阻塞
+                let _ = rejecter.try_send(conn);
+                continue;
+            }
+
+            state.connections.fetch_add(1, Ordering::SeqCst);
+            state.queued.fetch_add(1, Ordering::SeqCst);
+            let engine = engine.clone();
+            let connection_logger = logger.clone();
+            let guard = ConnectionGuard(state.clone());
+            let mut queued = QueuedConnection {
+                conn: Some(conn),
+                rejecter: rejecter.clone(),
+            };
+            let queued_at = Instant::now();
+
+            let spawned = self.pool.try_spawn(move || {
+                let conn = queued.conn.take().expect("connection taken twice");
+                let state = guard.0.clone();
+                state.queued.fetch_sub(1, Ordering::SeqCst);
+                let queue_wait = queued_at.elapsed();
+                if let Err(e) = handle_connection(engine, conn, state, queue_wait, connection_logger.clone()) {
+                    error!(connection_logger, "Error on serving client: {}", e);
+                }
+                drop(guard);
             });
+            if spawned {
+                state.accepted.fetch_add(1, Ordering::SeqCst);
+            } else {
+                warn!(logger, "Thread pool queue full, rejecting connection");
+                state.queued.fetch_sub(1, Ordering::SeqCst);
+                state.rejected.fetch_add(1, Ordering::SeqCst);
+            }
         }
 
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream, logger: Arc<Logger>) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let tcp_cloned = tcp.try_clone()?;
-    let req_reader = Deserializer::from_reader(BufReader::new(&tcp)).into_iter::<Request>();
-    let mut writer = BufWriter::new(&tcp_cloned);
+/// 已接受的连接
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// 绑定Unix socket，并清理上次运行遗留的socket文件
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        // 只删除socket文件，避免误删同名的普通文件
+        if meta.file_type().is_socket() && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// 按需建立TLS会话，再交给对应协议的请求循环处理
+fn handle_connection<E: KvsEngine>(
+    engine: E,
+    conn: Connection,
+    state: Arc<ServerState>,
+    queue_wait: Duration,
+    logger: Arc<Logger>,
+) -> Result<()> {
+    match conn {
+        Connection::Tcp(tcp) => {
+            let peer_addr = PeerAddr::Tcp(tcp.peer_addr()?);
+            // 流水线请求的响应会被逐个写回，关闭Nagle算法以免其与延迟确认相互等待
+            tcp.set_nodelay(true)?;
+            tcp.set_read_timeout(state.socket_timeout())?;
+            match &state.tls {
+                Some(tls) => dispatch(engine, BufStream::new(tls.accept(tcp)?), &state, peer_addr, queue_wait, logger),
+                None => dispatch(engine, BufStream::new(tcp), &state, peer_addr, queue_wait, logger),
+            }
+        }
+        #[cfg(unix)]
+        Connection::Unix(unix) => {
+            let peer_addr = PeerAddr::of_unix(&unix)?;
+            unix.set_read_timeout(state.socket_timeout())?;
+            dispatch(engine, BufStream::new(unix), &state, peer_addr, queue_wait, logger)
+        }
+    }
+}
+
+fn dispatch<E: KvsEngine, S: BufRead + Write>(
+    engine: E,
+    stream: S,
+    state: &ServerState,
+    peer_addr: PeerAddr,
+    queue_wait: Duration,
+    logger: Arc<Logger>,
+) -> Result<()> {
+    let session = Session::new(state.auth.clone());
+    match state.protocol {
+        Protocol::Kvs => serve(engine, stream, session, state, peer_addr, queue_wait, logger),
+        Protocol::Resp => resp::serve(engine, stream, session, state, peer_addr, logger),
+    }
+}
+
+/// 在后台线程中向被拒绝的连接发送"server busy"响应
+fn spawn_rejecter(state: Arc<ServerState>, logger: Arc<Logger>) -> Result<Sender<Connection>> {
+    let (tx, rx) = crossbeam_channel::bounded(REJECT_QUEUE_SIZE);
+    thread::Builder::new().spawn(move || {
+        for conn in rx {
+            if let Err(e) = reject(conn, &state) {
+                debug!(logger, "Failed to reject connection: {}", e);
+            }
+        }
+    })?;
+    Ok(tx)
+}
+
+fn reject(conn: Connection, state: &ServerState) -> Result<()> {
+    match conn {
+        Connection::Tcp(tcp) => {
+            tcp.set_read_timeout(Some(REJECT_TIMEOUT))?;
+            tcp.set_write_timeout(Some(REJECT_TIMEOUT))?;
+            match &state.tls {
+                Some(tls) => send_busy(BufStream::new(tls.accept(tcp)?), state.protocol),
+                None => send_busy(BufStream::new(tcp), state.protocol),
+            }
+        }
+        #[cfg(unix)]
+        Connection::Unix(unix) => {
+            unix.set_read_timeout(Some(REJECT_TIMEOUT))?;
+            unix.set_write_timeout(Some(REJECT_TIMEOUT))?;
+            send_busy(BufStream::new(unix), state.protocol)
+        }
+    }
+}
+
+/// 回复"server busy"后关闭连接
+///
+/// kvs协议先读取客户端的`Hello`，再以带错误的`HelloAck`回复；
+/// 若在客户端数据未读完时关闭连接，对端可能收到RST而读不到响应。
+fn send_busy<S: BufRead + Write>(mut stream: S, protocol: Protocol) -> Result<()> {
+    match protocol {
+        Protocol::Kvs => {
+            if read_frame(&mut stream)?.is_none() {
+                return Ok(());
+            }
+            write_frame(&mut stream, Codec::Json, &HelloAck::rejected(KvsError::ServerBusy.into()))?;
+        }
+        Protocol::Resp => {
+            stream.write_all(b"-ERR max number of clients reached\r\n")?;
+        }
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+fn serve<E: KvsEngine, S: BufRead + Write>(
+    engine: E,
+    mut stream: S,
+    mut session: Session,
+    state: &ServerState,
+    peer_addr: PeerAddr,
+    mut queue_wait: Duration,
+    logger: Arc<Logger>,
+) -> Result<()> {
+    if !wait_for_request(&mut stream, state.idle_timeout)? {
+        return Ok(());
+    }
+    // 握手帧始终使用JSON编码，之后切换到协商出的编码方式
+    let hello: Hello = match read_frame(&mut Deadline::new(&mut stream, state.read_timeout))? {
+        Some(frame) => Codec::Json.decode(&frame)?,
+        None => return Ok(()),
+    };
+    let ack = HelloAck::negotiate(&hello);
+    write_frame(&mut stream, Codec::Json, &ack)?;
+    stream.flush()?;
+    if !ack.is_accepted(&hello) {
+        warn!(logger, "No common protocol version with {}: {:?}", peer_addr, hello);
+        return Ok(());
+    }
+    debug!(logger, "Handshake with {} succeeded: {:?}", peer_addr, ack);
+    let codec = ack.codec;
 
     macro_rules! send_resp {
-        ($resp:expr) => {
+        ($logger:expr, $resp:expr) => {
             {
                 let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!(logger, "Response sent to the {}: {:?}", peer_addr, resp);
+                let written = write_frame(&mut stream, codec, &resp)?;
+                stream.flush()?;
+                debug!($logger, "Response sent to the {}: {:?}", peer_addr, resp);
+                written
             }
         };
     }
 
-    for req in req_reader {
-        let req = req?;
-        debug!(logger, "Receive request from {}: {:?}", peer_addr, req);
+    loop {
+        if !wait_for_request(&mut stream, state.idle_timeout)? {
+            debug!(logger, "Closing idle connection from {}", peer_addr);
+            break;
+        }
+        let frame = match read_frame(&mut Deadline::new(&mut stream, state.read_timeout))? {
+            Some(frame) => frame,
+            None => break,
+        };
+        let start = Instant::now();
 
-        match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
-                Ok(value) => GetResponse::Ok(value),
-                Err(e) => GetResponse::Err(format!("{}", e)),
-            }),
-            Request::Rm { key } => send_resp!(match engine.remove(key) {
-                Ok(_) => RmResponse::Ok(()),
-                Err(e) => RmResponse::Err(format!("{}", e))
-            }),
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(_) => SetResponse::Ok(()),
-                Err(e) => SetResponse::Err(format!("{}", e))
-            }),
+        // 认证后按用户限流，因此每个请求都重新确定客户端标识
+        let limited = state
+            .rate_limiter
+            .as_ref()
+            .map(|limiter| (limiter, client_key(&session, &peer_addr)));
+        if let Some((limiter, client)) = &limited {
+            if let Err(e) = limiter.acquire(client, 4 + frame.len()) {
+                debug!(logger, "Request from {} throttled", peer_addr; "client" => client);
+                send_resp!(logger, Response::new(Err(e.into())));
+                continue;
+            }
+        }
+
+        // 帧边界独立于负载，因此无法解析的请求只需回复错误，后续请求不受影响
+        let req = match codec.decode::<Request>(&frame) {
+            Ok(req) => req,
+            Err(e) => {
+                warn!(logger, "Invalid request from {}: {}", peer_addr, e);
+                send_resp!(logger, Response::new(Err(e.into())));
+                continue;
+            }
+        };
+
+        // 附带请求ID的请求在该请求的所有日志中记录其ID，并在响应中原样返回
+        let (request_id, req) = match req {
+            Request::Traced { request_id, request } => (Some(request_id), *request),
+            req => (None, req),
+        };
+        let logger = match &request_id {
+            Some(request_id) => logger.new(o!("request_id" => request_id.clone())),
+            None => Logger::clone(&logger),
+        };
+
+        let command = req.name();
+        let key = state.slow_log.as_ref().and_then(|_| req.key_summary());
+        // 只在访问存储引擎期间进入该span，不包括认证与事件推送
+        let span = tracing::info_span!("engine", command, request_id = request_id.as_deref(), peer = %peer_addr);
+        let executed = Instant::now();
+        // 快照分多帧发送，在写回阶段代替单个响应
+        let mut snapshot = None;
+        let result = match req {
+            Request::Auth { user, password } => {
+                // 请求中含有密码，只记录用户名
+                debug!(logger, "Receive auth request from {} as {}", peer_addr, user);
+                match session.login(user, &password) {
+                    Ok(()) => Ok(Reply::Done),
+                    Err(e) => {
+                        warn!(logger, "Authentication failed for {}", peer_addr);
+                        Err(e.into())
+                    }
+                }
+            }
+            Request::Watch { key_or_prefix } => {
+                debug!(logger, "Receive watch request from {}: {:?}", peer_addr, key_or_prefix; "user" => session.user());
+                let watcher = session
+                    .check(&key_or_prefix, Access::Read)
+                    .and_then(|()| span.in_scope(|| engine.watch(&key_or_prefix)));
+                match watcher {
+                    Ok(mut watcher) => {
+                        state.metrics.record(command, true, start.elapsed());
+                        send_resp!(logger, Response::new(traced(request_id, Ok(Reply::Done))));
+                        // 该连接此后只用于推送事件，直到连接断开
+                        let res = push_events(&mut stream, codec, |timeout| {
+                            Ok(watcher.next_timeout(timeout)?.map(Reply::Event))
+                        });
+                        debug!(logger, "Watch from {} ended: {:?}", peer_addr, res);
+                        return res;
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Request::Changes { from_seq } => {
+                debug!(logger, "Receive changes request from {} since {}", peer_addr, from_seq; "user" => session.user());
+                match span.in_scope(|| engine.changes(from_seq)) {
+                    Ok(mut changes) => {
+                        state.metrics.record(command, true, start.elapsed());
+                        send_resp!(logger, Response::new(traced(request_id, Ok(Reply::Done))));
+                        let res = push_events(&mut stream, codec, |timeout| {
+                            // 变更涉及所有键，只推送当前用户有权读取的部分
+                            let change = changes.next_timeout(timeout)?;
+                            Ok(change
+                                .filter(|change| session.check(change.event.key(), Access::Read).is_ok())
+                                .map(Reply::Change))
+                        });
+                        debug!(logger, "Changes from {} ended: {:?}", peer_addr, res);
+                        return res;
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Request::Snapshot => {
+                debug!(logger, "Receive snapshot request from {}", peer_addr; "user" => session.user());
+                // 快照包含所有键，要求对全部键有读权限
+                match session.check("", Access::Read).and_then(|()| span.in_scope(|| engine.snapshot())) {
+                    Ok(taken) => {
+                        let seq = taken.seq();
+                        snapshot = Some(taken);
+                        Ok(Reply::SnapshotDone(seq))
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+            req => {
+                debug!(logger, "Receive request from {}: {:?}", peer_addr, req; "user" => session.user());
+                span.in_scope(|| execute(&engine, &session, state, req))
+            }
+        };
+        let engine_time = executed.elapsed();
+        // 在写回响应之前记录，客户端收到响应时指标已经更新
+        state.metrics.record(command, result.is_ok(), start.elapsed());
+        let written_at = Instant::now();
+        let written = match snapshot {
+            Some(snapshot) => {
+                let seq = snapshot.seq();
+                let written = send_snapshot(&mut stream, codec, snapshot, request_id)?;
+                debug!(logger, "Snapshot at {} sent to {}", seq, peer_addr);
+                written
+            }
+            None => send_resp!(logger, Response::new(traced(request_id, result))),
+        };
+        let write_back = written_at.elapsed();
+        if let Some((limiter, client)) = &limited {
+            limiter.charge(client, written);
         }
+
+        if let Some(slow_log) = &state.slow_log {
+            let total = queue_wait + start.elapsed();
+            if total > slow_log.threshold {
+                let entry = SlowRequest {
+                    at: SystemTime::now(),
+                    command: command.to_owned(),
+                    key: key.map(truncate_key),
+                    peer: peer_addr.to_string(),
+                    user: session.user().map(str::to_owned),
+                    total,
+                    queue_wait,
+                    engine: engine_time,
+                    write_back,
+                };
+                warn!(logger, "Slow request from {}: {} took {:?}", peer_addr, command, total;
+                    "key" => &entry.key,
+                    "queue_wait" => ?entry.queue_wait,
+                    "engine" => ?entry.engine,
+                    "write_back" => ?entry.write_back);
+                slow_log.push(entry);
+            }
+        }
+        // 连接的排队时间只计入第一个请求
+        queue_wait = Duration::ZERO;
     }
 
     Ok(())
 }
 
+/// 执行访问存储引擎的请求，多键请求中的每个键单独检查权限
+fn execute<E: KvsEngine>(engine: &E, session: &Session, state: &ServerState, req: Request) -> ReplyResult {
+    match req {
+        Request::Get { key } => get(engine, session, key),
+        Request::Rm { key } => remove(engine, session, key),
+        Request::Set { key, value } => set(engine, session, key, value),
+        Request::MGet { keys } => batch(
+            session,
+            Access::Read,
+            keys,
+            |key| key,
+            |keys| engine.get_many(keys),
+            Reply::Value,
+        ),
+        Request::MSet { pairs } => batch(
+            session,
+            Access::Write,
+            pairs,
+            |(key, _)| key,
+            |pairs| engine.set_many(pairs),
+            |()| Reply::Done,
+        ),
+        Request::MRm { keys } => batch(
+            session,
+            Access::Write,
+            keys,
+            |key| key,
+            |keys| engine.remove_many(keys),
+            |()| Reply::Done,
+        ),
+        Request::SetIdempotent { key, value, token } => {
+            session.check(&key, Access::Write)?;
+            let id = (session.user().map(str::to_owned), token);
+            // 令牌已存在说明这是重试，原请求已成功执行
+            if !state.idempotency.begin(&id, idempotency_digest(&key, &value))? {
+                return Ok(Reply::Done);
+            }
+            let result = set(engine, session, key, value);
+            state.idempotency.finish(&id, result.is_ok());
+            result
+        }
+        Request::Ping => Ok(Reply::Done),
+        Request::Stats => {
+            session.check_authenticated()?;
+            Ok(Reply::Stats(Box::new(state.stats(engine))))
+        }
+        Request::Compact => {
+            session.check_admin()?;
+            engine.compact()?;
+            Ok(Reply::Done)
+        }
+        Request::Flush => {
+            session.check_admin()?;
+            engine.flush()?;
+            Ok(Reply::Done)
+        }
+        Request::Backup { dest } => {
+            session.check_admin()?;
+            engine.backup(&backup_path(state, &dest)?)?;
+            Ok(Reply::Done)
+        }
+        Request::SlowLog => {
+            session.check_admin()?;
+            let entries = state.slow_log.as_ref().map_or_else(Vec::new, SlowLog::entries);
+            Ok(Reply::SlowLog(entries))
+        }
+        Request::Auth { .. }
+        | Request::Watch { .. }
+        | Request::Changes { .. }
+        | Request::Snapshot
+        | Request::Traced { .. } => Err(KvsError::UnexpectedCommandType.into()),
+    }
+}
+
+/// 请求附带请求ID时，把执行结果连同该ID一起返回
+fn traced(request_id: Option<String>, result: ReplyResult) -> ReplyResult {
+    match request_id {
+        Some(request_id) => Ok(Reply::Traced {
+            request_id,
+            result: Box::new(result),
+        }),
+        None => result,
+    }
+}
+
+/// 按字符截断过长的键，避免日志被超长的键淹没
+fn truncate_key(key: String) -> String {
+    match key.char_indices().nth(SLOW_LOG_KEY_LEN) {
+        Some((end, _)) => format!("{}...", &key[..end]),
+        None => key,
+    }
+}
+
+/// 分批发送快照，读取出错时以错误代替`SnapshotDone`结束回复，返回写入的字节数
+///
+/// 请求附带请求ID时，由结束回复带回该ID。
+fn send_snapshot<S: Write>(
+    stream: &mut S,
+    codec: Codec,
+    mut snapshot: Snapshot,
+    request_id: Option<String>,
+) -> Result<usize> {
+    let seq = snapshot.seq();
+    let mut written = 0;
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0;
+    let result = loop {
+        match snapshot.next() {
+            Some(Ok((key, value))) => {
+                chunk_size += key.len() + value.len();
+                chunk.push((key, value));
+                if chunk_size >= SNAPSHOT_CHUNK_SIZE {
+                    written += write_frame(stream, codec, &Response::new(Ok(Reply::SnapshotChunk(chunk))))?;
+                    chunk = Vec::new();
+                    chunk_size = 0;
+                }
+            }
+            Some(Err(e)) => break Err(e.into()),
+            None => break Ok(Reply::SnapshotDone(seq)),
+        }
+    };
+    if !chunk.is_empty() && result.is_ok() {
+        written += write_frame(stream, codec, &Response::new(Ok(Reply::SnapshotChunk(chunk))))?;
+    }
+    written += write_frame(stream, codec, &Response::new(traced(request_id, result)))?;
+    stream.flush()?;
+    Ok(written)
+}
 
+/// 将`next`产生的事件逐个推送给客户端，直到写入失败或订阅被关闭
+///
+/// `next`最多等待给定时长，没有可推送的事件时返回None，此时发送心跳。
+/// `Watch`的前缀已通过读权限检查，其下的所有键均可读，因此无需逐个检查事件。
+fn push_events<S, F>(stream: &mut S, codec: Codec, mut next: F) -> Result<()>
+where
+    S: Write,
+    F: FnMut(Duration) -> Result<Option<Reply>>,
+{
+    loop {
+        let result = match next(WATCH_HEARTBEAT_INTERVAL) {
+            Ok(Some(reply)) => Ok(reply),
+            Ok(None) => Ok(Reply::Heartbeat),
+            Err(e) => Err(e.into()),
+        };
+        let closed = result.is_err();
+        write_frame(stream, codec, &Response::new(result))?;
+        stream.flush()?;
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+/// 先逐个检查权限，再把有权访问的部分一次交给引擎，结果按请求中的顺序合并
+fn batch<T, R, F, M>(
+    session: &Session,
+    access: Access,
+    items: Vec<T>,
+    key: fn(&T) -> &String,
+    run: F,
+    map: M,
+) -> ReplyResult
+where
+    F: FnOnce(Vec<T>) -> Vec<Result<R>>,
+    M: Fn(R) -> Reply,
+{
+    let mut denied = Vec::with_capacity(items.len());
+    let mut allowed = Vec::new();
+    for item in items {
+        match session.check(key(&item), access) {
+            Ok(()) => {
+                denied.push(None);
+                allowed.push(item);
+            }
+            Err(e) => denied.push(Some(e)),
+        }
+    }
+    let mut results = run(allowed).into_iter();
+    let replies = denied
+        .into_iter()
+        .map(|denied| match denied {
+            Some(e) => Err(e.into()),
+            None => match results.next() {
+                Some(result) => result.map(&map).map_err(Into::into),
+                None => Err(KvsError::UnexpectedResponse.into()),
+            },
+        })
+        .collect();
+    Ok(Reply::Multi(replies))
+}
+
+fn get<E: KvsEngine>(engine: &E, session: &Session, key: String) -> ReplyResult {
+    session.check(&key, Access::Read)?;
+    Ok(Reply::Value(engine.get(key)?))
+}
+
+fn set<E: KvsEngine>(engine: &E, session: &Session, key: String, value: String) -> ReplyResult {
+    session.check(&key, Access::Write)?;
+    engine.set(key, value)?;
+    Ok(Reply::Done)
+}
+
+fn remove<E: KvsEngine>(engine: &E, session: &Session, key: String) -> ReplyResult {
+    session.check(&key, Access::Write)?;
+    engine.remove(key)?;
+    Ok(Reply::Done)
+}
@@ -0,0 +1,247 @@
+//! 按一致性哈希把键分布到多个kvs-server的客户端
+
+use crate::client::{KvsClient, KvsClientBuilder};
+use crate::error::{KvsError, Result};
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+
+/// 默认每个节点在哈希环上的虚拟节点数
+const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// 带虚拟节点的一致性哈希环
+///
+/// 每个节点在环上占据多个位置，键归属于其哈希值之后的第一个位置所属的节点。
+/// 增删一个节点时，只有落在该节点位置上的键改变归属。
+/// 哈希函数固定不变，因此不同进程中由相同节点构成的环对每个键给出相同的结果，与节点加入的顺序无关。
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    /// 极少数位置会被多个节点占据，此时该位置归属其中地址最小的节点
+    ring: BTreeMap<u64, BTreeSet<SocketAddr>>,
+    nodes: BTreeSet<SocketAddr>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        HashRing::new(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+impl HashRing {
+    /// 创建空的哈希环，每个节点占据`virtual_nodes`个位置
+    pub fn new(virtual_nodes: usize) -> Self {
+        HashRing {
+            virtual_nodes: virtual_nodes.max(1),
+            ring: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    /// 添加节点，节点已存在时返回false
+    pub fn add(&mut self, node: SocketAddr) -> bool {
+        if !self.nodes.insert(node) {
+            return false;
+        }
+        for i in 0..self.virtual_nodes {
+            self.ring.entry(hash(&format!("{}#{}", node, i))).or_default().insert(node);
+        }
+        true
+    }
+
+    /// 移除节点，节点不存在时返回false
+    pub fn remove(&mut self, node: SocketAddr) -> bool {
+        if !self.nodes.remove(&node) {
+            return false;
+        }
+        for i in 0..self.virtual_nodes {
+            let h = hash(&format!("{}#{}", node, i));
+            if let Some(nodes) = self.ring.get_mut(&h) {
+                nodes.remove(&node);
+                if nodes.is_empty() {
+                    self.ring.remove(&h);
+                }
+            }
+        }
+        true
+    }
+
+    /// 给定键所属的节点，环为空时返回None
+    pub fn node_for(&self, key: &str) -> Option<SocketAddr> {
+        let h = hash(key);
+        self.ring
+            .range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .and_then(|(_, nodes)| nodes.first().copied())
+    }
+
+    /// 环上的所有节点
+    pub fn nodes(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.nodes.iter().copied()
+    }
+
+    /// 环上的节点数
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// 环上是否没有节点
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+/// FNV-1a哈希再经过64位混合，使相近的虚拟节点名也能均匀分布在环上
+fn hash(s: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in s.bytes() {
+        h ^= u64::from(byte);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// 按一致性哈希把键分布到多个kvs-server的客户端
+///
+/// 每个键只存放在哈希环上对应的一个服务器上，到各服务器的连接在首次使用时建立。
+/// 增删节点只改变路由，已写入的键需通过`rebalance`或`migrate_from`迁移到新的归属节点。
+///
+/// ```no_run
+/// # use kvs::{Result, ShardedKvsClient};
+/// # fn main() -> Result<()> {
+/// let mut client = ShardedKvsClient::connect(vec![
+///     "127.0.0.1:4000".parse().unwrap(),
+///     "127.0.0.1:4001".parse().unwrap(),
+/// ]);
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// client.add_node("127.0.0.1:4002".parse().unwrap());
+/// client.rebalance()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    options: KvsClientBuilder,
+    clients: HashMap<SocketAddr, KvsClient>,
+}
+
+impl ShardedKvsClient {
+    /// 使用默认的客户端配置连接给定的服务器
+    pub fn connect(addrs: Vec<SocketAddr>) -> Self {
+        // 连接每个服务器时会替换其中的地址
+        let options = KvsClientBuilder::new(([127, 0, 0, 1], 0).into());
+        Self::with_options(addrs, options)
+    }
+
+    /// 以`options`中的超时、重试、TLS与认证配置连接每个服务器，其中的地址被忽略
+    pub fn with_options(addrs: Vec<SocketAddr>, options: KvsClientBuilder) -> Self {
+        let mut ring = HashRing::default();
+        for addr in addrs {
+            ring.add(addr);
+        }
+        ShardedKvsClient {
+            ring,
+            options,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// 把服务器加入哈希环，之后归属于它的键路由到该服务器
+    pub fn add_node(&mut self, addr: SocketAddr) -> bool {
+        self.ring.add(addr)
+    }
+
+    /// 把服务器移出哈希环并关闭到它的连接，其上的键不再可见，直到被迁移
+    pub fn remove_node(&mut self, addr: SocketAddr) -> bool {
+        self.clients.remove(&addr);
+        self.ring.remove(addr)
+    }
+
+    /// 哈希环上的所有服务器
+    pub fn nodes(&self) -> Vec<SocketAddr> {
+        self.ring.nodes().collect()
+    }
+
+    /// 给定键所属的服务器
+    pub fn node_for(&self, key: &str) -> Option<SocketAddr> {
+        self.ring.node_for(key)
+    }
+
+    /// 从键所属的服务器获取对应值
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key)?.get(key)
+    }
+
+    /// 在键所属的服务器上设置键值对
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for(&key)?.set(key, value)
+    }
+
+    /// 删除键所属的服务器上的给定键
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client_for(&key)?.remove(key)
+    }
+
+    /// 把每个服务器上不再归属于它的键迁移到当前的归属节点，返回迁移的键数
+    pub fn rebalance(&mut self) -> Result<usize> {
+        let mut moved = 0;
+        for addr in self.nodes() {
+            moved += self.migrate_from(addr)?;
+        }
+        Ok(moved)
+    }
+
+    /// 把给定服务器上不归属于它的键迁移到当前的归属节点，返回迁移的键数
+    ///
+    /// 服务器可以已被移出哈希环，此时其上所有的键都会被迁移。
+    /// 需要源服务器支持快照，迁移期间对这些键的并发写入可能被覆盖。
+    pub fn migrate_from(&mut self, addr: SocketAddr) -> Result<usize> {
+        let mut misplaced = Vec::new();
+        let ring = &self.ring;
+        client(&mut self.clients, &self.options, addr)?.snapshot(|key, value| {
+            if ring.node_for(&key) != Some(addr) {
+                misplaced.push((key, value));
+            }
+            Ok(())
+        })?;
+
+        let moved = misplaced.len();
+        for (key, value) in misplaced {
+            self.client_for(&key)?.set(key.clone(), value)?;
+            match client(&mut self.clients, &self.options, addr)?.remove(key) {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !self.ring.nodes.contains(&addr) {
+            self.clients.remove(&addr);
+        }
+        Ok(moved)
+    }
+
+    fn client_for(&mut self, key: &str) -> Result<&mut KvsClient> {
+        let addr = self
+            .ring
+            .node_for(key)
+            .ok_or_else(|| KvsError::StringError("No nodes in the hash ring".to_owned()))?;
+        client(&mut self.clients, &self.options, addr)
+    }
+}
+
+/// 取得到给定服务器的连接，尚未连接时先建立连接
+fn client<'a>(
+    clients: &'a mut HashMap<SocketAddr, KvsClient>,
+    options: &KvsClientBuilder,
+    addr: SocketAddr,
+) -> Result<&'a mut KvsClient> {
+    match clients.entry(addr) {
+        Entry::Occupied(entry) => Ok(entry.into_mut()),
+        Entry::Vacant(entry) => Ok(entry.insert(options.with_addr(addr).build()?)),
+    }
+}
@@ -0,0 +1,179 @@
+//! 连接所使用的底层字节流
+
+use std::fmt::{self, Display};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// 可读写并可在线程间传递的字节流，如`TcpStream`或TLS流
+pub(crate) trait ReadWrite: Read + Write + Send {}
+
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+/// 连接的对端地址，用于日志
+#[derive(Debug, Clone)]
+pub(crate) enum PeerAddr {
+    Tcp(SocketAddr),
+    /// 客户端的Unix socket通常未绑定路径
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+#[cfg(unix)]
+impl PeerAddr {
+    pub fn of_unix(stream: &UnixStream) -> io::Result<Self> {
+        let addr = stream.peer_addr()?;
+        Ok(PeerAddr::Unix(addr.as_pathname().map(Into::into)))
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            PeerAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            PeerAddr::Unix(None) => write!(f, "unix:<unnamed>"),
+        }
+    }
+}
+
+/// 等待下一个请求的第一个字节，用于在请求边界处实现空闲超时
+///
+/// 底层socket需设置读超时，超时后若空闲时间尚未达到`idle_timeout`则继续等待。
+/// 返回false表示连接空闲过久；对端关闭连接时返回true，由随后的读取发现EOF。
+pub(crate) fn wait_for_request<R: BufRead>(reader: &mut R, idle_timeout: Option<Duration>) -> io::Result<bool> {
+    let start = Instant::now();
+    loop {
+        match reader.fill_buf() {
+            Ok(_) => return Ok(true),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if idle_timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    return Ok(false);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// 限制读完一个请求所用时间的读端
+///
+/// 底层socket的读超时只限制单次读取，对端每隔一段时间发送一个字节即可一直占用连接；
+/// 该读端在每次读取前检查截止时间，超过时返回`TimedOut`错误。
+/// 截止时间之前socket读超时产生的错误会被忽略并继续等待，因此请求中途的停顿不受空闲超时影响。
+pub(crate) struct Deadline<'a, R> {
+    inner: &'a mut R,
+    deadline: Option<Instant>,
+}
+
+impl<'a, R> Deadline<'a, R> {
+    /// 从现在起最多读取`timeout`，为None时不限制
+    pub fn new(inner: &'a mut R, timeout: Option<Duration>) -> Self {
+        Deadline {
+            inner,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out reading request"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    )
+}
+
+impl<R: Read> Read for Deadline<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            self.check()?;
+            match self.inner.read(buf) {
+                Err(e) if is_timeout(&e) => {}
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Deadline<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        loop {
+            self.check()?;
+            match self.inner.fill_buf() {
+                Err(e) if is_timeout(&e) => {}
+                Err(e) => return Err(e),
+                Ok(_) => break,
+            }
+        }
+        // 缓冲区已有数据或已到EOF，再次调用会立即返回
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+/// 在同一个底层流上提供读缓冲与写缓冲
+///
+/// TLS流无法像`TcpStream`那样通过`try_clone`拆分为独立的读端与写端，
+/// 因此读写共用一个流：写入的数据先缓存在内存中，直到`flush`时才一次性写出。
+pub(crate) struct BufStream<S: Read + Write> {
+    reader: BufReader<S>,
+    write_buf: Vec<u8>,
+}
+
+impl<S: Read + Write> BufStream<S> {
+    pub fn new(inner: S) -> Self {
+        BufStream {
+            reader: BufReader::new(inner),
+            write_buf: Vec::new(),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<S: Read + Write> BufRead for BufStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+impl<S: Read + Write> Write for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = self.reader.get_mut();
+        inner.write_all(&self.write_buf)?;
+        self.write_buf.clear();
+        inner.flush()
+    }
+}
@@ -38,6 +38,14 @@ pub trait ThreadPool {
     /// 2. 即使任务执行时panic也不会影响线程池运行
     /// 3. 线程池会维持固定的线程数量
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static;
+
+    /// 尝试向线程池提交任务，等待队列已满时不提交并返回false，任务随之被丢弃
+    ///
+    /// 默认实现总是提交成功，适用于没有等待队列的线程池。
+    fn try_spawn<F>(&self, job: F) -> bool where F: FnOnce() + Send + 'static {
+        self.spawn(job);
+        true
+    }
 }
 
 mod naive;
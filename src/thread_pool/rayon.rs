@@ -6,7 +6,7 @@ pub struct RayonThreadPool(rayon::ThreadPool);
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        if threads <= 0 {
+        if threads == 0 {
             return Err(KvsError::StringError("Argument 'threads' must be positive".to_string()));
         }
 
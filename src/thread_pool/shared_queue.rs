@@ -1,6 +1,6 @@
 use std::thread;
 
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
 use super::ThreadPool;
 use crate::{Result, KvsError};
 
@@ -8,7 +8,6 @@ type Job = Box<dyn FnOnce() + Send + 'static>;
 
 enum ThreadPoolMessage {
     RunJob(Job),
-    Shutdown,
 }
 
 #[derive(Clone)]
@@ -26,31 +25,32 @@ impl Drop for TaskReceiver {
 }
 
 fn run_tasks(rx: TaskReceiver) {
-    loop {
-        match rx.0.recv() {
-            Ok(ThreadPoolMessage::RunJob(task)) => {
-                task();
-            }
-            Err(_) | Ok(ThreadPoolMessage::Shutdown) => break,
-        }
+    // 所有发送端被丢弃后recv返回错误，工作线程随之退出
+    while let Ok(ThreadPoolMessage::RunJob(task)) = rx.0.recv() {
+        task();
     }
 }
 
 /// 通过共享队列实现的线程池
 /// 
 /// 如果线程池中的一个线程发生panic，那么旧线程会被销毁，同时创建一个新线程。
+/// `new`创建的线程池等待队列没有上限；通过`with_queue_size`创建时队列已满则`spawn`阻塞直到有空位，
+/// `try_spawn`直接拒绝任务。
 pub struct SharedQueueThreadPool {
     task_sender: Sender<ThreadPoolMessage>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
-        if threads <= 0 {
+impl SharedQueueThreadPool {
+    /// 创建线程池，最多`queue_size`个任务排队等待工作线程
+    pub fn with_queue_size(threads: u32, queue_size: usize) -> Result<Self> {
+        Self::with_channel(threads, bounded(queue_size))
+    }
+
+    fn with_channel(threads: u32, (s, r): (Sender<ThreadPoolMessage>, Receiver<ThreadPoolMessage>)) -> Result<Self> {
+        if threads == 0 {
             return Err(KvsError::StringError("Argument 'threads' must be positive".to_string()));
         }
 
-        let (s, r) = unbounded();
-
         for _ in 0..threads {
             let task_receiver = TaskReceiver(r.clone());
             thread::Builder::new().spawn(move || run_tasks(task_receiver))?;
@@ -58,10 +58,22 @@ impl ThreadPool for SharedQueueThreadPool {
 
         Ok(Self { task_sender: s })
     }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        Self::with_channel(threads, unbounded())
+    }
 
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
         let message = ThreadPoolMessage::RunJob(Box::new(job));
 
         self.task_sender.send(message).expect("The thread pool has no thread.");
     }
-}
\ No newline at end of file
+
+    fn try_spawn<F>(&self, job: F) -> bool where F: FnOnce() + Send + 'static {
+        let message = ThreadPoolMessage::RunJob(Box::new(job));
+
+        self.task_sender.try_send(message).is_ok()
+    }
+}
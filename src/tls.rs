@@ -0,0 +1,124 @@
+//! 客户端与服务器之间的TLS加密
+
+use crate::error::{KvsError, Result};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+/// 服务器端TLS配置
+#[derive(Clone)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// 根据PEM格式的证书链与私钥创建服务器端配置
+    ///
+    /// 若给定`client_ca`，则要求客户端出示由其中的CA签发的证书（双向TLS）。
+    pub fn new(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(load_roots(client_ca)?),
+                    provider,
+                )
+                .build()
+                .map_err(|e| KvsError::StringError(format!("Invalid client CA: {}", e)))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// 在已接受的连接上建立TLS会话，握手在首次读写时进行
+    pub(crate) fn accept(&self, tcp: TcpStream) -> Result<StreamOwned<ServerConnection, TcpStream>> {
+        let conn = ServerConnection::new(Arc::clone(&self.config))?;
+        Ok(StreamOwned::new(conn, tcp))
+    }
+}
+
+/// 客户端TLS配置
+///
+/// 只信任给定的CA证书，而不使用系统的根证书。
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl ClientTls {
+    /// 根据PEM格式的CA证书创建客户端配置
+    ///
+    /// 若给定`identity`（证书链与私钥），则在服务器要求时出示该客户端证书。
+    pub fn new(ca_cert: &Path, identity: Option<(&Path, &Path)>) -> Result<Self> {
+        let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(ca_cert)?);
+        let config = match identity {
+            Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// 设置用于校验服务器证书的名称
+    ///
+    /// 默认使用所连接地址的IP。
+    pub fn with_server_name(mut self, name: &str) -> Result<Self> {
+        let name = ServerName::try_from(name.to_owned())
+            .map_err(|_| KvsError::StringError(format!("Invalid server name: {}", name)))?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    /// 在已建立的连接上发起TLS会话，握手在首次读写时进行
+    pub(crate) fn connect(&self, tcp: TcpStream, ip: IpAddr) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+        let server_name = self.server_name.clone().unwrap_or_else(|| ServerName::from(ip));
+        let conn = ClientConnection::new(Arc::clone(&self.config), server_name)?;
+        Ok(StreamOwned::new(conn, tcp))
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(KvsError::StringError(format!("No certificate found in {:?}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| KvsError::StringError(format!("No private key found in {:?}", path)))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
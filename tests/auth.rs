@@ -0,0 +1,250 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AuthConfig, KvStore, KvsClient, KvsClientBuilder, KvsError, KvsServer, Protocol, Result};
+use slog::{o, Discard, Logger};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+mod common;
+use common::{auth_config, Proxy};
+
+// alice may read everything, write under "app:" and run admin requests; bob may only read "public:".
+// Passwords are "alice-secret" and "bob-secret".
+const AUTH_CONFIG: &str = r#"{
+  "users": [
+    {
+      "name": "alice",
+      "password_hash": "pbkdf2-sha256$1000$5a1f0c3e9b7d42a6e8c1f3b5d7092a4c$8cbb64b6ff1c6ac234b71e4d2c875326a71bae2a282caff7fdfd1920efc096e6",
+      "admin": true,
+      "rules": [
+        { "prefix": "app:", "read": true, "write": true },
+        { "prefix": "", "read": true }
+      ]
+    },
+    {
+      "name": "bob",
+      "password_hash": "pbkdf2-sha256$1000$c3d9e17a4b2f6805a1e7c9d3b5f70248$d67a216b211abf822632f0641f0a2aaab2b7645bec6c78a4f2a432b7948c7e78",
+      "rules": [{ "prefix": "public:", "read": true }]
+    }
+  ]
+}"#;
+
+// Start a server requiring authentication and wait until it accepts connections.
+fn start_server(addr: SocketAddr, protocol: Protocol) -> TempDir {
+    common::start_server(addr, |server, dir| server.protocol(protocol).auth(auth_config(dir, AUTH_CONFIG)))
+}
+
+// Requests before authenticating are rejected
+#[test]
+fn unauthenticated_requests_denied() -> Result<()> {
+    let addr = "127.0.0.1:4050".parse().unwrap();
+    let _temp_dir = start_server(addr, Protocol::Kvs);
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.features().iter().any(|f| f == "auth"));
+
+    assert!(matches!(client.get("app:key".to_owned()), Err(KvsError::PermissionDenied)));
+    assert!(matches!(
+        client.set("app:key".to_owned(), "value".to_owned()),
+        Err(KvsError::PermissionDenied)
+    ));
+    assert!(matches!(client.stats(), Err(KvsError::PermissionDenied)));
+
+    // Any logged-in user may read the server stats
+    client.auth("bob".to_owned(), "bob-secret".to_owned())?;
+    assert_eq!(client.stats()?.engine.unwrap().engine, "kvs");
+    Ok(())
+}
+
+// Wrong credentials fail and leave the session unauthenticated
+#[test]
+fn wrong_password_rejected() -> Result<()> {
+    let addr = "127.0.0.1:4051".parse().unwrap();
+    let _temp_dir = start_server(addr, Protocol::Kvs);
+    let mut client = KvsClient::connect(addr)?;
+
+    assert!(matches!(
+        client.auth("alice".to_owned(), "bob-secret".to_owned()),
+        Err(KvsError::AuthFailed)
+    ));
+    assert!(matches!(
+        client.auth("mallory".to_owned(), "alice-secret".to_owned()),
+        Err(KvsError::AuthFailed)
+    ));
+    assert!(matches!(client.get("app:key".to_owned()), Err(KvsError::PermissionDenied)));
+    Ok(())
+}
+
+// Read and write permissions follow the user's prefix rules
+#[test]
+fn prefix_rules_enforced() -> Result<()> {
+    let addr = "127.0.0.1:4052".parse().unwrap();
+    let _temp_dir = start_server(addr, Protocol::Kvs);
+
+    let mut alice = KvsClient::connect(addr)?;
+    alice.auth("alice".to_owned(), "alice-secret".to_owned())?;
+    alice.set("app:key".to_owned(), "value".to_owned())?;
+    assert_eq!(alice.get("app:key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(alice.get("other".to_owned())?, None);
+    assert!(matches!(
+        alice.set("public:key".to_owned(), "value".to_owned()),
+        Err(KvsError::PermissionDenied)
+    ));
+    assert!(matches!(alice.remove("other".to_owned()), Err(KvsError::PermissionDenied)));
+
+    let mut bob = KvsClient::connect(addr)?;
+    bob.auth("bob".to_owned(), "bob-secret".to_owned())?;
+    assert_eq!(bob.get("public:key".to_owned())?, None);
+    assert!(matches!(bob.get("app:key".to_owned()), Err(KvsError::PermissionDenied)));
+    assert!(matches!(bob.remove("app:key".to_owned()), Err(KvsError::PermissionDenied)));
+
+    // The denied removal did not touch the key
+    assert_eq!(alice.get("app:key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Each key of a multi-key request is checked on its own
+#[test]
+fn multi_key_checked_per_key() -> Result<()> {
+    let addr = "127.0.0.1:4053".parse().unwrap();
+    let _temp_dir = start_server(addr, Protocol::Kvs);
+    let mut client = KvsClient::connect(addr)?;
+    client.auth("alice".to_owned(), "alice-secret".to_owned())?;
+
+    let results = client.mset(vec![
+        ("app:a".to_owned(), "1".to_owned()),
+        ("b".to_owned(), "2".to_owned()),
+    ])?;
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(KvsError::PermissionDenied)));
+
+    let values = client.mget(vec!["app:a".to_owned(), "b".to_owned()])?;
+    assert_eq!(values[0].as_ref().unwrap(), &Some("1".to_owned()));
+    assert_eq!(values[1].as_ref().unwrap(), &None);
+    Ok(())
+}
+
+// Admin requests require a user with the admin flag
+#[test]
+fn admin_requests_require_admin() -> Result<()> {
+    let addr = "127.0.0.1:4057".parse().unwrap();
+    let _temp_dir = start_server(addr, Protocol::Kvs);
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(client.compact(), Err(KvsError::PermissionDenied)));
+
+    client.auth("bob".to_owned(), "bob-secret".to_owned())?;
+    assert!(matches!(client.compact(), Err(KvsError::PermissionDenied)));
+    assert!(matches!(client.flush(), Err(KvsError::PermissionDenied)));
+    assert!(matches!(client.backup("/tmp/kvs-backup"), Err(KvsError::PermissionDenied)));
+
+    client.auth("alice".to_owned(), "alice-secret".to_owned())?;
+    client.compact()?;
+    client.flush()?;
+    Ok(())
+}
+
+fn resp_command(reader: &mut BufReader<TcpStream>, args: &[&str]) -> String {
+    let mut cmd = format!("*{}\r\n", args.len());
+    for arg in args {
+        cmd.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    reader.get_mut().write_all(cmd.as_bytes()).unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line
+}
+
+// The RESP protocol supports AUTH and enforces the same rules
+#[test]
+fn resp_auth() {
+    let addr = "127.0.0.1:4054".parse().unwrap();
+    let _temp_dir = start_server(addr, Protocol::Resp);
+    let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+
+    assert!(resp_command(&mut reader, &["GET", "app:key"]).starts_with("-NOPERM"));
+    assert!(resp_command(&mut reader, &["AUTH", "alice", "wrong"]).starts_with("-WRONGPASS"));
+    assert_eq!(resp_command(&mut reader, &["AUTH", "alice", "alice-secret"]), "+OK\r\n");
+    assert_eq!(resp_command(&mut reader, &["SET", "app:key", "value"]), "+OK\r\n");
+    assert!(resp_command(&mut reader, &["SET", "key", "value"]).starts_with("-NOPERM"));
+}
+
+// The HTTP gateway has no authentication and cannot be combined with it
+#[test]
+fn http_gateway_refused_with_auth() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("auth.json");
+    fs::write(&config_path, AUTH_CONFIG).unwrap();
+
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let logger = Arc::new(Logger::root(Discard, o!()));
+    let res = KvsServer::new(engine, pool)
+        .auth(AuthConfig::load(&config_path).unwrap())
+        .http_addr("127.0.0.1:4056".parse().unwrap())
+        .run("127.0.0.1:4055".parse().unwrap(), logger);
+    assert!(res.is_err());
+}
+
+// `kvs-server --hash-password` produces salted hashes the server accepts
+#[test]
+fn hashed_passwords() -> Result<()> {
+    let hash_password = |password: &str| {
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .arg("--hash-password")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        writeln!(child.stdin.take().unwrap(), "{}", password).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    };
+    let hash = hash_password("carol-secret");
+    assert!(hash.starts_with("pbkdf2-sha256$"));
+    // Every hash gets its own salt
+    assert_ne!(hash, hash_password("carol-secret"));
+
+    let config = format!(
+        r#"{{ "users": [{{ "name": "carol", "password_hash": "{}", "rules": [{{ "prefix": "", "read": true }}] }}] }}"#,
+        hash
+    );
+    let addr = "127.0.0.1:4058".parse().unwrap();
+    let temp_dir = common::start_server(addr, |server, dir| server.auth(auth_config(dir, &config)));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(client.auth("carol".to_owned(), "wrong".to_owned()), Err(KvsError::AuthFailed)));
+    assert!(matches!(client.auth("nobody".to_owned(), "carol-secret".to_owned()), Err(KvsError::AuthFailed)));
+    client.auth("carol".to_owned(), "carol-secret".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, None);
+
+    // Unsalted SHA-256 digests are no longer accepted
+    let legacy = r#"{ "users": [{ "name": "carol", "password_hash": "0c848abb03307b06cf70cd4e29c157dc81af5e94ab3eb1d0c59a120269572376" }] }"#;
+    let config_path = temp_dir.path().join("auth.json");
+    fs::write(&config_path, legacy).unwrap();
+    assert!(AuthConfig::load(&config_path).is_err());
+    Ok(())
+}
+
+// Re-authenticating after a reconnect does not use up the request ID meant for a snapshot
+#[test]
+fn snapshot_request_id_survives_reconnect() -> Result<()> {
+    let server_addr = "127.0.0.1:4215".parse().unwrap();
+    let _temp_dir = start_server(server_addr, Protocol::Kvs);
+    let proxy = Proxy::start("127.0.0.1:4216".parse().unwrap(), server_addr, || {});
+    let mut client = KvsClientBuilder::new("127.0.0.1:4216".parse().unwrap())
+        .auth("alice".to_owned(), "alice-secret".to_owned())
+        .build()?;
+    client.set("app:key".to_owned(), "value".to_owned())?;
+
+    proxy.drop_connections();
+    assert!(client.get("app:key".to_owned()).is_err());
+    client.set_request_id("my-snapshot");
+    client.snapshot(|_, _| Ok(()))?;
+    assert_eq!(client.last_request_id(), Some("my-snapshot"));
+    Ok(())
+}
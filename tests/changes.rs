@@ -0,0 +1,156 @@
+use kvs::{Change, Event, KvStore, KvsClient, KvsEngine, KvsError, Result, SledEngine};
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+fn set_change(seq: u64, key: &str, value: &str) -> Change {
+    Change {
+        seq,
+        event: Event::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        },
+    }
+}
+
+fn remove_change(seq: u64, key: &str) -> Change {
+    Change {
+        seq,
+        event: Event::Remove { key: key.to_owned() },
+    }
+}
+
+// History is read from the log and followed by changes made after subscribing
+#[test]
+fn history_then_live() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.remove("a".to_owned())?;
+
+    let mut changes = store.changes(2)?;
+    store.set("c".to_owned(), "3".to_owned())?;
+
+    let timeout = Duration::from_secs(1);
+    assert_eq!(changes.next_timeout(timeout)?, Some(set_change(2, "b", "2")));
+    assert_eq!(changes.next_timeout(timeout)?, Some(remove_change(3, "a")));
+    assert_eq!(changes.next_timeout(timeout)?, Some(set_change(4, "c", "3")));
+    assert_eq!(changes.next_timeout(Duration::from_millis(100))?, None);
+    Ok(())
+}
+
+// Sequence numbers keep increasing across restarts
+#[test]
+fn sequence_survives_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+    let changes: Vec<Change> = store.changes(0)?.take(3).collect::<Result<_>>()?;
+    assert_eq!(
+        changes,
+        vec![set_change(1, "a", "1"), set_change(2, "b", "2"), set_change(3, "c", "3")]
+    );
+    Ok(())
+}
+
+// Sequences dropped by compaction are reported, recent ones are still available
+#[test]
+fn compacted_sequence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+
+    let mut last = None;
+    for seq in 1..=10_000 {
+        store.set("key".to_owned(), value.clone())?;
+        if let Err(KvsError::SequenceCompacted) = store.changes(1) {
+            last = Some(seq);
+            break;
+        }
+    }
+    let last = last.expect("no compaction was triggered");
+    // The latest writes survive compaction even though they overwrite each other
+    let mut changes = store.changes(last - 1)?;
+    assert_eq!(changes.next().unwrap()?, set_change(last - 1, "key", &value));
+    assert_eq!(changes.next().unwrap()?, set_change(last, "key", &value));
+    store.set("key".to_owned(), "after".to_owned())?;
+    drop(store);
+
+    // The compaction point is persisted in the log
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.changes(1), Err(KvsError::SequenceCompacted)));
+    let mut changes = store.changes(last)?;
+    assert_eq!(changes.next().unwrap()?, set_change(last, "key", &value));
+    assert_eq!(changes.next().unwrap()?, set_change(last + 1, "key", "after"));
+    Ok(())
+}
+
+// An explicit compaction keeps the recent history, including overwrites and removals
+#[test]
+fn compaction_keeps_recent_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("a".to_owned(), "3".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.compact()?;
+
+    let expected = vec![
+        set_change(1, "a", "1"),
+        set_change(2, "b", "2"),
+        set_change(3, "a", "3"),
+        remove_change(4, "b"),
+    ];
+    let changes: Vec<Change> = store.changes(1)?.take(4).collect::<Result<_>>()?;
+    assert_eq!(changes, expected);
+    assert_eq!(store.get("a".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    drop(store);
+
+    // Replaying the compacted log gives the same data and history
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    let changes: Vec<Change> = store.changes(1)?.take(4).collect::<Result<_>>()?;
+    assert_eq!(changes, expected);
+    store.set("c".to_owned(), "5".to_owned())?;
+    assert_eq!(store.changes(5)?.next().unwrap()?, set_change(5, "c", "5"));
+    Ok(())
+}
+
+#[test]
+fn sled_unsupported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledEngine::new(sled::open(temp_dir.path())?);
+    assert!(matches!(engine.changes(0), Err(KvsError::Unsupported(_))));
+    Ok(())
+}
+
+// Changes are streamed over the network from the requested sequence
+#[test]
+fn client_changes() -> Result<()> {
+    let addr = "127.0.0.1:4130".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+
+    let mut writer = KvsClient::connect(addr)?;
+    writer.set("a".to_owned(), "1".to_owned())?;
+    writer.set("b".to_owned(), "2".to_owned())?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.features().iter().any(|f| f == "changes"));
+    let mut changes = client.changes(2)?;
+    writer.remove("b".to_owned())?;
+
+    assert_eq!(changes.next().unwrap()?, set_change(2, "b", "2"));
+    assert_eq!(changes.next().unwrap()?, remove_change(3, "b"));
+    Ok(())
+}
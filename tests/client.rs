@@ -0,0 +1,405 @@
+use kvs::{Codec, KvStore, KvsClient, KvsEngine, KvsError, PipelineResponse, Result};
+use slog::{o, Drain, Key, Logger, Never, OwnedKVList, Record, KV};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server, start_server_with};
+
+// Pipelined requests should be answered in the order they were queued
+#[test]
+fn pipeline_responses_in_order() -> Result<()> {
+    let addr = "127.0.0.1:4010".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+    let mut client = KvsClient::connect(addr)?;
+
+    let mut pipeline = client.pipeline();
+    pipeline
+        .set("key1".to_owned(), "value1".to_owned())
+        .get("key1".to_owned())
+        .set("key1".to_owned(), "value2".to_owned())
+        .get("key1".to_owned())
+        .remove("key1".to_owned())
+        .get("key1".to_owned())
+        .remove("key1".to_owned());
+    assert_eq!(pipeline.len(), 7);
+
+    let responses = pipeline.execute()?;
+    assert_eq!(responses.len(), 7);
+    let mut responses = responses.into_iter();
+    assert_eq!(responses.next().unwrap()?, PipelineResponse::Set);
+    assert_eq!(responses.next().unwrap()?, PipelineResponse::Get(Some("value1".to_owned())));
+    assert_eq!(responses.next().unwrap()?, PipelineResponse::Set);
+    assert_eq!(responses.next().unwrap()?, PipelineResponse::Get(Some("value2".to_owned())));
+    assert_eq!(responses.next().unwrap()?, PipelineResponse::Remove);
+    assert_eq!(responses.next().unwrap()?, PipelineResponse::Get(None));
+    assert!(matches!(responses.next().unwrap(), Err(KvsError::KeyNotFound)));
+
+    // The connection is still usable for ordinary requests afterwards
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// A pipeline much larger than the in-flight window should not deadlock
+#[test]
+fn pipeline_many_requests() -> Result<()> {
+    let addr = "127.0.0.1:4011".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+    let mut client = KvsClient::connect(addr)?;
+
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    for i in 0..1000 {
+        pipeline.get(format!("key{}", i));
+    }
+
+    let responses = pipeline.execute()?;
+    assert_eq!(responses.len(), 2000);
+    for (i, resp) in responses.into_iter().skip(1000).enumerate() {
+        assert_eq!(resp?, PipelineResponse::Get(Some(format!("value{}", i))));
+    }
+
+    Ok(())
+}
+
+// Multi-key requests should return one result per key, in request order
+#[test]
+fn multi_key_requests() -> Result<()> {
+    let addr = "127.0.0.1:4012".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+    let mut client = KvsClient::connect(addr)?;
+
+    let results = client.mset(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+    assert_eq!(results.len(), 2);
+    assert!(results.into_iter().all(|res| res.is_ok()));
+
+    let values = client.mget(vec!["key2".to_owned(), "key3".to_owned(), "key1".to_owned()])?;
+    let values: Vec<Option<String>> = values.into_iter().collect::<Result<_>>()?;
+    assert_eq!(values, vec![Some("value2".to_owned()), None, Some("value1".to_owned())]);
+
+    let results = client.mremove(vec!["key1".to_owned(), "key3".to_owned()])?;
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(KvsError::KeyNotFound)));
+
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Server-side errors should be mapped back to the matching `KvsError` variant
+#[test]
+fn typed_server_errors() -> Result<()> {
+    let addr = "127.0.0.1:4013".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+    let mut client = KvsClient::connect(addr)?;
+
+    assert!(matches!(client.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert!(matches!(client.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+
+    Ok(())
+}
+
+// Write a length-prefixed frame by hand
+fn write_raw_frame(stream: &mut TcpStream, payload: &[u8]) {
+    stream.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+    stream.write_all(payload).unwrap();
+}
+
+// Read a length-prefixed frame by hand
+fn read_raw_frame(stream: &mut TcpStream) -> String {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    String::from_utf8(payload).unwrap()
+}
+
+// The JSON codec should be negotiated on request and behave like the binary one
+#[test]
+fn json_codec() -> Result<()> {
+    let addr = "127.0.0.1:4014".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+
+    let mut client = KvsClient::connect_with_codec(addr, Codec::Json)?;
+    assert_eq!(client.codec(), Codec::Json);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.codec(), Codec::Bincode);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.features().iter().any(|f| f == "multi-key"));
+
+    Ok(())
+}
+
+// A malformed request should be answered with an error without breaking the connection
+#[test]
+fn resync_after_malformed_request() {
+    let addr = "127.0.0.1:4015".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_raw_frame(
+        &mut stream,
+        br#"{"min_version":2,"max_version":2,"codecs":["Json"],"features":[]}"#,
+    );
+    assert!(read_raw_frame(&mut stream).contains(r#""codec":"Json""#));
+
+    write_raw_frame(&mut stream, b"{not json");
+    assert!(read_raw_frame(&mut stream).contains(r#""code":"Serde""#));
+
+    write_raw_frame(&mut stream, br#"{"Set":{"key":"key1","value":"value1"}}"#);
+    assert!(read_raw_frame(&mut stream).contains(r#""Ok":"Done""#));
+    write_raw_frame(&mut stream, br#"{"Get":{"key":"key1"}}"#);
+    assert!(read_raw_frame(&mut stream).contains(r#""Value":"value1""#));
+}
+
+// A repeated idempotency token is only accepted for the same key and value
+#[test]
+fn idempotency_token_reuse() {
+    let addr = "127.0.0.1:4195".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_raw_frame(
+        &mut stream,
+        br#"{"min_version":2,"max_version":2,"codecs":["Json"],"features":["idempotency"]}"#,
+    );
+    assert!(read_raw_frame(&mut stream).contains(r#""codec":"Json""#));
+
+    let set_idempotent = br#"{"SetIdempotent":{"key":"key1","value":"value1","token":"token1"}}"#;
+    write_raw_frame(&mut stream, set_idempotent);
+    assert!(read_raw_frame(&mut stream).contains(r#""Ok":"Done""#));
+    write_raw_frame(&mut stream, br#"{"Set":{"key":"key1","value":"other"}}"#);
+    assert!(read_raw_frame(&mut stream).contains(r#""Ok":"Done""#));
+
+    // A retry is acknowledged without writing again
+    write_raw_frame(&mut stream, set_idempotent);
+    assert!(read_raw_frame(&mut stream).contains(r#""Ok":"Done""#));
+    write_raw_frame(&mut stream, br#"{"Get":{"key":"key1"}}"#);
+    assert!(read_raw_frame(&mut stream).contains(r#""Value":"other""#));
+
+    write_raw_frame(&mut stream, br#"{"SetIdempotent":{"key":"key1","value":"value2","token":"token1"}}"#);
+    assert!(read_raw_frame(&mut stream).contains(r#""code":"IdempotencyConflict""#));
+    write_raw_frame(&mut stream, br#"{"Get":{"key":"key1"}}"#);
+    assert!(read_raw_frame(&mut stream).contains(r#""Value":"other""#));
+}
+
+// The server should close the connection when no protocol version is shared
+#[test]
+fn reject_unsupported_version() {
+    let addr = "127.0.0.1:4016".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_raw_frame(
+        &mut stream,
+        br#"{"min_version":100,"max_version":101,"codecs":["Json"],"features":[]}"#,
+    );
+    assert!(read_raw_frame(&mut stream).contains(r#""version":2"#));
+
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+}
+
+// Stats describe the server, its connections and the engine
+#[test]
+fn server_stats() -> Result<()> {
+    let addr = "127.0.0.1:4017".parse().unwrap();
+    let temp_dir = start_server(addr, |server, _| server);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.set("key2".to_owned(), "value".to_owned())?;
+
+    let stats = client.stats()?;
+    assert_eq!(stats.version, env!("CARGO_PKG_VERSION"));
+    // The readiness probe in start_server may not have been closed on the server side yet
+    assert!(stats.connections >= 1);
+    assert_eq!(stats.queued_connections, 0);
+    assert_eq!(stats.accepted_connections, 2);
+    assert_eq!(stats.rejected_connections, 0);
+
+    let engine = stats.engine.unwrap();
+    assert_eq!(engine.engine, "kvs");
+    assert_eq!(engine.data_dir.as_deref(), Some(temp_dir.path()));
+    assert_eq!(engine.keys, 2);
+    assert_eq!(engine.generations, 1);
+    assert!(engine.disk_bytes > engine.live_bytes);
+    assert_eq!(engine.disk_bytes, engine.live_bytes + engine.uncompacted_bytes);
+    assert_eq!(engine.compactions, 0);
+
+    // Stats work with the JSON codec as well
+    let mut json = KvsClient::connect_with_codec(addr, Codec::Json)?;
+    assert_eq!(json.stats()?.engine.unwrap().keys, 2);
+    Ok(())
+}
+
+// Admin requests compact, sync and back up the engine on demand
+#[test]
+fn admin_requests() -> Result<()> {
+    let addr = "127.0.0.1:4018".parse().unwrap();
+    let backup_root = TempDir::new().unwrap();
+    let root = backup_root.path().to_owned();
+    let _temp_dir = start_server(addr, move |server, _| server.backup_dir(root));
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..10 {
+        client.set("key".to_owned(), i.to_string())?;
+    }
+    client.set("other".to_owned(), "value".to_owned())?;
+    let uncompacted = client.stats()?.engine.unwrap().uncompacted_bytes;
+    assert!(uncompacted > 0);
+
+    // Recent overwrites are kept for change streams and reclaimed by a later compaction
+    client.compact()?;
+    let engine = client.stats()?.engine.unwrap();
+    assert_eq!(engine.compactions, 1);
+    assert!(engine.uncompacted_bytes <= uncompacted);
+    assert_eq!(client.get("key".to_owned())?, Some("9".to_owned()));
+    client.flush()?;
+
+    // Destinations are relative to the backup directory and may not leave it
+    let outside = TempDir::new().unwrap();
+    assert!(client.backup(outside.path().join("backup")).is_err());
+    assert!(client.backup("../backup").is_err());
+    assert!(!backup_root.path().parent().unwrap().join("backup").exists());
+
+    client.backup("backup")?;
+    client.set("key".to_owned(), "after backup".to_owned())?;
+    // The destination is no longer empty
+    assert!(client.backup("backup").is_err());
+
+    let backup = KvStore::open(backup_root.path().join("backup"))?;
+    assert_eq!(backup.get("key".to_owned())?, Some("9".to_owned()));
+    assert_eq!(backup.get("other".to_owned())?, Some("value".to_owned()));
+
+    // The slow log is disabled by default
+    assert!(client.slow_log()?.is_empty());
+    Ok(())
+}
+
+// Without a backup directory the server refuses every backup
+#[test]
+fn backup_disabled_by_default() -> Result<()> {
+    let addr = "127.0.0.1:4214".parse().unwrap();
+    let temp_dir = start_server(addr, |server, _| server);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    match client.backup("backup") {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("disabled"), "{}", msg),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(!temp_dir.path().join("backup").exists());
+    Ok(())
+}
+
+// With a zero threshold every request is slow, and only the latest entries are kept
+#[test]
+fn slow_log() -> Result<()> {
+    let addr = "127.0.0.1:4019".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.slow_log(Duration::ZERO, 3));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.set("x".repeat(100), "value".to_owned())?;
+    client.mget(vec!["key1".to_owned(), "key2".to_owned(), "key3".to_owned()])?;
+    client.snapshot(|_, _| Ok(()))?;
+
+    let entries = client.slow_log()?;
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].command, "snapshot");
+    assert_eq!(entries[0].key, None);
+    assert_eq!(entries[1].command, "mget");
+    assert_eq!(entries[1].key.as_deref(), Some("key1 (+2 more)"));
+    assert_eq!(entries[2].command, "set");
+    assert_eq!(entries[2].key, Some(format!("{}...", "x".repeat(64))));
+    for entry in &entries {
+        assert_eq!(entry.peer, entries[0].peer);
+        assert!(entry.queue_wait + entry.engine + entry.write_back <= entry.total);
+    }
+    Ok(())
+}
+
+// Collects the `request_id` of every server log record
+struct RequestIds(Arc<Mutex<Vec<String>>>);
+
+impl Drain for RequestIds {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> std::result::Result<(), Never> {
+        struct Collect<'a>(&'a mut Vec<String>);
+        impl slog::Serializer for Collect<'_> {
+            fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+                if key == "request_id" {
+                    self.0.push(val.to_string());
+                }
+                Ok(())
+            }
+        }
+        let mut ids = self.0.lock().unwrap();
+        values.serialize(record, &mut Collect(&mut ids)).unwrap();
+        Ok(())
+    }
+}
+
+// Request IDs are generated by the client, logged by the server and echoed back in the response
+#[test]
+fn request_ids() -> Result<()> {
+    let addr = "127.0.0.1:4194".parse().unwrap();
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    let logger = Arc::new(Logger::root(RequestIds(logged.clone()).fuse(), o!()));
+    let _temp_dir = start_server_with(addr, 4, logger, |server, _| server);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.features().iter().any(|f| f == "request-id"));
+    assert_eq!(client.last_request_id(), None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let first = client.last_request_id().expect("request id echoed").to_owned();
+    client.get("key1".to_owned())?;
+    let second = client.last_request_id().expect("request id echoed").to_owned();
+    assert_ne!(first, second);
+
+    // Errors carry the request ID too
+    client.set_request_id("my-request");
+    assert!(matches!(client.remove("missing".to_owned()), Err(KvsError::KeyNotFound)));
+    assert_eq!(client.last_request_id(), Some("my-request"));
+
+    let mut pipeline = client.pipeline();
+    pipeline.get("key1".to_owned()).set("key2".to_owned(), "value2".to_owned());
+    assert_eq!(pipeline.execute()?.len(), 2);
+    let last = client.last_request_id().expect("request id echoed").to_owned();
+    assert!(![&first, &second, "my-request"].contains(&last.as_str()));
+
+    // A snapshot spans several frames, the last one echoes the ID
+    client.set_request_id("my-snapshot");
+    client.snapshot(|_, _| Ok(()))?;
+    assert_eq!(client.last_request_id(), Some("my-snapshot"));
+
+    // The traced envelope decodes the same under every codec
+    let mut client = KvsClient::connect_with_codec(addr, Codec::Json)?;
+    client.set_request_id("my-json-request");
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.last_request_id(), Some("my-json-request"));
+
+    let logged = logged.lock().unwrap();
+    for id in [&first, &second, "my-request", &last, "my-snapshot", "my-json-request"] {
+        assert!(logged.iter().any(|logged| logged == id), "{} not logged", id);
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use kvs::{KvsClientBuilder, KvsClientPool, KvsError, Result};
+use std::thread;
+use std::time::Duration;
+
+mod common;
+use common::{Proxy, discard_logger, start_server_with};
+
+// A single pool can be shared by many threads without exceeding its size
+#[test]
+fn shared_across_threads() -> Result<()> {
+    let addr = "127.0.0.1:4080".parse().unwrap();
+    let _temp_dir = start_server_with(addr, 16, discard_logger(), |server, _| server);
+    let pool = KvsClientPool::builder(KvsClientBuilder::new(addr))
+        .max_connections(4)
+        .build()?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let mut client = pool.get().unwrap();
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), i.to_string()).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(i.to_string()));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert!(pool.connections() <= 4);
+    assert_eq!(pool.idle_connections(), pool.connections());
+    Ok(())
+}
+
+// Checkout fails with a distinct error once every connection is in use for too long
+#[test]
+fn checkout_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4081".parse().unwrap();
+    let _temp_dir = start_server_with(addr, 16, discard_logger(), |server, _| server);
+    let pool = KvsClientPool::builder(KvsClientBuilder::new(addr))
+        .max_connections(1)
+        .checkout_timeout(Duration::from_millis(100))
+        .build()?;
+
+    let held = pool.get()?;
+    assert!(matches!(pool.get(), Err(KvsError::PoolTimeout)));
+    drop(held);
+    pool.get()?.ping()?;
+    assert_eq!(pool.connections(), 1);
+    Ok(())
+}
+
+// Idle connections above the minimum are closed after the idle timeout
+#[test]
+fn idle_connections_evicted() -> Result<()> {
+    let addr = "127.0.0.1:4082".parse().unwrap();
+    let _temp_dir = start_server_with(addr, 16, discard_logger(), |server, _| server);
+    let pool = KvsClientPool::builder(KvsClientBuilder::new(addr))
+        .min_connections(1)
+        .max_connections(4)
+        .idle_timeout(Some(Duration::from_millis(100)))
+        .build()?;
+    assert_eq!(pool.connections(), 1);
+
+    let clients: Vec<_> = (0..3).map(|_| pool.get()).collect::<Result<_>>()?;
+    assert_eq!(pool.connections(), 3);
+    drop(clients);
+    assert_eq!(pool.idle_connections(), 3);
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(pool.connections(), 1);
+    assert_eq!(pool.idle_connections(), 1);
+    Ok(())
+}
+
+// A connection that died while idle fails the health check and is replaced
+#[test]
+fn dead_connection_replaced_on_checkout() -> Result<()> {
+    let server_addr = "127.0.0.1:4083".parse().unwrap();
+    let _temp_dir = start_server_with(server_addr, 16, discard_logger(), |server, _| server);
+    let proxy = Proxy::start("127.0.0.1:4084".parse().unwrap(), server_addr, || {});
+    let pool = KvsClientPool::builder(KvsClientBuilder::new("127.0.0.1:4084".parse().unwrap()))
+        .min_connections(2)
+        .build()?;
+
+    proxy.drop_connections();
+    let mut client = pool.get()?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    drop(client);
+    assert_eq!(pool.connections(), 1);
+    Ok(())
+}
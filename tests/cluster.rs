@@ -0,0 +1,294 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, KvsClientBuilder, Result};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// A local cluster of `kvs-server` processes, killed when dropped
+struct Cluster {
+    nodes: Vec<ClusterNode>,
+}
+
+struct ClusterNode {
+    dir: TempDir,
+    addr: SocketAddr,
+    args: Vec<String>,
+    child: Option<Child>,
+}
+
+impl Cluster {
+    fn start(size: u16, base_port: u16) -> Cluster {
+        Cluster::with_secrets(&vec!["cluster secret"; size as usize], base_port)
+    }
+
+    // Node `i` serves clients on `base_port + 2i` and talks to its peers on the next port
+    fn with_secrets(secrets: &[&str], base_port: u16) -> Cluster {
+        let size = secrets.len() as u16;
+        let addr = |i: u16| format!("127.0.0.1:{}", base_port + 2 * i);
+        let raft_addr = |i: u16| format!("127.0.0.1:{}", base_port + 2 * i + 1);
+        let mut nodes = Vec::new();
+        for i in 0..size {
+            let dir = TempDir::new().expect("unable to create temporary working directory");
+            std::fs::write(dir.path().join("secret"), secrets[i as usize]).unwrap();
+            let mut args = vec!["--addr".to_owned(), addr(i), "--node-id".to_owned(), (i + 1).to_string()];
+            args.extend(["--cluster-secret-file".to_owned(), "secret".to_owned()]);
+            for peer in 0..size {
+                args.push("--peer".to_owned());
+                args.push(format!("{}={}/{}", peer + 1, addr(peer), raft_addr(peer)));
+            }
+            nodes.push(ClusterNode {
+                dir,
+                addr: addr(i).parse().unwrap(),
+                args,
+                child: None,
+            });
+        }
+        let mut cluster = Cluster { nodes };
+        for i in 0..cluster.nodes.len() {
+            cluster.restart(i);
+        }
+        cluster
+    }
+
+    fn addrs(&self) -> Vec<SocketAddr> {
+        self.nodes.iter().map(|node| node.addr).collect()
+    }
+
+    fn index_of(&self, addr: SocketAddr) -> usize {
+        self.nodes.iter().position(|node| node.addr == addr).unwrap()
+    }
+
+    // Start the node again on its existing data directory and wait until it accepts connections
+    fn restart(&mut self, i: usize) {
+        let node = &mut self.nodes[i];
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&node.args)
+            .current_dir(&node.dir)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        node.child = Some(child);
+        for _ in 0..50 {
+            if TcpStream::connect(node.addr).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("node did not start listening on {}", node.addr);
+    }
+
+    fn kill(&mut self, i: usize) {
+        if let Some(mut child) = self.nodes[i].child.take() {
+            child.kill().expect("node exited before killed");
+            child.wait().unwrap();
+        }
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for i in 0..self.nodes.len() {
+            self.kill(i);
+        }
+    }
+}
+
+fn cluster_client(cluster: &Cluster) -> Result<KvsClient> {
+    KvsClientBuilder::cluster(cluster.addrs())
+        .connect_timeout(Duration::from_millis(500))
+        .read_timeout(Duration::from_secs(10))
+        .retries(5)
+        .build()
+}
+
+// Requests sent to any node are redirected to the same leader
+#[test]
+fn followers_redirect_to_leader() -> Result<()> {
+    let cluster = Cluster::start(3, 4150);
+    let mut leaders = Vec::new();
+    for (i, addr) in cluster.addrs().into_iter().enumerate() {
+        let mut client = KvsClient::connect(addr)?;
+        client.set(format!("key{}", i), i.to_string())?;
+        leaders.push(client.server_addr());
+    }
+    assert!(leaders.iter().all(|leader| *leader == leaders[0]));
+
+    let mut client = cluster_client(&cluster)?;
+    for i in 0..3 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+    Ok(())
+}
+
+// Committed writes survive leader failures, and a restarted node catches up from its log
+#[test]
+fn writes_survive_leader_failure() -> Result<()> {
+    let mut cluster = Cluster::start(3, 4156);
+    let mut client = cluster_client(&cluster)?;
+    client.set("a".to_owned(), "1".to_owned())?;
+    client.remove("a".to_owned())?;
+    client.set("a".to_owned(), "2".to_owned())?;
+
+    let first_leader = cluster.index_of(client.server_addr().unwrap());
+    cluster.kill(first_leader);
+    client.set("b".to_owned(), "3".to_owned())?;
+    assert_eq!(client.get("a".to_owned())?, Some("2".to_owned()));
+    let second_leader = cluster.index_of(client.server_addr().unwrap());
+    assert_ne!(first_leader, second_leader);
+
+    // The old leader has to catch up before the cluster survives losing the new one
+    cluster.restart(first_leader);
+    client.set("c".to_owned(), "4".to_owned())?;
+    thread::sleep(Duration::from_secs(1));
+    cluster.kill(second_leader);
+    assert_eq!(client.get("b".to_owned())?, Some("3".to_owned()));
+    assert_eq!(client.get("c".to_owned())?, Some("4".to_owned()));
+    assert_eq!(client.get("a".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+// Without a majority writes are refused until enough nodes come back
+#[test]
+fn minority_refuses_writes() -> Result<()> {
+    let mut cluster = Cluster::start(3, 4162);
+    let mut client = cluster_client(&cluster)?;
+    client.set("key".to_owned(), "before".to_owned())?;
+
+    let leader = cluster.index_of(client.server_addr().unwrap());
+    let follower = (leader + 1) % 3;
+    cluster.kill(leader);
+    cluster.kill(follower);
+    let mut client = KvsClientBuilder::cluster(cluster.addrs())
+        .connect_timeout(Duration::from_millis(500))
+        .read_timeout(Duration::from_secs(10))
+        .build()?;
+    assert!(client.set("key".to_owned(), "lost".to_owned()).is_err());
+
+    cluster.restart(follower);
+    let mut client = cluster_client(&cluster)?;
+    client.set("key".to_owned(), "after".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("after".to_owned()));
+    Ok(())
+}
+
+// Nodes with different secrets cannot form a cluster, and unauthenticated peers are dropped
+#[test]
+fn peers_need_cluster_secret() -> Result<()> {
+    let cluster = Cluster::with_secrets(&["cluster secret", "other secret"], 4210);
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvsClientBuilder::cluster(cluster.addrs())
+        .connect_timeout(Duration::from_millis(500))
+        .read_timeout(Duration::from_secs(10))
+        .build()?;
+    assert!(client.set("key".to_owned(), "value".to_owned()).is_err());
+
+    // A connection that does not complete the handshake is closed
+    let mut stream = TcpStream::connect("127.0.0.1:4211")?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&[0, 0, 0, 4, 1, 2, 3, 4])?;
+    let mut buf = [0; 64];
+    assert!(!matches!(stream.read(&mut buf), Ok(n) if n > 0));
+    Ok(())
+}
+
+// Every node records how far it has applied the log, so a restart does not replay the whole history
+#[test]
+fn applied_index_persisted() -> Result<()> {
+    let mut cluster = Cluster::start(3, 4200);
+    let mut client = cluster_client(&cluster)?;
+    for i in 0..3 {
+        client.set(format!("key{}", i), i.to_string())?;
+    }
+    // Followers learn about the commit with the next heartbeat
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..3 {
+        cluster.kill(i);
+        let state = std::fs::read_to_string(cluster.nodes[i].dir.path().join("raft").join("raft.state"))?;
+        let state: serde_json::Value = serde_json::from_str(&state)?;
+        // The leader's no-op entry followed by the three writes
+        assert!(state["applied"].as_u64().unwrap() >= 4, "node {}: {}", i, state);
+    }
+
+    for i in 0..3 {
+        cluster.restart(i);
+    }
+    let mut client = cluster_client(&cluster)?;
+    for i in 0..3 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+    Ok(())
+}
+
+// Write a raft log for a single-node cluster and start the node on it
+fn start_with_raft_log(dir: &TempDir, log: &str, port: u16) -> Command {
+    let raft_dir = dir.path().join("raft");
+    std::fs::create_dir_all(&raft_dir).unwrap();
+    std::fs::write(raft_dir.join("raft.log"), log).unwrap();
+    std::fs::write(dir.path().join("secret"), "cluster secret").unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args([
+        "--addr",
+        &format!("127.0.0.1:{}", port),
+        "--node-id",
+        "1",
+        "--cluster-secret-file",
+        "secret",
+        "--peer",
+        &format!("1=127.0.0.1:{}/127.0.0.1:{}", port, port + 1),
+    ])
+    .current_dir(dir)
+    .stderr(Stdio::null());
+    cmd
+}
+
+const SET_ENTRY: &str = r#"{"term":1,"command":{"Set":{"key":"key","value":"value"}}}"#;
+
+// An entry cut short by a crash is dropped, the entries before it are kept
+#[test]
+fn torn_last_entry_truncated() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = format!("{}\n{{\"term\":1,\"comm", SET_ENTRY);
+    let mut child = start_with_raft_log(&dir, &log, 4206).spawn()?;
+    let addr = "127.0.0.1:4206".parse().unwrap();
+    for _ in 0..50 {
+        if TcpStream::connect(addr).is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let mut client = KvsClientBuilder::cluster(vec![addr]).retries(50).build()?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}
+
+// A corrupt entry in the middle of the log is an error, not a reason to drop everything after it
+#[test]
+fn corrupt_entry_refused() {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = format!("{}\nnot an entry\n{}\n", SET_ENTRY, SET_ENTRY);
+    let mut child = start_with_raft_log(&dir, &log, 4208).spawn().unwrap();
+    let mut status = None;
+    for _ in 0..50 {
+        status = child.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    if status.is_none() {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+    assert!(matches!(status, Some(status) if !status.success()), "node started on a corrupt log");
+    // The log is left untouched for inspection
+    let kept = std::fs::read_to_string(dir.path().join("raft").join("raft.log")).unwrap();
+    assert_eq!(kept, log);
+}
@@ -0,0 +1,142 @@
+//! Helpers shared by the integration tests.
+
+// Each test binary uses only some of the helpers
+#![allow(dead_code)]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AuthConfig, KvStore, KvsEngine, KvsServer};
+use slog::{o, Discard, Logger};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// The server most tests run: a `KvStore` engine on a shared queue thread pool.
+pub type TestServer = KvsServer<KvStore, SharedQueueThreadPool>;
+
+// Start a `TestServer` with 4 threads in a new temporary directory, configured by `configure`,
+// in the background and wait until it accepts connections.
+// `configure` also gets the directory, e.g. to write an auth config next to the store.
+pub fn start_server<F>(addr: SocketAddr, configure: F) -> TempDir
+where
+    F: FnOnce(TestServer, &TempDir) -> TestServer,
+{
+    start_server_with(addr, 4, discard_logger(), configure)
+}
+
+// Like `start_server`, with a custom thread count and logger.
+pub fn start_server_with<F>(addr: SocketAddr, threads: u32, logger: Arc<Logger>, configure: F) -> TempDir
+where
+    F: FnOnce(TestServer, &TempDir) -> TestServer,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(threads).unwrap();
+    let server = configure(KvsServer::new(engine, pool), &temp_dir);
+    run_server(addr, server, logger);
+    temp_dir
+}
+
+// Run an already built server in the background and wait until it accepts connections.
+pub fn run_server<E, P>(addr: SocketAddr, server: KvsServer<E, P>, logger: Arc<Logger>)
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + 'static,
+{
+    thread::spawn(move || server.run(addr, logger));
+    wait_for_listener(addr);
+}
+
+// Wait until something accepts connections on `addr`.
+pub fn wait_for_listener(addr: SocketAddr) {
+    for _ in 0..50 {
+        if TcpStream::connect(addr).is_ok() {
+            // Let the server notice the probe connection closing, so it does not count against limits
+            thread::sleep(Duration::from_millis(100));
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("server did not start listening on {}", addr);
+}
+
+pub fn discard_logger() -> Arc<Logger> {
+    Arc::new(Logger::root(Discard, o!()))
+}
+
+// Write `json` as an auth config into `dir` and load it.
+pub fn auth_config(dir: &TempDir, json: &str) -> AuthConfig {
+    let path = dir.path().join("auth.json");
+    fs::write(&path, json).unwrap();
+    AuthConfig::load(&path).unwrap()
+}
+
+// A TCP proxy in front of the server that can break its connections on demand.
+pub struct Proxy {
+    conns: Arc<Mutex<Vec<TcpStream>>>,
+    // When set, the next response from the server is swallowed and the connection is closed.
+    pub cut_next_response: Arc<AtomicBool>,
+}
+
+impl Proxy {
+    pub fn start<F: Fn() + Send + Sync + 'static>(listen: SocketAddr, upstream: SocketAddr, on_cut: F) -> Proxy {
+        let listener = TcpListener::bind(listen).unwrap();
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let cut_next_response = Arc::new(AtomicBool::new(false));
+        let on_cut = Arc::new(on_cut);
+
+        let proxy = Proxy {
+            conns: conns.clone(),
+            cut_next_response: cut_next_response.clone(),
+        };
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let server = TcpStream::connect(upstream).unwrap();
+                conns.lock().unwrap().push(client.try_clone().unwrap());
+                conns.lock().unwrap().push(server.try_clone().unwrap());
+
+                let (mut from, mut to) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+                thread::spawn(move || {
+                    let _ = std::io::copy(&mut from, &mut to);
+                    let _ = to.shutdown(Shutdown::Both);
+                });
+
+                let (mut from, mut to) = (server, client);
+                let cut = cut_next_response.clone();
+                let on_cut = on_cut.clone();
+                thread::spawn(move || {
+                    let mut buf = [0; 4096];
+                    while let Ok(n) = from.read(&mut buf) {
+                        if n == 0 {
+                            break;
+                        }
+                        if cut.swap(false, Ordering::SeqCst) {
+                            on_cut();
+                            break;
+                        }
+                        if to.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                    let _ = to.shutdown(Shutdown::Both);
+                    let _ = from.shutdown(Shutdown::Both);
+                });
+            }
+        });
+        proxy
+    }
+
+    // Close every connection currently going through the proxy.
+    pub fn drop_connections(&self) {
+        for conn in self.conns.lock().unwrap().drain(..) {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+        // Give the client side a moment to observe the closed socket
+        thread::sleep(Duration::from_millis(50));
+    }
+}
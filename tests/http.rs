@@ -0,0 +1,89 @@
+use kvs::KvsClient;
+use serde_json::{json, Value};
+
+mod common;
+use common::start_server;
+
+// Return the status code of a request, whether or not it succeeded
+fn status(res: Result<ureq::Response, ureq::Error>) -> u16 {
+    match res {
+        Ok(resp) => resp.status(),
+        Err(ureq::Error::Status(code, _)) => code,
+        Err(e) => panic!("request failed: {}", e),
+    }
+}
+
+#[test]
+fn http_key_operations() {
+    let addr = "127.0.0.1:4030".parse().unwrap();
+    let http_addr = "127.0.0.1:4031".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.http_addr(http_addr));
+    let url = |path: &str| format!("http://{}{}", http_addr, path);
+
+    let put = ureq::put(&url("/keys/key1")).send_json(json!({ "value": "value1" }));
+    assert_eq!(status(put), 204);
+
+    let body: Value = ureq::get(&url("/keys/key1")).call().unwrap().into_json().unwrap();
+    assert_eq!(body, json!({ "key": "key1", "value": "value1" }));
+
+    // Keys are percent-decoded from the path
+    let put = ureq::put(&url("/keys/a%20b%2Fc")).send_json(json!({ "value": "value2" }));
+    assert_eq!(status(put), 204);
+    let body: Value = ureq::get(&url("/keys/a%20b%2Fc")).call().unwrap().into_json().unwrap();
+    assert_eq!(body["key"], "a b/c");
+
+    assert_eq!(status(ureq::delete(&url("/keys/key1")).call()), 204);
+    assert_eq!(status(ureq::get(&url("/keys/key1")).call()), 404);
+    assert_eq!(status(ureq::delete(&url("/keys/key1")).call()), 404);
+}
+
+#[test]
+fn http_list_keys() {
+    let addr = "127.0.0.1:4032".parse().unwrap();
+    let http_addr = "127.0.0.1:4033".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.http_addr(http_addr));
+    let url = |path: &str| format!("http://{}{}", http_addr, path);
+
+    for key in &["user1", "user2", "order1"] {
+        let put = ureq::put(&url(&format!("/keys/{}", key))).send_json(json!({ "value": "v" }));
+        assert_eq!(status(put), 204);
+    }
+
+    let body: Value = ureq::get(&url("/keys?prefix=user")).call().unwrap().into_json().unwrap();
+    assert_eq!(body, json!({ "keys": ["user1", "user2"] }));
+    let body: Value = ureq::get(&url("/keys")).call().unwrap().into_json().unwrap();
+    assert_eq!(body, json!({ "keys": ["order1", "user1", "user2"] }));
+}
+
+// The HTTP gateway and the TCP protocol should share the same engine
+#[test]
+fn http_shares_engine_with_tcp() -> kvs::Result<()> {
+    let addr = "127.0.0.1:4034".parse().unwrap();
+    let http_addr = "127.0.0.1:4035".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.http_addr(http_addr));
+    let url = |path: &str| format!("http://{}{}", http_addr, path);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let body: Value = ureq::get(&url("/keys/key1")).call().unwrap().into_json().unwrap();
+    assert_eq!(body["value"], "value1");
+
+    let put = ureq::put(&url("/keys/key2")).send_json(json!({ "value": "value2" }));
+    assert_eq!(status(put), 204);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn http_bad_requests() {
+    let addr = "127.0.0.1:4036".parse().unwrap();
+    let http_addr = "127.0.0.1:4037".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.http_addr(http_addr));
+    let url = |path: &str| format!("http://{}{}", http_addr, path);
+
+    assert_eq!(status(ureq::put(&url("/keys/key1")).send_string("not json")), 400);
+    assert_eq!(status(ureq::get(&url("/keys/%zz")).call()), 400);
+    assert_eq!(status(ureq::post(&url("/keys/key1")).call()), 405);
+    assert_eq!(status(ureq::get(&url("/other")).call()), 404);
+}
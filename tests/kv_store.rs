@@ -1,6 +1,7 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvsEngine, KvsError, Result, SledEngine};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +210,144 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should list keys with the given prefix in lexicographic order
+#[test]
+fn list_keys_with_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("b1".to_owned(), "value".to_owned())?;
+    store.set("a2".to_owned(), "value".to_owned())?;
+    store.set("a1".to_owned(), "value".to_owned())?;
+    store.set("ab".to_owned(), "value".to_owned())?;
+    store.remove("a2".to_owned())?;
+
+    assert_eq!(store.keys("a")?, vec!["a1".to_owned(), "ab".to_owned()]);
+    assert_eq!(store.keys("")?.len(), 3);
+    assert!(store.keys("c")?.is_empty());
+
+    Ok(())
+}
+
+fn log_files(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+// A read-only store sees what another instance writes, and never writes itself
+#[test]
+fn read_only_follows_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    let files = log_files(temp_dir.path());
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(log_files(temp_dir.path()), files);
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(matches!(reader.set("key1".to_owned(), "other".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+
+    let mut watcher = reader.watch("key")?;
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    writer.remove("key1".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(reader.keys("")?, vec!["key2".to_owned()]);
+    assert_eq!(watcher.next().unwrap()?.key(), "key2");
+    assert_eq!(watcher.next().unwrap()?.key(), "key1");
+
+    // New records are picked up in the background without an explicit refresh
+    writer.set("key3".to_owned(), "value3".to_owned())?;
+    for _ in 0..50 {
+        if reader.get("key3".to_owned())?.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // A restarted writer switches to a new log file
+    drop(writer);
+    let writer = KvStore::open(temp_dir.path())?;
+    writer.set("key4".to_owned(), "value4".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A read-only store reloads the compacted log after the writer compacts
+#[test]
+fn read_only_follows_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let writer = KvStore::open(temp_dir.path())?;
+    writer.set("removed".to_owned(), "value".to_owned())?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    writer.remove("removed".to_owned())?;
+
+    let files = log_files(temp_dir.path());
+    let value = "x".repeat(1000);
+    let mut iter = 0;
+    while log_files(temp_dir.path()).contains(&files[0]) {
+        for key_id in 0..10 {
+            writer.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+    }
+
+    reader.refresh()?;
+    for key_id in 0..10 {
+        assert_eq!(reader.get(format!("key{}", key_id))?, Some(format!("{}{}", iter - 1, value)));
+    }
+    assert_eq!(reader.get("removed".to_owned())?, None);
+    assert_eq!(reader.keys("")?.len(), 10);
+
+    writer.set("after".to_owned(), "compaction".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("after".to_owned())?, Some("compaction".to_owned()));
+    Ok(())
+}
+
+// A backup is a standalone copy of the data at the time it was taken
+fn check_backup<E: KvsEngine>(engine: E, dest: &std::path::Path) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    engine.backup(dest)?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(engine.backup(dest).is_err());
+    Ok(())
+}
+
+#[test]
+fn kvs_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = temp_dir.path().join("backup");
+    check_backup(KvStore::open(temp_dir.path().join("data"))?, &dest)?;
+
+    let backup = KvStore::open(&dest)?;
+    assert_eq!(backup.keys("")?, vec!["key1".to_owned()]);
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = temp_dir.path().join("backup");
+    check_backup(SledEngine::new(sled::open(temp_dir.path().join("data"))?), &dest)?;
+
+    let backup = SledEngine::new(sled::open(&dest)?);
+    assert_eq!(backup.keys("")?, vec!["key1".to_owned()]);
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use kvs::{KvsClient, KvsClientBuilder, KvsError, Protocol, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{discard_logger, start_server, start_server_with};
+
+// Connections beyond the limit are rejected until an existing one closes
+#[test]
+fn max_connections() -> Result<()> {
+    let addr = "127.0.0.1:4090".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.max_connections(2));
+
+    let mut first = KvsClient::connect(addr)?;
+    let second = KvsClient::connect(addr)?;
+    assert!(matches!(KvsClient::connect(addr), Err(KvsError::ServerBusy)));
+
+    drop(second);
+    thread::sleep(Duration::from_millis(100));
+    let mut third = KvsClient::connect(addr)?;
+    third.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(first.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Once the dispatch queue is full new clients are told the server is busy
+#[test]
+fn full_queue_rejects() -> Result<()> {
+    let addr = "127.0.0.1:4091".parse().unwrap();
+    let _temp_dir = start_server_with(addr, 1, discard_logger(), |server, _| server.queue_size(1));
+
+    // Occupies the only worker thread
+    let mut active = KvsClient::connect(addr)?;
+    // Waits in the queue for a worker
+    let _queued = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+
+    assert!(matches!(KvsClient::connect(addr), Err(KvsError::ServerBusy)));
+    active.ping()?;
+    Ok(())
+}
+
+// Idle connections are closed and no longer count against the limit
+#[test]
+fn idle_connections_closed() -> Result<()> {
+    let addr = "127.0.0.1:4092".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| {
+        server.max_connections(1).idle_timeout(Duration::from_millis(200))
+    });
+
+    let mut idle = KvsClient::connect(addr)?;
+    idle.set("key".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(600));
+
+    let mut other = KvsClient::connect(addr)?;
+    assert_eq!(other.get("key".to_owned())?, Some("value".to_owned()));
+    drop(other);
+    thread::sleep(Duration::from_millis(100));
+
+    // The idle client notices the closed connection and reconnects
+    assert!(idle.get("key".to_owned()).is_err());
+    assert_eq!(idle.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A client that stops in the middle of a request is disconnected after the read timeout
+#[test]
+fn partial_request_times_out() -> Result<()> {
+    let addr = "127.0.0.1:4093".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.read_timeout(Duration::from_millis(200)));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // Announce a 100-byte frame but never send it
+    stream.write_all(&100u32.to_be_bytes())?;
+
+    let start = Instant::now();
+    let mut buf = [0; 16];
+    let closed = matches!(stream.read(&mut buf), Ok(0) | Err(_));
+    assert!(closed);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    Ok(())
+}
+
+// The read timeout covers the whole request, so trickling bytes does not keep the connection alive
+#[test]
+fn slow_request_times_out() -> Result<()> {
+    let addr = "127.0.0.1:4096".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.read_timeout(Duration::from_millis(300)));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&100u32.to_be_bytes())?;
+    let start = Instant::now();
+    // Each byte arrives well within the read timeout, but the request never completes
+    let closed = (0..100).any(|_| {
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(b" ").is_err()
+    });
+    assert!(closed);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    Ok(())
+}
+
+// A pause in the middle of a request is bounded by the read timeout, not the idle timeout
+#[test]
+fn idle_timeout_spares_partial_request() -> Result<()> {
+    let addr = "127.0.0.1:4097".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| {
+        server.idle_timeout(Duration::from_millis(200)).read_timeout(Duration::from_secs(2))
+    });
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    write_frame(&mut stream, br#"{"min_version":2,"max_version":2,"codecs":["Json"],"features":[]}"#)?;
+    read_frame(&mut stream)?;
+
+    let request = br#"{"Set":{"key":"key","value":"value"}}"#;
+    stream.write_all(&(request.len() as u32).to_be_bytes())?;
+    stream.write_all(&request[..10])?;
+    thread::sleep(Duration::from_millis(600));
+    stream.write_all(&request[10..])?;
+    assert!(String::from_utf8_lossy(&read_frame(&mut stream)?).contains(r#""Ok":"Done""#));
+    Ok(())
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+// The RESP mode reports rejections the way Redis does
+#[test]
+fn resp_max_clients() -> Result<()> {
+    let addr = "127.0.0.1:4094".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server.protocol(Protocol::Resp).max_connections(1));
+
+    let mut first = BufReader::new(TcpStream::connect(addr)?);
+    first.get_mut().write_all(b"PING\r\n")?;
+    let mut line = String::new();
+    first.read_line(&mut line)?;
+    assert_eq!(line, "+PONG\r\n");
+
+    let mut second = BufReader::new(TcpStream::connect(addr)?);
+    let mut line = String::new();
+    second.read_line(&mut line)?;
+    assert_eq!(line, "-ERR max number of clients reached\r\n");
+    Ok(())
+}
+
+// Timeouts configured on the client and server do not interfere with normal traffic
+#[test]
+fn timeouts_allow_active_clients() -> Result<()> {
+    let addr = "127.0.0.1:4095".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| {
+        server.idle_timeout(Duration::from_secs(5)).read_timeout(Duration::from_millis(500))
+    });
+
+    let mut client = KvsClientBuilder::new(addr)
+        .read_timeout(Duration::from_secs(1))
+        .build()?;
+    for i in 0..5 {
+        client.set(format!("key{}", i), i.to_string())?;
+        thread::sleep(Duration::from_millis(200));
+    }
+    assert_eq!(client.get("key4".to_owned())?, Some("4".to_owned()));
+    Ok(())
+}
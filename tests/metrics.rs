@@ -0,0 +1,88 @@
+use kvs::{KvsClient, Protocol};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use tempfile::TempDir;
+
+mod common;
+
+// Start a kvs server with a metrics endpoint in the background and wait until it accepts connections.
+fn start_server(addr: SocketAddr, metrics_addr: SocketAddr, protocol: Protocol) -> TempDir {
+    common::start_server(addr, |server, _| server.protocol(protocol).metrics_addr(metrics_addr))
+}
+
+fn scrape(metrics_addr: SocketAddr) -> String {
+    ureq::get(&format!("http://{}/metrics", metrics_addr))
+        .call()
+        .unwrap()
+        .into_string()
+        .unwrap()
+}
+
+// Return the value of the sample with the given name and labels
+fn sample(metrics: &str, series: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("{} not found in:\n{}", series, metrics))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn request_and_engine_metrics() {
+    let addr = "127.0.0.1:4190".parse().unwrap();
+    let metrics_addr = "127.0.0.1:4191".parse().unwrap();
+    let _temp_dir = start_server(addr, metrics_addr, Protocol::Kvs);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value2".to_owned()));
+    assert!(client.remove("missing".to_owned()).is_err());
+
+    let metrics = scrape(metrics_addr);
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{command="set",result="ok"}"#), 3.0);
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{command="get",result="ok"}"#), 1.0);
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{command="rm",result="error"}"#), 1.0);
+    assert_eq!(sample(&metrics, r#"kvs_request_duration_seconds_count{command="set"}"#), 3.0);
+    assert_eq!(sample(&metrics, r#"kvs_request_duration_seconds_bucket{command="set",le="+Inf"}"#), 3.0);
+    assert!(metrics.contains("# TYPE kvs_request_duration_seconds histogram"));
+
+    assert_eq!(sample(&metrics, "kvs_connections"), 1.0);
+    assert_eq!(sample(&metrics, "kvs_thread_pool_queue_depth"), 0.0);
+    assert_eq!(sample(&metrics, "kvs_engine_keys"), 2.0);
+    assert!(sample(&metrics, "kvs_engine_live_bytes") > 0.0);
+    assert!(sample(&metrics, "kvs_engine_uncompacted_bytes") > 0.0);
+    assert_eq!(sample(&metrics, "kvs_engine_compactions_total"), 0.0);
+
+    match ureq::get(&format!("http://{}/other", metrics_addr)).call() {
+        Err(ureq::Error::Status(code, _)) => assert_eq!(code, 404),
+        res => panic!("unexpected response: {:?}", res.map(|resp| resp.status())),
+    }
+}
+
+// RESP commands are counted by name, unknown commands under a single label
+#[test]
+fn resp_metrics() {
+    let addr = "127.0.0.1:4192".parse().unwrap();
+    let metrics_addr = "127.0.0.1:4193".parse().unwrap();
+    let _temp_dir = start_server(addr, metrics_addr, Protocol::Resp);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    for command in ["SET key value", "GET key", "FLUSHALL"] {
+        stream.write_all(format!("{}\r\n", command).as_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.starts_with('$') {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+    }
+
+    let metrics = scrape(metrics_addr);
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{command="set",result="ok"}"#), 1.0);
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{command="get",result="ok"}"#), 1.0);
+    assert_eq!(sample(&metrics, r#"kvs_requests_total{command="unknown",result="error"}"#), 1.0);
+}
@@ -0,0 +1,124 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    HashRing, KvsClient, KvsClientBuilder, KvsEngine, KvsError, KvsServer, PrefixTable, ProxyEngine,
+    Result, Routing,
+};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+
+mod common;
+use common::{discard_logger, run_server, start_server, wait_for_listener};
+
+fn start_backend(addr: SocketAddr) -> TempDir {
+    start_server(addr, |server, _| server)
+}
+
+// Serve `engine` on `addr` in the background and wait until it accepts connections.
+fn start_proxy<E: KvsEngine>(addr: SocketAddr, engine: E) {
+    run_server(addr, KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap()), discard_logger());
+}
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+// Single and multi-key requests land on the backend the hash ring picks
+#[test]
+fn hash_ring_routing() -> Result<()> {
+    let backends = vec![addr(4180), addr(4181), addr(4182)];
+    let _dirs: Vec<TempDir> = backends.iter().map(|addr| start_backend(*addr)).collect();
+    let mut ring = HashRing::default();
+    for backend in &backends {
+        ring.add(*backend);
+    }
+    let engine = ProxyEngine::new(Routing::HashRing(ring.clone()), KvsClientBuilder::new(addr(4183)), 4)?;
+    start_proxy(addr(4183), engine);
+
+    let mut client = KvsClient::connect(addr(4183))?;
+    client.set("single".to_owned(), "1".to_owned())?;
+    assert_eq!(client.get("single".to_owned())?, Some("1".to_owned()));
+
+    let pairs: Vec<(String, String)> = (0..50).map(|i| (format!("key{}", i), i.to_string())).collect();
+    assert!(client.mset(pairs.clone())?.iter().all(Result::is_ok));
+    let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let values = client.mget(keys.clone())?;
+    for ((_, value), result) in pairs.iter().zip(values) {
+        assert_eq!(result?, Some(value.clone()));
+    }
+
+    for backend in &backends {
+        let mut direct = KvsClient::connect(*backend)?;
+        for (key, value) in &pairs {
+            let expected = Some(value.clone()).filter(|_| ring.node_for(key) == Some(*backend));
+            assert_eq!(direct.get(key.clone())?, expected);
+        }
+    }
+
+    // Missing keys fail individually within a multi-key request
+    let results = client.mremove(vec!["key0".to_owned(), "missing".to_owned(), "key1".to_owned()])?;
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(KvsError::KeyNotFound)));
+    assert!(results[2].is_ok());
+    assert_eq!(client.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// The longest matching prefix wins, and the empty prefix catches everything else
+#[test]
+fn prefix_routing() -> Result<()> {
+    let _users = start_backend(addr(4184));
+    let _rest = start_backend(addr(4185));
+    let table = PrefixTable::new().route("user:", addr(4184)).route("", addr(4185));
+    let engine = ProxyEngine::new(Routing::Prefix(table), KvsClientBuilder::new(addr(4186)), 4)?;
+    start_proxy(addr(4186), engine);
+
+    let mut client = KvsClient::connect(addr(4186))?;
+    client.set("user:1".to_owned(), "alice".to_owned())?;
+    client.set("order:1".to_owned(), "book".to_owned())?;
+
+    let mut users = KvsClient::connect(addr(4184))?;
+    let mut rest = KvsClient::connect(addr(4185))?;
+    assert_eq!(users.get("user:1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(users.get("order:1".to_owned())?, None);
+    assert_eq!(rest.get("order:1".to_owned())?, Some("book".to_owned()));
+    assert_eq!(rest.get("user:1".to_owned())?, None);
+    Ok(())
+}
+
+// The kvs-proxy binary forwards to its backends
+#[test]
+fn proxy_binary() -> Result<()> {
+    let _first = start_backend(addr(4187));
+    let _second = start_backend(addr(4188));
+    let mut proxy = Command::cargo_bin("kvs-proxy")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4189", "--backend", "127.0.0.1:4187", "--backend", "127.0.0.1:4188"])
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_for_listener(addr(4189));
+
+    let res = (|| {
+        let mut client = KvsClient::connect(addr(4189))?;
+        for i in 0..20 {
+            client.set(format!("key{}", i), i.to_string())?;
+        }
+        let mut stored = 0;
+        for backend in [addr(4187), addr(4188)] {
+            let mut direct = KvsClient::connect(backend)?;
+            for i in 0..20 {
+                if direct.get(format!("key{}", i))?.is_some() {
+                    stored += 1;
+                }
+            }
+        }
+        assert_eq!(stored, 20);
+        assert_eq!(client.get("key7".to_owned())?, Some("7".to_owned()));
+        Ok(())
+    })();
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+    res
+}
@@ -0,0 +1,120 @@
+use kvs::{KvsClient, KvsError, RateLimit, Result};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::auth_config;
+
+// Both users may read and write everything. Passwords are "alice-secret" and "bob-secret".
+const AUTH_CONFIG: &str = r#"{
+  "users": [
+    {
+      "name": "alice",
+      "password_hash": "pbkdf2-sha256$1000$5a1f0c3e9b7d42a6e8c1f3b5d7092a4c$8cbb64b6ff1c6ac234b71e4d2c875326a71bae2a282caff7fdfd1920efc096e6",
+      "rules": [{ "prefix": "", "read": true, "write": true }]
+    },
+    {
+      "name": "bob",
+      "password_hash": "pbkdf2-sha256$1000$c3d9e17a4b2f6805a1e7c9d3b5f70248$d67a216b211abf822632f0641f0a2aaab2b7645bec6c78a4f2a432b7948c7e78",
+      "rules": [{ "prefix": "", "read": true, "write": true }]
+    }
+  ]
+}"#;
+
+// Start a rate-limited server and wait until it accepts connections.
+fn start_server(addr: SocketAddr, limit: RateLimit, with_auth: bool) -> TempDir {
+    common::start_server(addr, |server, dir| {
+        let server = server.rate_limit(limit);
+        if with_auth {
+            server.auth(auth_config(dir, AUTH_CONFIG))
+        } else {
+            server
+        }
+    })
+}
+
+// Requests beyond the per-second budget are throttled until the bucket refills
+#[test]
+fn ops_per_sec() -> Result<()> {
+    let addr = "127.0.0.1:4110".parse().unwrap();
+    let _temp_dir = start_server(addr, RateLimit::new().ops_per_sec(5.0), false);
+    let mut client = KvsClient::connect(addr)?;
+
+    for _ in 0..5 {
+        client.get("key".to_owned())?;
+    }
+    assert!(matches!(client.get("key".to_owned()), Err(KvsError::Throttled)));
+
+    // The connection stays usable and the budget refills over time
+    thread::sleep(Duration::from_millis(500));
+    client.get("key".to_owned())?;
+    Ok(())
+}
+
+// A budget below one request per second still lets a request through every so often
+#[test]
+fn fractional_ops_per_sec() -> Result<()> {
+    let addr = "127.0.0.1:4114".parse().unwrap();
+    let _temp_dir = start_server(addr, RateLimit::new().ops_per_sec(2.0 / 3.0), false);
+    let mut client = KvsClient::connect(addr)?;
+
+    client.get("key".to_owned())?;
+    assert!(matches!(client.get("key".to_owned()), Err(KvsError::Throttled)));
+
+    thread::sleep(Duration::from_millis(1600));
+    client.get("key".to_owned())?;
+    assert!(matches!(client.get("key".to_owned()), Err(KvsError::Throttled)));
+    Ok(())
+}
+
+// Clients from the same address share a budget
+#[test]
+fn shared_by_address() -> Result<()> {
+    let addr = "127.0.0.1:4111".parse().unwrap();
+    let _temp_dir = start_server(addr, RateLimit::new().ops_per_sec(4.0), false);
+    let mut first = KvsClient::connect(addr)?;
+    let mut second = KvsClient::connect(addr)?;
+
+    first.get("key".to_owned())?;
+    first.get("key".to_owned())?;
+    second.get("key".to_owned())?;
+    second.get("key".to_owned())?;
+    assert!(matches!(first.get("key".to_owned()), Err(KvsError::Throttled)));
+    assert!(matches!(second.get("key".to_owned()), Err(KvsError::Throttled)));
+    Ok(())
+}
+
+// Authenticated users get their own budget
+#[test]
+fn separate_by_user() -> Result<()> {
+    let addr = "127.0.0.1:4112".parse().unwrap();
+    let _temp_dir = start_server(addr, RateLimit::new().ops_per_sec(3.0), true);
+    let mut alice = KvsClient::connect(addr)?;
+    let mut bob = KvsClient::connect(addr)?;
+    alice.auth("alice".to_owned(), "alice-secret".to_owned())?;
+    bob.auth("bob".to_owned(), "bob-secret".to_owned())?;
+
+    for _ in 0..3 {
+        alice.set("key".to_owned(), "value".to_owned())?;
+    }
+    assert!(matches!(alice.get("key".to_owned()), Err(KvsError::Throttled)));
+    assert_eq!(bob.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A large request is served but has to be paid back before the next one
+#[test]
+fn bytes_per_sec() -> Result<()> {
+    let addr = "127.0.0.1:4113".parse().unwrap();
+    let _temp_dir = start_server(addr, RateLimit::new().bytes_per_sec(1000.0), false);
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set("key".to_owned(), "x".repeat(1500))?;
+    assert!(matches!(client.get("key".to_owned()), Err(KvsError::Throttled)));
+
+    thread::sleep(Duration::from_millis(800));
+    assert_eq!(client.get("key".to_owned())?.map(|v| v.len()), Some(1500));
+    Ok(())
+}
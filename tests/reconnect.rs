@@ -0,0 +1,85 @@
+use kvs::{KvsClient, KvsClientBuilder, KvsError, Result};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{Proxy, start_server};
+
+// A server that accepts connections but never answers produces a read timeout
+#[test]
+fn read_timeout_error() {
+    let listener = TcpListener::bind("127.0.0.1:4070").unwrap();
+    thread::spawn(move || {
+        let _conns: Vec<_> = listener.incoming().collect();
+    });
+
+    let start = Instant::now();
+    let res = KvsClientBuilder::new("127.0.0.1:4070".parse().unwrap())
+        .read_timeout(Duration::from_millis(200))
+        .build();
+    assert!(matches!(res, Err(KvsError::ReadTimeout)));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+// After the connection is dropped the failed request errors and the next one reconnects
+#[test]
+fn reconnect_after_connection_dropped() -> Result<()> {
+    let server_addr = "127.0.0.1:4071".parse().unwrap();
+    let _temp_dir = start_server(server_addr, |server, _| server);
+    let proxy = Proxy::start("127.0.0.1:4072".parse().unwrap(), server_addr, || {});
+
+    let mut client = KvsClient::connect("127.0.0.1:4072".parse().unwrap())?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    proxy.drop_connections();
+    assert!(client.get("key".to_owned()).is_err());
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Idempotent requests are retried transparently, others are not
+#[test]
+fn retry_idempotent_requests_only() -> Result<()> {
+    let server_addr = "127.0.0.1:4073".parse().unwrap();
+    let _temp_dir = start_server(server_addr, |server, _| server);
+    let proxy = Proxy::start("127.0.0.1:4074".parse().unwrap(), server_addr, || {});
+
+    let mut client = KvsClientBuilder::new("127.0.0.1:4074".parse().unwrap())
+        .retries(2)
+        .retry_backoff(Duration::from_millis(10))
+        .build()?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    proxy.drop_connections();
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    proxy.drop_connections();
+    assert!(matches!(client.remove("key".to_owned()), Err(KvsError::Io(_))));
+    client.remove("key".to_owned())?;
+    Ok(())
+}
+
+// A set whose response was lost is retried with the same token and not applied twice
+#[test]
+fn retried_set_applied_once() -> Result<()> {
+    let server_addr: SocketAddr = "127.0.0.1:4075".parse().unwrap();
+    let _temp_dir = start_server(server_addr, |server, _| server);
+    // Another writer updates the key after the first attempt but before the retry
+    let proxy = Proxy::start("127.0.0.1:4076".parse().unwrap(), server_addr, move || {
+        let mut other = KvsClient::connect(server_addr).unwrap();
+        other.set("key".to_owned(), "other".to_owned()).unwrap();
+    });
+
+    let mut client = KvsClientBuilder::new("127.0.0.1:4076".parse().unwrap())
+        .retries(2)
+        .retry_backoff(Duration::from_millis(10))
+        .build()?;
+    assert!(client.features().iter().any(|f| f == "idempotency"));
+
+    proxy.cut_next_response.store(true, Ordering::SeqCst);
+    client.set("key".to_owned(), "mine".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("other".to_owned()));
+    Ok(())
+}
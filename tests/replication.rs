@@ -0,0 +1,135 @@
+use kvs::{KvsClient, KvsClientBuilder, KvsError, Protocol, Result};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{Proxy, start_server};
+
+// Poll the replica until `key` has the expected value
+fn wait_for(client: &mut KvsClient, key: &str, expected: Option<&str>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while client.get(key.to_owned())?.as_deref() != expected {
+        assert!(Instant::now() < deadline, "replica did not converge on {}", key);
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+// A replica loads existing data, follows new writes and rejects writes of its own
+#[test]
+fn replica_follows_primary() -> Result<()> {
+    let primary_addr = "127.0.0.1:4140".parse().unwrap();
+    let replica_addr = "127.0.0.1:4141".parse().unwrap();
+    let _primary_dir = start_server(primary_addr, |server, _| server);
+    let mut primary = KvsClient::connect(primary_addr)?;
+    primary.set("old".to_owned(), "1".to_owned())?;
+
+    let replica_dir = start_server(replica_addr, move |server, dir| {
+        server.replica_of(KvsClientBuilder::new(primary_addr), dir.path().join("replica.seq"))
+    });
+    let mut replica = KvsClient::connect(replica_addr)?;
+    wait_for(&mut replica, "old", Some("1"))?;
+
+    primary.set("new".to_owned(), "2".to_owned())?;
+    primary.remove("old".to_owned())?;
+    wait_for(&mut replica, "new", Some("2"))?;
+    wait_for(&mut replica, "old", None)?;
+
+    assert!(matches!(
+        replica.set("key".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(replica.remove("new".to_owned()), Err(KvsError::ReadOnly)));
+    assert_eq!(primary.get("key".to_owned())?, None);
+
+    // The progress is saved next to the replica's data
+    let state_path = replica_dir.path().join("replica.seq");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while fs::read_to_string(&state_path).ok().as_deref() != Some("4") {
+        assert!(Instant::now() < deadline, "replication progress was not saved");
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+// A restarted replica continues from its saved progress instead of loading a snapshot
+#[test]
+fn replica_resumes_from_saved_progress() -> Result<()> {
+    let primary_addr = "127.0.0.1:4147".parse().unwrap();
+    let replica_addr = "127.0.0.1:4148".parse().unwrap();
+    let _primary_dir = start_server(primary_addr, |server, _| server);
+    let mut primary = KvsClient::connect(primary_addr)?;
+    primary.set("before".to_owned(), "1".to_owned())?;
+    primary.set("after".to_owned(), "2".to_owned())?;
+
+    let _replica_dir = start_server(replica_addr, move |server, dir| {
+        let state_path = dir.path().join("replica.seq");
+        fs::write(&state_path, "2").unwrap();
+        server.replica_of(KvsClientBuilder::new(primary_addr), state_path)
+    });
+    let mut replica = KvsClient::connect(replica_addr)?;
+    wait_for(&mut replica, "after", Some("2"))?;
+    assert_eq!(replica.get("before".to_owned())?, None);
+    Ok(())
+}
+
+// A replica that falls behind the primary's compaction reloads a snapshot
+#[test]
+fn replica_catches_up_after_compaction() -> Result<()> {
+    let primary_addr: SocketAddr = "127.0.0.1:4142".parse().unwrap();
+    let proxy_addr = "127.0.0.1:4143".parse().unwrap();
+    let replica_addr = "127.0.0.1:4144".parse().unwrap();
+    let _primary_dir = start_server(primary_addr, |server, _| server);
+    let mut primary = KvsClient::connect(primary_addr)?;
+    primary.set("removed".to_owned(), "1".to_owned())?;
+
+    // While the replica is cut off, enough is written to compact away what it missed
+    let proxy = Proxy::start(proxy_addr, primary_addr, move || {
+        let mut client = KvsClient::connect(primary_addr).unwrap();
+        client.remove("removed".to_owned()).unwrap();
+        let value = "v".repeat(1000);
+        for _ in 0..2000 {
+            client.set("big".to_owned(), value.clone()).unwrap();
+        }
+        client.set("last".to_owned(), "done".to_owned()).unwrap();
+    });
+
+    let _replica_dir = start_server(replica_addr, move |server, dir| {
+        server.replica_of(KvsClientBuilder::new(proxy_addr), dir.path().join("replica.seq"))
+    });
+    let mut replica = KvsClient::connect(replica_addr)?;
+    wait_for(&mut replica, "removed", Some("1"))?;
+
+    // The change for this write never reaches the replica
+    proxy.cut_next_response.store(true, Ordering::SeqCst);
+    primary.set("missed".to_owned(), "x".to_owned())?;
+
+    wait_for(&mut replica, "last", Some("done"))?;
+    assert_eq!(replica.get("missed".to_owned())?, Some("x".to_owned()));
+    assert_eq!(replica.get("removed".to_owned())?, None);
+    Ok(())
+}
+
+// RESP clients get the same error as from a Redis replica
+#[test]
+fn resp_replica_read_only() -> Result<()> {
+    let primary_addr = "127.0.0.1:4145".parse().unwrap();
+    let replica_addr = "127.0.0.1:4146".parse().unwrap();
+    let _primary_dir = start_server(primary_addr, |server, _| server);
+    let _replica_dir = start_server(replica_addr, move |server, dir| {
+        server
+            .protocol(Protocol::Resp)
+            .replica_of(KvsClientBuilder::new(primary_addr), dir.path().join("replica.seq"))
+    });
+
+    let mut stream = BufReader::new(TcpStream::connect(replica_addr)?);
+    stream.get_mut().write_all(b"SET key value\r\n")?;
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    assert!(line.starts_with("-READONLY "));
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use kvs::Protocol;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+
+// Start a RESP kvs server with a `KvStore` engine in the background and connect to it.
+fn start_server(addr: SocketAddr) -> (TempDir, RespConn) {
+    let temp_dir = common::start_server(addr, |server, _| server.protocol(Protocol::Resp));
+    (temp_dir, RespConn::new(TcpStream::connect(addr).unwrap()))
+}
+
+struct RespConn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespConn {
+    fn new(stream: TcpStream) -> Self {
+        RespConn {
+            writer: stream.try_clone().unwrap(),
+            reader: BufReader::new(stream),
+        }
+    }
+
+    // Send a command as an array of bulk strings, like redis-cli does
+    fn send(&mut self, args: &[&str]) {
+        let mut cmd = format!("*{}\r\n", args.len());
+        for arg in args {
+            cmd.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(cmd.as_bytes()).unwrap();
+    }
+
+    // Read one reply and render it as a single line for easy comparison
+    fn read_reply(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end().to_owned();
+        match line.as_bytes()[0] {
+            b'$' if line != "$-1" => {
+                let len: usize = line[1..].parse().unwrap();
+                let mut data = vec![0; len + 2];
+                self.reader.read_exact(&mut data).unwrap();
+                String::from_utf8(data[..len].to_vec()).unwrap()
+            }
+            b'*' => {
+                let count: usize = line[1..].parse().unwrap();
+                let items: Vec<String> = (0..count).map(|_| self.read_reply()).collect();
+                format!("[{}]", items.join(","))
+            }
+            _ => line,
+        }
+    }
+
+    fn call(&mut self, args: &[&str]) -> String {
+        self.send(args);
+        self.read_reply()
+    }
+}
+
+#[test]
+fn resp_basic_commands() {
+    let (_temp_dir, mut conn) = start_server("127.0.0.1:4020".parse().unwrap());
+
+    assert_eq!(conn.call(&["PING"]), "+PONG");
+    assert_eq!(conn.call(&["ping", "hello"]), "hello");
+    assert_eq!(conn.call(&["SET", "key1", "value1"]), "+OK");
+    assert_eq!(conn.call(&["GET", "key1"]), "value1");
+    assert_eq!(conn.call(&["GET", "key2"]), "$-1");
+    assert_eq!(conn.call(&["SET", "key2", "value2"]), "+OK");
+    assert_eq!(conn.call(&["EXISTS", "key1", "key2", "key3"]), ":2");
+    assert_eq!(conn.call(&["DEL", "key1", "key3"]), ":1");
+    assert_eq!(conn.call(&["EXISTS", "key1"]), ":0");
+    assert!(conn.call(&["INFO"]).contains(env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn resp_keys_pattern() {
+    let (_temp_dir, mut conn) = start_server("127.0.0.1:4021".parse().unwrap());
+
+    for key in &["user:1", "user:2", "user:10", "order:1"] {
+        assert_eq!(conn.call(&["SET", key, "v"]), "+OK");
+    }
+
+    assert_eq!(conn.call(&["KEYS", "*"]), "[order:1,user:1,user:10,user:2]");
+    assert_eq!(conn.call(&["KEYS", "user:*"]), "[user:1,user:10,user:2]");
+    assert_eq!(conn.call(&["KEYS", "user:?"]), "[user:1,user:2]");
+    assert_eq!(conn.call(&["KEYS", "*:1"]), "[order:1,user:1]");
+    assert_eq!(conn.call(&["KEYS", "user:[^1]"]), "[user:2]");
+    assert_eq!(conn.call(&["KEYS", "nothing*"]), "[]");
+
+    // An unclosed character class matches a literal '['
+    assert_eq!(conn.call(&["SET", "tag[1", "v"]), "+OK");
+    assert_eq!(conn.call(&["KEYS", "tag[1"]), "[tag[1]");
+    assert_eq!(conn.call(&["KEYS", "tag[*"]), "[tag[1]");
+    assert_eq!(conn.call(&["KEYS", "*[1"]), "[tag[1]");
+}
+
+// Patterns with many wildcards finish quickly, and overly long patterns are refused
+#[test]
+fn resp_keys_pattern_limits() {
+    let (_temp_dir, mut conn) = start_server("127.0.0.1:4024".parse().unwrap());
+    let key = "a".repeat(200);
+    assert_eq!(conn.call(&["SET", &key, "v"]), "+OK");
+    assert_eq!(conn.call(&["SET", "a*b", "v"]), "+OK");
+
+    let start = Instant::now();
+    assert_eq!(conn.call(&["KEYS", &"a*".repeat(20)]), format!("[{}]", key));
+    assert_eq!(conn.call(&["KEYS", &format!("{}b", "a*".repeat(20))]), "[]");
+    assert_eq!(conn.call(&["KEYS", &format!("{}a", "*".repeat(1000))]), format!("[{}]", key));
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    assert_eq!(conn.call(&["KEYS", "a\\*b"]), "[a*b]");
+    assert_eq!(conn.call(&["KEYS", "a[*]?"]), "[a*b]");
+    assert!(conn.call(&["KEYS", &"?".repeat(2000)]).starts_with("-ERR pattern too long"));
+}
+
+#[test]
+fn resp_errors() {
+    let (_temp_dir, mut conn) = start_server("127.0.0.1:4022".parse().unwrap());
+
+    assert!(conn.call(&["GET"]).starts_with("-ERR wrong number of arguments"));
+    assert!(conn.call(&["SET", "key1", "value1", "EX", "10"]).starts_with("-ERR syntax error"));
+    assert!(conn.call(&["FLUSHALL"]).starts_with("-ERR unknown command"));
+
+    // Inline commands, as typed into telnet, are accepted too
+    conn.writer.write_all(b"SET key1 value1\r\nGET key1\r\n").unwrap();
+    assert_eq!(conn.read_reply(), "+OK");
+    assert_eq!(conn.read_reply(), "value1");
+
+    // A protocol error is reported and the connection is closed
+    conn.writer.write_all(b"*1\r\n+PING\r\n").unwrap();
+    assert!(conn.read_reply().starts_with("-ERR Protocol error"));
+    let mut buf = [0; 1];
+    assert_eq!(conn.reader.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn resp_line_too_long() {
+    let (_temp_dir, mut conn) = start_server("127.0.0.1:4023".parse().unwrap());
+
+    // Lines are capped rather than buffered until a newline shows up
+    conn.writer.write_all(&vec![b'a'; 128 * 1024]).unwrap();
+    assert!(conn.read_reply().starts_with("-ERR Protocol error: line too long"));
+    // The rest of the line is never read, so the close may surface as a reset
+    let mut buf = [0; 1];
+    assert!(!matches!(conn.reader.read(&mut buf), Ok(n) if n > 0));
+}
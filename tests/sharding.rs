@@ -0,0 +1,128 @@
+use kvs::{HashRing, KvsClient, Result, ShardedKvsClient};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+// Keys are spread evenly, and adding a node only moves keys onto that node
+#[test]
+fn ring_minimal_movement() {
+    let mut ring = HashRing::default();
+    for port in 5000..5003 {
+        ring.add(addr(port));
+    }
+    let keys: Vec<String> = (0..10_000).map(|i| format!("key{}", i)).collect();
+    let before: Vec<SocketAddr> = keys.iter().map(|key| ring.node_for(key).unwrap()).collect();
+
+    let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+    for node in &before {
+        *counts.entry(*node).or_default() += 1;
+    }
+    assert!(counts.values().all(|count| *count > 2_500 && *count < 4_200));
+
+    ring.add(addr(5003));
+    let mut moved = 0;
+    for (key, old) in keys.iter().zip(&before) {
+        let new = ring.node_for(key).unwrap();
+        if new != *old {
+            assert_eq!(new, addr(5003));
+            moved += 1;
+        }
+    }
+    assert!(moved > 1_500 && moved < 3_500, "{} keys moved", moved);
+
+    // Removing the node restores the original placement
+    ring.remove(addr(5003));
+    let after: Vec<SocketAddr> = keys.iter().map(|key| ring.node_for(key).unwrap()).collect();
+    assert_eq!(before, after);
+}
+
+// Placement depends only on the set of nodes, not on the order they were added or removed in
+#[test]
+fn ring_independent_of_order() {
+    let ports = [5000, 5001, 5002, 5003];
+    let mut forward = HashRing::new(1000);
+    let mut backward = HashRing::new(1000);
+    for &port in &ports {
+        forward.add(addr(port));
+    }
+    for &port in ports.iter().rev() {
+        backward.add(addr(port));
+    }
+    // Going through an extra node leaves no trace once it is removed again
+    backward.add(addr(5004));
+    backward.remove(addr(5004));
+    for i in 0..10_000 {
+        let key = format!("key{}", i);
+        assert_eq!(forward.node_for(&key), backward.node_for(&key));
+    }
+
+    for &port in &ports {
+        forward.remove(addr(port));
+    }
+    assert!(forward.is_empty());
+    assert_eq!(forward.node_for("key"), None);
+}
+
+#[test]
+fn sharded_get_set_remove() -> Result<()> {
+    let addrs = vec![addr(4170), addr(4171), addr(4172)];
+    let _dirs: Vec<TempDir> = addrs.iter().map(|addr| start_server(*addr, |server, _| server)).collect();
+
+    let mut client = ShardedKvsClient::connect(addrs.clone());
+    for i in 0..100 {
+        client.set(format!("key{}", i), i.to_string())?;
+    }
+    for i in 0..100 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+    client.remove("key0".to_owned())?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+
+    // Each key is stored only on the server it hashes to
+    for addr in addrs {
+        let mut server = KvsClient::connect(addr)?;
+        for i in 1..100 {
+            let key = format!("key{}", i);
+            let expected = Some(i.to_string()).filter(|_| client.node_for(&key) == Some(addr));
+            assert_eq!(server.get(key)?, expected);
+        }
+    }
+    Ok(())
+}
+
+// Only keys whose owner changed are moved when nodes join or leave
+#[test]
+fn rebalance_after_membership_change() -> Result<()> {
+    let addrs = vec![addr(4173), addr(4174), addr(4175)];
+    let _dirs: Vec<TempDir> = addrs.iter().map(|addr| start_server(*addr, |server, _| server)).collect();
+    let _new_dir = start_server(addr(4176), |server, _| server);
+
+    let mut client = ShardedKvsClient::connect(addrs);
+    for i in 0..200 {
+        client.set(format!("key{}", i), i.to_string())?;
+    }
+
+    let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+    client.add_node(addr(4176));
+    let expected = keys.iter().filter(|key| client.node_for(key) == Some(addr(4176))).count();
+    assert!(expected > 0);
+    assert_eq!(client.rebalance()?, expected);
+    for i in 0..200 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+
+    // Draining a removed node moves all of its keys to their new owners
+    client.remove_node(addr(4173));
+    assert!(client.migrate_from(addr(4173))? > 0);
+    for i in 0..200 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+    Ok(())
+}
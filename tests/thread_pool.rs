@@ -68,3 +68,58 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn shared_queue_thread_pool_rejects_when_full() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue_size(1, 1)?;
+    let (started_tx, started_rx) = crossbeam_channel::bounded(0);
+    let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(0);
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+    });
+    // The only worker is busy, so one more task fits in the queue and the next is rejected
+    started_rx.recv().unwrap();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let queued = counter.clone();
+    assert!(pool.try_spawn(move || {
+        queued.fetch_add(1, Ordering::SeqCst);
+    }));
+    assert!(!pool.try_spawn(|| {}));
+
+    release_tx.send(()).unwrap();
+    let wg = WaitGroup::new();
+    let done = wg.clone();
+    pool.spawn(move || drop(done));
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_unbounded_by_default() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let (started_tx, started_rx) = crossbeam_channel::bounded(0);
+    let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(0);
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+    });
+    // Nothing is rejected however many tasks wait behind the busy worker
+    started_rx.recv().unwrap();
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..5000 {
+        let counter = counter.clone();
+        let wg = wg.clone();
+        assert!(pool.try_spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            drop(wg);
+        }));
+    }
+
+    release_tx.send(()).unwrap();
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), 5000);
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use assert_cmd::prelude::*;
+use kvs::{ClientTls, Codec, KvsClient, Result, ServerTls};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+// A self-signed CA written to a temporary directory, able to issue leaf certificates
+struct TestCa {
+    dir: TempDir,
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl TestCa {
+    fn new() -> Self {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "kvs test CA");
+        let cert = params.self_signed(&key).unwrap();
+
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("ca.pem"), cert.pem()).unwrap();
+        TestCa { dir, cert, key }
+    }
+
+    fn ca_path(&self) -> PathBuf {
+        self.dir.path().join("ca.pem")
+    }
+
+    // Issue a certificate and return the paths of its PEM certificate and key
+    fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+        let cert_path = self.dir.path().join(format!("{}.pem", name));
+        let key_path = self.dir.path().join(format!("{}-key.pem", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+}
+
+#[test]
+fn tls_round_trip() -> Result<()> {
+    let ca = TestCa::new();
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    let addr = "127.0.0.1:4040".parse().unwrap();
+    let tls = ServerTls::new(&cert, &key, None)?;
+    let _temp_dir = start_server(addr, |server, _| server.tls(tls));
+
+    let tls = ClientTls::new(&ca.ca_path(), None)?;
+    let mut client = KvsClient::connect_tls(addr, Codec::Bincode, &tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // The certificate is also valid for its DNS name
+    let tls = tls.with_server_name("localhost")?;
+    let mut client = KvsClient::connect_tls(addr, Codec::Json, &tls)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn tls_rejects_untrusted_server() -> Result<()> {
+    let ca = TestCa::new();
+    let other_ca = TestCa::new();
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    let addr = "127.0.0.1:4041".parse().unwrap();
+    let tls = ServerTls::new(&cert, &key, None)?;
+    let _temp_dir = start_server(addr, |server, _| server.tls(tls));
+
+    // A client pinned to another CA refuses the server certificate
+    let tls = ClientTls::new(&other_ca.ca_path(), None)?;
+    assert!(KvsClient::connect_tls(addr, Codec::Bincode, &tls).is_err());
+
+    // The certificate does not cover this name
+    let tls = ClientTls::new(&ca.ca_path(), None)?.with_server_name("example.com")?;
+    assert!(KvsClient::connect_tls(addr, Codec::Bincode, &tls).is_err());
+
+    // A plaintext client cannot talk to a TLS server
+    assert!(KvsClient::connect(addr).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn tls_client_auth() -> Result<()> {
+    let ca = TestCa::new();
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    let (client_cert, client_key) = ca.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+    let addr = "127.0.0.1:4042".parse().unwrap();
+    let tls = ServerTls::new(&cert, &key, Some(&ca.ca_path()))?;
+    let _temp_dir = start_server(addr, |server, _| server.tls(tls));
+
+    let tls = ClientTls::new(&ca.ca_path(), None)?;
+    assert!(KvsClient::connect_tls(addr, Codec::Bincode, &tls).is_err());
+
+    // A client certificate from an unknown CA is rejected too
+    let other_ca = TestCa::new();
+    let (other_cert, other_key) = other_ca.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+    let tls = ClientTls::new(&ca.ca_path(), Some((&other_cert, &other_key)))?;
+    assert!(KvsClient::connect_tls(addr, Codec::Bincode, &tls).is_err());
+
+    let tls = ClientTls::new(&ca.ca_path(), Some((&client_cert, &client_key)))?;
+    let mut client = KvsClient::connect_tls(addr, Codec::Bincode, &tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn tls_cli() {
+    let ca = TestCa::new();
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    let (client_cert, client_key) = ca.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4043";
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--tls-cert"])
+        .arg(&cert)
+        .arg("--tls-key")
+        .arg(&key)
+        .arg("--tls-client-ca")
+        .arg(ca.ca_path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--tls-ca"])
+        .arg(ca.ca_path())
+        .arg("--tls-cert")
+        .arg(&client_cert)
+        .arg("--tls-key")
+        .arg(&client_key)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--tls-server-name", "localhost", "--tls-ca"])
+        .arg(ca.ca_path())
+        .arg("--tls-cert")
+        .arg(&client_cert)
+        .arg("--tls-key")
+        .arg(&client_key)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--tls-ca"])
+        .arg(ca.ca_path())
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
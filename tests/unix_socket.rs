@@ -0,0 +1,84 @@
+#![cfg(unix)]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Codec, KvStore, KvsClient, KvsServer, Result};
+use slog::{o, Discard, Logger};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn engine(temp_dir: &TempDir) -> KvStore {
+    KvStore::open(temp_dir.path()).unwrap()
+}
+
+fn logger() -> Arc<Logger> {
+    Arc::new(Logger::root(Discard, o!()))
+}
+
+// Wait until the server accepts connections on the socket.
+fn wait_for_socket(path: &Path) {
+    for _ in 0..50 {
+        if KvsClient::connect_unix(path, Codec::Bincode).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("server did not start listening on {}", path.display());
+}
+
+// A server listening only on a Unix socket serves the usual requests
+#[test]
+fn unix_socket_only() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let server = KvsServer::new(engine(&temp_dir), SharedQueueThreadPool::new(4)?);
+    let socket = path.clone();
+    thread::spawn(move || server.run_unix(socket, logger()));
+    wait_for_socket(&path);
+
+    let mut client = KvsClient::connect_unix(&path, Codec::Json)?;
+    assert_eq!(client.codec(), Codec::Json);
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.remove("key".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// TCP and Unix socket listeners share the same engine
+#[test]
+fn unix_socket_alongside_tcp() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let addr: SocketAddr = "127.0.0.1:4060".parse().unwrap();
+    let server = KvsServer::new(engine(&temp_dir), SharedQueueThreadPool::new(4)?).unix_socket(&path);
+    thread::spawn(move || server.run(addr, logger()));
+    wait_for_socket(&path);
+
+    let mut unix_client = KvsClient::connect_unix(&path, Codec::Bincode)?;
+    let mut tcp_client = KvsClient::connect(addr)?;
+    unix_client.set("key".to_owned(), "from unix".to_owned())?;
+    assert_eq!(tcp_client.get("key".to_owned())?, Some("from unix".to_owned()));
+    tcp_client.set("key".to_owned(), "from tcp".to_owned())?;
+    assert_eq!(unix_client.get("key".to_owned())?, Some("from tcp".to_owned()));
+    Ok(())
+}
+
+// A socket file left behind by a previous run does not prevent binding
+#[test]
+fn stale_socket_file_replaced() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    drop(UnixListener::bind(&path)?);
+    assert!(path.exists());
+
+    let server = KvsServer::new(engine(&temp_dir), SharedQueueThreadPool::new(2)?);
+    let socket = path.clone();
+    thread::spawn(move || server.run_unix(socket, logger()));
+    wait_for_socket(&path);
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use assert_cmd::prelude::*;
+use kvs::{Event, KvStore, KvsClient, KvsEngine, KvsError, Result, SledEngine};
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{auth_config, start_server};
+
+// bob may only read keys under "public:", password "bob-secret".
+const AUTH_CONFIG: &str = r#"{
+  "users": [
+    {
+      "name": "bob",
+      "password_hash": "pbkdf2-sha256$1000$c3d9e17a4b2f6805a1e7c9d3b5f70248$d67a216b211abf822632f0641f0a2aaab2b7645bec6c78a4f2a432b7948c7e78",
+      "rules": [{ "prefix": "public:", "read": true }]
+    }
+  ]
+}"#;
+
+fn set_event(key: &str, value: &str) -> Event {
+    Event::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn remove_event(key: &str) -> Event {
+    Event::Remove { key: key.to_owned() }
+}
+
+// Exercise the write path of an engine and check the events seen by a prefix watcher
+fn engine_events(engine: impl KvsEngine) -> Result<()> {
+    engine.set("config:old".to_owned(), "0".to_owned())?;
+    let mut watcher = engine.watch("config:")?;
+
+    engine.set("config:a".to_owned(), "1".to_owned())?;
+    engine.set("other".to_owned(), "2".to_owned())?;
+    engine.set("config:a".to_owned(), "3".to_owned())?;
+    engine.remove("config:old".to_owned())?;
+
+    let timeout = Duration::from_secs(1);
+    assert_eq!(watcher.next_timeout(timeout)?, Some(set_event("config:a", "1")));
+    assert_eq!(watcher.next_timeout(timeout)?, Some(set_event("config:a", "3")));
+    assert_eq!(watcher.next_timeout(timeout)?, Some(remove_event("config:old")));
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100))?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_engine_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_events(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_engine_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_events(SledEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Clones of a store share subscribers, and a dropped watcher does not affect writes
+#[test]
+fn kvs_engine_shared_watchers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut watcher = store.watch("")?;
+    drop(store.watch("")?);
+
+    let writer = store.clone();
+    thread::spawn(move || writer.set("key".to_owned(), "value".to_owned()).unwrap())
+        .join()
+        .unwrap();
+    assert_eq!(watcher.next().unwrap()?, set_event("key", "value"));
+    Ok(())
+}
+
+// Changes made by other clients are streamed to the watching client
+#[test]
+fn client_watch() -> Result<()> {
+    let addr = "127.0.0.1:4120".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+
+    let mut watching = KvsClient::connect(addr)?;
+    assert!(watching.features().iter().any(|f| f == "watch"));
+    let mut watch = watching.watch("config:".to_owned())?;
+
+    let handle = thread::spawn(move || -> Result<()> {
+        let mut writer = KvsClient::connect(addr)?;
+        writer.set("config:a".to_owned(), "1".to_owned())?;
+        writer.set("unrelated".to_owned(), "2".to_owned())?;
+        // Heartbeats sent while nothing changes are not reported as events
+        thread::sleep(Duration::from_millis(1500));
+        writer.remove("config:a".to_owned())?;
+        Ok(())
+    });
+
+    assert_eq!(watch.next().unwrap()?, set_event("config:a", "1"));
+    assert_eq!(watch.next().unwrap()?, remove_event("config:a"));
+    handle.join().unwrap()?;
+
+    // Once the watch is dropped the client reconnects for ordinary requests
+    drop(watch);
+    assert_eq!(watching.get("config:a".to_owned())?, None);
+    Ok(())
+}
+
+// Watching requires read access to the whole prefix
+#[test]
+fn watch_requires_read_access() -> Result<()> {
+    let addr = "127.0.0.1:4121".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, dir| server.auth(auth_config(dir, AUTH_CONFIG)));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.auth("bob".to_owned(), "bob-secret".to_owned())?;
+    assert!(matches!(client.watch("".to_owned()), Err(KvsError::PermissionDenied)));
+    assert!(client.watch("public:".to_owned()).is_ok());
+    Ok(())
+}
+
+// `kvs-client watch` prints one line per change
+#[test]
+fn cli_watch() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4122".parse().unwrap();
+    let _temp_dir = start_server(addr, |server, _| server);
+
+    let mut child = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "app:", "--addr", &addr.to_string()])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    // Give the client time to subscribe before writing
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("app:key".to_owned(), "value".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    client.remove("app:key".to_owned())?;
+
+    assert_eq!(lines.next().unwrap()?, "set app:key value");
+    assert_eq!(lines.next().unwrap()?, "rm app:key");
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}